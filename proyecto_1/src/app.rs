@@ -1,31 +1,60 @@
 use eframe::{
-    egui::{self, Align, Align2, Id, Layout, Rgba, Sense, TextStyle, Ui},
-    epaint::{Color32, Pos2, Vec2},
+    egui::{
+        self, Align, Align2, Id, Layout, Rgba, RichText, Sense, TextStyle, Ui,
+    },
+    epaint::{
+        text::{LayoutJob, TextFormat},
+        Color32, Pos2, Vec2,
+    },
 };
 use std::{
-    collections::VecDeque,
-    mem::{size_of, variant_count},
-    sync::mpsc::{Receiver, SyncSender, TryRecvError},
+    collections::{HashSet, VecDeque},
+    mem::{self, size_of},
+    sync::mpsc::{channel, Receiver, TryRecvError},
     time::{Duration, Instant},
 };
 
-use crate::{
+use proyecto_1::{
+    events::Event,
     models::{
-        cache::CacheLine, instructions::Instruction, system::SocProperties,
+        arbiter::WaitStats,
+        assembler::{self, Assembly},
+        bus::{BusSignal, TrafficStats},
+        cache::{CacheActivity, CacheLine, CacheState},
+        consistency::ConsistencyModel,
+        dram::BankStats,
+        error::SimError,
+        instructions::{
+            AluOp, Flags, Instruction, Operand, Register, NUM_REGISTERS,
+        },
+        interconnect::{
+            self, InterconnectStats, Link, NetworkMessage, Topology,
+        },
+        numa::NodeStats,
+        prefetcher::{PrefetchPolicy, PrefetchStats},
+        processor::CpuCommand,
+        system::{SocProperties, System},
         Data, MemOp,
     },
-    random::UniformRng,
+    programs::EXAMPLE_PROGRAMS,
+    scenarios::SharingPattern,
+    workload::{
+        AddressDistribution, InstructionKind, Workload, WorkloadConfig,
+    },
 };
 
 const PROCESSORS_PER_ROW: usize = 2;
 const PROCESSORS_HEIGHT_PERCENT: f32 = 0.66;
 
 const INSTRUCTIONS_HIST: usize = 8;
+// Granted transactions shown in the bus timeline
+const BUS_HIST: usize = 8;
 
 #[derive(Debug, PartialEq)]
 enum ExecutionMode {
     Automatic,
     Manual,
+    Program,
 }
 
 type GuiCache = Vec<CacheLine>;
@@ -33,17 +62,30 @@ type GuiMemory = Vec<Data>;
 
 pub struct AppState {
     system_props: SocProperties,
-    instruction_txs: Vec<SyncSender<Instruction>>,
-    rng: UniformRng,
+    system: System,
+    workload: Workload,
     mode: ExecutionMode,
     speed: f32,
     previous_time: Instant,
     ctx: egui::Context,
     events_rx: Receiver<Event>,
+    // Why the system stopped, nothing runs until it's reset
+    error: Option<SimError>,
 
     // Manual instruction crafting
     manual_instruction: (usize, Instruction),
-    manual_instruction_data: String,
+    manual_instruction_data: [String; 2],
+
+    // Program execution
+    selected_program: usize,
+    running_program: bool,
+    halted: Vec<bool>,
+    // Program text being edited for every processor and the last one that
+    // was loaded, with the assembly it produced
+    editor_cpu: usize,
+    editor_sources: Vec<String>,
+    loaded_sources: Vec<String>,
+    assemblies: Vec<Assembly>,
 
     // Last address that was missed per processor
     read_miss_addresses: Vec<usize>,
@@ -51,47 +93,69 @@ pub struct AppState {
 
     // Last instruction for each processor
     last_instructions: Vec<Instruction>,
+    reservations: Vec<Option<usize>>,
+    sc_results: Vec<Option<bool>>,
+    consistency_model: ConsistencyModel,
+    store_buffers: Vec<Vec<(usize, Data)>>,
+
+    // Last request the arbiter granted and the ones left waiting
+    bus_granted: Option<BusSignal>,
+    bus_pending: Vec<BusSignal>,
+    bus_in_flight: Vec<BusSignal>,
+    // Newest first
+    bus_hist: VecDeque<BusSignal>,
+    bus_wait_stats: Vec<WaitStats>,
+    // Messages on the interconnect, dropped once they arrive
+    network_messages: Vec<NetworkMessage>,
+    interconnect_stats: InterconnectStats,
+    bus_traffic: TrafficStats,
+    // Per memory node
+    bank_stats: Vec<Vec<BankStats>>,
+    memory_queued: Vec<usize>,
+    node_accesses: Vec<NodeStats>,
     instructions_hist: VecDeque<(usize, Instruction)>,
 
     // These are different from the real system's memories, they're used for
     // the GUI to keep track of the current state of things
     caches: Vec<GuiCache>,
+    victim_caches: Vec<Vec<CacheLine>>,
+    write_back_buffers: Vec<Vec<CacheLine>>,
+    prefetch_stats: Vec<PrefetchStats>,
+    cache_activity: Vec<CacheActivity>,
+    // Instructions every CPU executed, the busiest one's are the cycles the
+    // energy model charges leakage for
+    instructions_executed: Vec<usize>,
     main_memory: GuiMemory,
+    registers: Vec<[Data; NUM_REGISTERS]>,
+    flags: Vec<Flags>,
+    pcs: Vec<usize>,
 
     offset_bits: usize,
     index_bits: usize,
     address_bits: usize,
 }
 
-pub enum Event {
-    CacheWrite {
-        cache_i: usize,
-        block_i: usize,
-        line: CacheLine,
-    },
-    MemWrite {
-        block_i: usize,
-        data: Data,
-    },
-    // Assumes a miss
-    Alert {
-        processor_i: usize,
-        address: usize,
-        op: MemOp,
-    },
-}
-
 impl AppState {
     pub fn new(
         cc: &eframe::CreationContext<'_>,
         events_rx: Receiver<Event>,
-        instruction_txs: Vec<SyncSender<Instruction>>,
-        system_props: SocProperties,
+        system: System,
+        workload: WorkloadConfig,
     ) -> Self {
         let mut style: egui::Style = (*cc.egui_ctx.style()).clone();
         style.spacing.item_spacing = egui::vec2(10.0, 5.0);
         style.animation_time = 1.0;
         cc.egui_ctx.set_style(style);
+        Self::with_system(cc.egui_ctx.clone(), events_rx, system, workload)
+    }
+
+    fn with_system(
+        ctx: egui::Context,
+        events_rx: Receiver<Event>,
+        system: System,
+        workload: WorkloadConfig,
+    ) -> Self {
+        let system_props = *system.props();
 
         // For cache drawing
         let mut index_bits = 0;
@@ -125,49 +189,122 @@ impl AppState {
                 ];
                 system_props.num_processors
             ],
+            victim_caches: vec![Vec::new(); system_props.num_processors],
+            write_back_buffers: vec![Vec::new(); system_props.num_processors],
+            prefetch_stats: vec![
+                PrefetchStats::default();
+                system_props.num_processors
+            ],
+            cache_activity: vec![
+                CacheActivity::default();
+                system_props.num_processors
+            ],
+            instructions_executed: vec![0; system_props.num_processors],
             main_memory: vec![0; system_props.main_memory_blocks],
+            registers: vec![[0; NUM_REGISTERS]; system_props.num_processors],
+            flags: vec![Flags::default(); system_props.num_processors],
+            pcs: vec![0; system_props.num_processors],
+            selected_program: 0,
+            running_program: false,
+            halted: vec![false; system_props.num_processors],
+            editor_cpu: 0,
+            editor_sources: vec![String::new(); system_props.num_processors],
+            loaded_sources: vec![String::new(); system_props.num_processors],
+            assemblies: vec![Assembly::default(); system_props.num_processors],
             read_miss_addresses: vec![0; system_props.num_processors],
             write_miss_addresses: vec![0; system_props.num_processors],
             last_instructions: vec![
                 Instruction::Calc;
                 system_props.num_processors
             ],
+            reservations: vec![None; system_props.num_processors],
+            sc_results: vec![None; system_props.num_processors],
+            consistency_model: system_props.consistency_model,
+            store_buffers: vec![Vec::new(); system_props.num_processors],
+            bus_granted: None,
+            bus_pending: Vec::new(),
+            bus_in_flight: Vec::new(),
+            bus_hist: VecDeque::with_capacity(BUS_HIST),
+            bus_wait_stats: vec![
+                WaitStats::default();
+                system_props.num_processors
+            ],
+            network_messages: Vec::new(),
+            interconnect_stats: InterconnectStats::default(),
+            bus_traffic: TrafficStats::default(),
+            bank_stats: vec![
+                vec![BankStats::default(); system_props.dram.banks];
+                system_props.numa.nodes
+            ],
+            memory_queued: vec![0; system_props.numa.nodes],
+            node_accesses: vec![NodeStats::default(); system_props.numa.nodes],
             instructions_hist: vec![(0, Instruction::Calc); INSTRUCTIONS_HIST]
                 .into(),
             manual_instruction: (0, Instruction::Calc),
-            manual_instruction_data: "0".to_owned(),
+            manual_instruction_data: ["0".to_owned(), "0".to_owned()],
             system_props,
-            rng: UniformRng::from_seed(0),
+            workload: Workload::new(
+                workload,
+                system_props.num_processors,
+                system_props.main_memory_blocks,
+                system_props.seed,
+            ),
             mode: ExecutionMode::Automatic,
             speed: 1.0,
             previous_time: Instant::now(),
-            ctx: cc.egui_ctx.clone(),
+            ctx,
             events_rx,
+            error: None,
             offset_bits,
             index_bits,
             address_bits,
-            instruction_txs,
+            system,
         }
     }
 
-    fn gen_random_address(&mut self) -> usize {
-        (self
-            .rng
-            .gen_range(0..self.system_props.main_memory_blocks as u32)
-            << self.offset_bits) as usize
+    // Starts over on a new system with the same configuration, with cold
+    // caches and zeroed memory. The programs being edited and the consistency
+    // model picked at runtime are kept. The old system stops when it's
+    // dropped, its events go away with its channel.
+    fn reset_system(&mut self) {
+        let (events_tx, events_rx) = channel();
+        let props = SocProperties {
+            consistency_model: self.consistency_model,
+            ..self.system_props
+        };
+        // The properties are the ones the current system was built with
+        let system = System::new(props, &[], events_tx).unwrap();
+        let mut state = AppState::with_system(
+            self.ctx.clone(),
+            events_rx,
+            system,
+            *self.workload.config_mut(),
+        );
+        state.mode = mem::replace(&mut self.mode, ExecutionMode::Automatic);
+        state.speed = self.speed;
+        state.selected_program = self.selected_program;
+        state.editor_cpu = self.editor_cpu;
+        state.editor_sources = mem::take(&mut self.editor_sources);
+        *self = state;
     }
 
-    fn gen_random_instruction(&mut self) -> Instruction {
-        match (self.rng.gen() >> 16) % variant_count::<Instruction>() as u32 {
-            0 => Instruction::Calc,
-            1 => Instruction::Read {
-                address: self.gen_random_address(),
-            },
-            2 => Instruction::Write {
-                address: self.gen_random_address(),
-                data: self.rng.gen() as u16,
-            },
-            _ => panic!("Unaccounted for instruction"),
+    // Shows why the system stopped and offers to reset it
+    fn error_dialog(&mut self, ctx: &egui::Context) {
+        let Some(ref error) = self.error else {
+            return;
+        };
+        let mut reset = false;
+        egui::Window::new("System error")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(error.to_string());
+                ui.label("Every component of the system stopped.");
+                reset = ui.button("Reset System").clicked();
+            });
+        if reset {
+            self.reset_system();
         }
     }
 
@@ -187,28 +324,65 @@ impl AppState {
         println!("---------------------------");
         self.save_instruction(cpu_i, instruction.clone());
         println!("Sending instruction {instruction:?} to processor {cpu_i}");
-        let processor_tx = &self.instruction_txs[cpu_i];
-        match processor_tx.send(instruction) {
-            Ok(_) => (),
-            Err(_) => {
-                panic!("One of the system threads died unexpectedly")
-            }
-        }
+        self.send_command(cpu_i, CpuCommand::Execute(instruction));
     }
 
     fn give_instruction_to_all(&mut self) {
         println!("---------------------------");
         for i in 0..self.system_props.num_processors {
-            let instruction = self.gen_random_instruction();
+            let instruction = self.workload.next_instruction(i);
             self.save_instruction(i, instruction.clone());
             println!("Sending instruction {instruction:?} to processor {i}");
-            let processor_tx = &self.instruction_txs[i];
-            match processor_tx.send(instruction) {
-                Ok(_) => (),
-                Err(_) => {
-                    panic!("One of the system threads died unexpectedly")
-                }
-            }
+            self.send_command(i, CpuCommand::Execute(instruction));
+        }
+    }
+
+    // A CPU that's gone stopped along with the rest of the system, the error
+    // that stopped it is on its way
+    fn send_command(&self, cpu_i: usize, command: CpuCommand) {
+        self.system.send(cpu_i, command).ok();
+    }
+
+    fn open_example_program(&mut self) {
+        let example = &EXAMPLE_PROGRAMS[self.selected_program];
+        for (i, source) in self.editor_sources.iter_mut().enumerate() {
+            *source = example.source(i).to_owned();
+        }
+    }
+
+    // Assembles every editor and loads the programs, only if all of them
+    // are valid
+    fn load_programs(&mut self) {
+        let assemblies: Result<Vec<Assembly>, _> = self
+            .editor_sources
+            .iter()
+            .map(|source| assembler::assemble(source))
+            .collect();
+        let Ok(assemblies) = assemblies else {
+            return;
+        };
+
+        for (i, assembly) in assemblies.iter().enumerate() {
+            self.send_command(
+                i,
+                CpuCommand::LoadProgram(assembly.program.clone()),
+            );
+        }
+        self.assemblies = assemblies;
+        self.loaded_sources = self.editor_sources.clone();
+        self.halted.fill(false);
+    }
+
+    fn set_consistency_model(&mut self, model: ConsistencyModel) {
+        self.consistency_model = model;
+        for i in 0..self.system_props.num_processors {
+            self.send_command(i, CpuCommand::SetConsistencyModel(model));
+        }
+    }
+
+    fn step_all(&self) {
+        for i in 0..self.system_props.num_processors {
+            self.send_command(i, CpuCommand::Step);
         }
     }
 
@@ -220,11 +394,25 @@ impl AppState {
         Id::new(format!("main_memory_line_id__{line_i}"))
     }
 
+    fn get_register_id(&self, processor_id: usize, register: Register) -> Id {
+        Id::new(format!("register_{processor_id}_{register}"))
+    }
+
     fn get_alert_id(&self, processor_id: usize, op: MemOp) -> Id {
         Id::new(format!("miss_alert_{processor_id}_{op:?}"))
     }
 }
 
+fn register_combo_box(ui: &mut Ui, label: &str, register: &mut Register) {
+    egui::ComboBox::from_label(label)
+        .selected_text(format!("r{register}"))
+        .show_ui(ui, |ui| {
+            for i in 0..NUM_REGISTERS {
+                ui.selectable_value(register, i, format!("r{i}"));
+            }
+        });
+}
+
 impl AppState {
     fn controls_panel(&mut self, ui: &mut Ui) {
         ui.heading("Execution Mode");
@@ -238,6 +426,31 @@ impl AppState {
             ExecutionMode::Manual,
             format!("{:?}", ExecutionMode::Manual),
         );
+        ui.radio_value(
+            &mut self.mode,
+            ExecutionMode::Program,
+            format!("{:?}", ExecutionMode::Program),
+        );
+
+        ui.separator();
+
+        let mut model = self.consistency_model;
+        egui::ComboBox::from_label("Consistency Model")
+            .selected_text(model.get_name())
+            .show_ui(ui, |ui| {
+                for option in ConsistencyModel::ALL {
+                    ui.selectable_value(&mut model, option, option.get_name());
+                }
+            });
+        if model != self.consistency_model {
+            self.set_consistency_model(model);
+        }
+
+        // Cold caches and zeroed memory, keeping the programs being edited
+        if ui.button("Reset System").clicked() {
+            self.reset_system();
+            return;
+        }
 
         ui.separator();
 
@@ -264,88 +477,167 @@ impl AppState {
                         }
                     });
 
-                let (address, data) = match self.manual_instruction.1 {
-                    Instruction::Calc => (0, 0),
-                    Instruction::Read { address } => (address, 0),
-                    Instruction::Write { address, data } => (address, data),
-                };
+                let address = self
+                    .manual_instruction
+                    .1
+                    .address()
+                    .unwrap_or_else(|| 0.into());
+                let rd = self
+                    .manual_instruction
+                    .1
+                    .destination_mut()
+                    .map_or(0, |rd| *rd);
+                let zero = Operand::Immediate(0);
+                let instruction_types = [
+                    Instruction::Calc,
+                    Instruction::Read { rd, address },
+                    Instruction::Write { address, src: zero },
+                    Instruction::TestAndSet { rd, address },
+                    Instruction::FetchAndAdd {
+                        rd,
+                        address,
+                        value: zero,
+                    },
+                    Instruction::CompareAndSwap {
+                        rd,
+                        address,
+                        expected: zero,
+                        new: zero,
+                    },
+                    Instruction::LoadLinked { rd, address },
+                    Instruction::StoreConditional {
+                        rd,
+                        address,
+                        src: zero,
+                    },
+                    Instruction::Fence,
+                    Instruction::Alu {
+                        op: AluOp::Add,
+                        rd,
+                        rs: 0,
+                        operand: zero,
+                    },
+                    Instruction::Mov { rd, operand: zero },
+                    Instruction::Cmp {
+                        rs: 0,
+                        operand: zero,
+                    },
+                ];
 
                 egui::ComboBox::from_label("Instruction Type")
-                    .selected_text(format!(
-                        "{}",
-                        self.manual_instruction.1.get_type_str()
-                    ))
+                    .selected_text(self.manual_instruction.1.get_type_str())
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(
-                            &mut self.manual_instruction.1,
-                            Instruction::Calc,
-                            Instruction::Calc.get_type_str(),
-                        );
+                        for instruction in instruction_types {
+                            let selected = instruction.get_type_str()
+                                == self.manual_instruction.1.get_type_str();
+                            if ui
+                                .selectable_label(
+                                    selected,
+                                    instruction.get_type_str(),
+                                )
+                                .clicked()
+                                && !selected
+                            {
+                                self.manual_instruction.1 = instruction;
+                            }
+                        }
+                    });
 
-                        ui.selectable_value(
-                            &mut self.manual_instruction.1,
-                            Instruction::Read { address },
-                            Instruction::Read { address }.get_type_str(),
-                        );
+                if let Instruction::Alu { ref mut op, .. } =
+                    self.manual_instruction.1
+                {
+                    egui::ComboBox::from_label("Operation")
+                        .selected_text(op.get_mnemonic())
+                        .show_ui(ui, |ui| {
+                            for alu_op in AluOp::ALL {
+                                ui.selectable_value(
+                                    op,
+                                    alu_op,
+                                    alu_op.get_mnemonic(),
+                                );
+                            }
+                        });
+                }
 
-                        ui.selectable_value(
-                            &mut self.manual_instruction.1,
-                            Instruction::Write { address, data },
-                            Instruction::Write { address, data }.get_type_str(),
-                        );
-                    });
+                if let Some(rd) = self.manual_instruction.1.destination_mut() {
+                    register_combo_box(ui, "Destination", rd);
+                }
+
+                if let Instruction::Alu { ref mut rs, .. }
+                | Instruction::Cmp { ref mut rs, .. } =
+                    self.manual_instruction.1
+                {
+                    register_combo_box(ui, "Source", rs);
+                }
 
                 let address_width = self.address_bits + 2;
-                match &mut self.manual_instruction.1 {
-                    Instruction::Calc => (),
-                    Instruction::Read {
-                        ref mut address, ..
-                    }
-                    | Instruction::Write {
-                        ref mut address, ..
-                    } => {
-                        egui::ComboBox::from_label("Address")
-                            .selected_text(format!(
-                                "{:#0address_width$b}",
-                                address
-                            ))
-                            .show_ui(ui, |ui| {
-                                for i in 0..self.system_props.main_memory_blocks
-                                {
-                                    ui.selectable_value(
-                                        address,
-                                        i << self.offset_bits,
-                                        format!(
-                                            "{:#0address_width$b}",
-                                            i << self.offset_bits
-                                        ),
-                                    );
-                                }
-                            });
-                    }
+                if let Some(address) = self.manual_instruction.1.address_mut() {
+                    egui::ComboBox::from_label("Address")
+                        .selected_text(format!(
+                            "{:#0address_width$b}",
+                            address.offset
+                        ))
+                        .show_ui(ui, |ui| {
+                            for i in 0..self.system_props.main_memory_blocks {
+                                ui.selectable_value(
+                                    &mut address.offset,
+                                    i << self.offset_bits,
+                                    format!(
+                                        "{:#0address_width$b}",
+                                        i << self.offset_bits
+                                    ),
+                                );
+                            }
+                        });
                 }
 
                 let mut enable_button = true;
-                match &mut self.manual_instruction.1 {
-                    Instruction::Calc | Instruction::Read { .. } => (),
-                    Instruction::Write { ref mut data, .. } => {
-                        ui.horizontal(|ui| {
-                            let data_label = ui.label("Data: ");
-                            ui.text_edit_singleline(
-                                &mut self.manual_instruction_data,
-                            )
+                let operand_fields: Vec<(&str, &mut Operand)> = match &mut self
+                    .manual_instruction
+                    .1
+                {
+                    Instruction::Write { ref mut src, .. }
+                    | Instruction::StoreConditional { ref mut src, .. } => {
+                        vec![("Data: ", src)]
+                    }
+                    Instruction::FetchAndAdd { ref mut value, .. } => {
+                        vec![("Value: ", value)]
+                    }
+                    Instruction::CompareAndSwap {
+                        ref mut expected,
+                        ref mut new,
+                        ..
+                    } => vec![("Expected: ", expected), ("New: ", new)],
+                    Instruction::Alu {
+                        ref mut operand, ..
+                    }
+                    | Instruction::Mov {
+                        ref mut operand, ..
+                    }
+                    | Instruction::Cmp {
+                        ref mut operand, ..
+                    } => vec![("Operand: ", operand)],
+                    _ => vec![],
+                };
+
+                for ((label, operand), text) in operand_fields
+                    .into_iter()
+                    .zip(self.manual_instruction_data.iter_mut())
+                {
+                    ui.horizontal(|ui| {
+                        let data_label = ui.label(label);
+                        ui.text_edit_singleline(text)
                             .labelled_by(data_label.id);
-                        });
+                    });
 
-                        match self.manual_instruction_data.parse::<Data>() {
-                            Ok(parsed_data) => *data = parsed_data,
-                            Err(err) => {
-                                enable_button = false;
-                                ui.colored_label(
-                                    Color32::RED,
-                                    format!("Invalid value for data: {err}"),
-                                );
-                            }
+                    match text.parse::<Operand>() {
+                        Ok(parsed_operand) => *operand = parsed_operand,
+                        Err(err) => {
+                            enable_button = false;
+                            ui.colored_label(
+                                Color32::RED,
+                                format!("Invalid value for {label}{err}"),
+                            );
                         }
                     }
                 }
@@ -368,15 +660,67 @@ impl AppState {
                     egui::Slider::new(&mut self.speed, 1.0..=10.0)
                         .text("seconds"),
                 );
+                self.workload_controls(ui);
                 let time_passed = Instant::now() - self.previous_time;
-                if time_passed
-                    > Duration::from_millis((self.speed * 1000.0) as u64)
+                if self.error.is_none()
+                    && time_passed
+                        > Duration::from_millis((self.speed * 1000.0) as u64)
                 {
                     self.give_instruction_to_all();
                     self.previous_time = Instant::now();
                 }
                 self.ctx.request_repaint();
             }
+            ExecutionMode::Program => {
+                egui::ComboBox::from_label("Program")
+                    .selected_text(EXAMPLE_PROGRAMS[self.selected_program].name)
+                    .show_ui(ui, |ui| {
+                        for (i, example) in EXAMPLE_PROGRAMS.iter().enumerate()
+                        {
+                            ui.selectable_value(
+                                &mut self.selected_program,
+                                i,
+                                example.name,
+                            );
+                        }
+                    });
+
+                if ui.button("Open Example").clicked() {
+                    self.open_example_program();
+                }
+
+                let valid = self
+                    .editor_sources
+                    .iter()
+                    .all(|source| assembler::assemble(source).is_ok());
+                if ui
+                    .add_enabled(valid, egui::Button::new("Load Programs"))
+                    .clicked()
+                {
+                    self.load_programs();
+                }
+
+                ui.add_space(spacing.y * 2.0);
+                if ui.button("Step All").clicked() {
+                    self.step_all();
+                }
+
+                ui.checkbox(&mut self.running_program, "Run");
+                ui.add(
+                    egui::Slider::new(&mut self.speed, 1.0..=10.0)
+                        .text("seconds"),
+                );
+                if self.running_program {
+                    let time_passed = Instant::now() - self.previous_time;
+                    if time_passed
+                        > Duration::from_millis((self.speed * 1000.0) as u64)
+                    {
+                        self.step_all();
+                        self.previous_time = Instant::now();
+                    }
+                    self.ctx.request_repaint();
+                }
+            }
         }
 
         ui.separator();
@@ -391,6 +735,174 @@ impl AppState {
         ui.label("(Most recent at the bottom)");
     }
 
+    fn workload_controls(&mut self, ui: &mut Ui) {
+        let max_private = self.workload.max_private_blocks();
+        let config = self.workload.config_mut();
+        ui.collapsing("Workload", |ui| {
+            egui::ComboBox::from_label("Scenario")
+                .selected_text(
+                    config
+                        .scenario
+                        .as_ref()
+                        .map_or("random", SharingPattern::get_name),
+                )
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut config.scenario, None, "random");
+                    for option in SharingPattern::ALL {
+                        ui.selectable_value(
+                            &mut config.scenario,
+                            Some(option),
+                            option.get_name(),
+                        );
+                    }
+                });
+            if let Some(pattern) = config.scenario {
+                ui.label(pattern.description());
+                return;
+            }
+
+            ui.label("Instruction mix:");
+            for (kind, weight) in
+                InstructionKind::ALL.iter().zip(config.mix.iter_mut())
+            {
+                ui.add(egui::Slider::new(weight, 0..=10).text(kind.get_name()));
+            }
+
+            egui::ComboBox::from_label("Addresses")
+                .selected_text(config.distribution.get_name())
+                .show_ui(ui, |ui| {
+                    for option in AddressDistribution::ALL {
+                        ui.selectable_value(
+                            &mut config.distribution,
+                            option,
+                            option.get_name(),
+                        );
+                    }
+                });
+            match config.distribution {
+                AddressDistribution::Uniform => (),
+                AddressDistribution::Binomial => {
+                    ui.add(
+                        egui::Slider::new(
+                            &mut config.binomial_percent,
+                            0..=100,
+                        )
+                        .text("% trial success"),
+                    );
+                }
+                AddressDistribution::Zipf => {
+                    ui.add(
+                        egui::Slider::new(&mut config.zipf_exponent, 0.0..=4.0)
+                            .text("exponent"),
+                    );
+                }
+                AddressDistribution::HotCold => {
+                    ui.add(
+                        egui::Slider::new(
+                            &mut config.hot_blocks_percent,
+                            0..=100,
+                        )
+                        .text("% hot blocks"),
+                    );
+                    ui.add(
+                        egui::Slider::new(
+                            &mut config.hot_accesses_percent,
+                            0..=100,
+                        )
+                        .text("% hot accesses"),
+                    );
+                }
+            }
+
+            ui.add(
+                egui::Slider::new(&mut config.private_blocks, 0..=max_private)
+                    .text("private blocks per CPU"),
+            );
+            if config.private_blocks > 0 {
+                ui.add(
+                    egui::Slider::new(&mut config.shared_percent, 0..=100)
+                        .text("% shared accesses"),
+                );
+            }
+            ui.add(
+                egui::Slider::new(&mut config.locality_percent, 0..=100)
+                    .text("% repeated addresses"),
+            );
+        });
+    }
+
+    fn editor_panel(&mut self, ui: &mut Ui) {
+        ui.heading("Program Editor");
+        ui.horizontal_wrapped(|ui| {
+            for i in 0..self.system_props.num_processors {
+                ui.selectable_value(
+                    &mut self.editor_cpu,
+                    i,
+                    format!("CPU{}", i + 1),
+                );
+            }
+        });
+
+        let cpu_i = self.editor_cpu;
+        let loaded = self.editor_sources[cpu_i] == self.loaded_sources[cpu_i];
+        // The loaded program's lines only match the text if it wasn't edited
+        let pc_line = if loaded {
+            self.assemblies[cpu_i].source_line(self.pcs[cpu_i])
+        } else {
+            None
+        };
+        let errors = match assembler::assemble(&self.editor_sources[cpu_i]) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors,
+        };
+        let error_lines: HashSet<usize> =
+            errors.iter().map(|err| err.line).collect();
+
+        let pc_color = ui.visuals().selection.bg_fill;
+        let mut layouter = |ui: &Ui, text: &str, wrap_width: f32| {
+            let font_id = TextStyle::Monospace.resolve(ui.style());
+            let color = ui.visuals().text_color();
+            let mut job = LayoutJob::default();
+            for (line_i, line) in text.split_inclusive('\n').enumerate() {
+                let background = if error_lines.contains(&line_i) {
+                    Color32::DARK_RED
+                } else if pc_line == Some(line_i) {
+                    pc_color
+                } else {
+                    Color32::TRANSPARENT
+                };
+                job.append(
+                    line,
+                    0.0,
+                    TextFormat {
+                        font_id: font_id.clone(),
+                        color,
+                        background,
+                        ..Default::default()
+                    },
+                );
+            }
+            job.wrap.max_width = wrap_width;
+            ui.fonts(|fonts| fonts.layout_job(job))
+        };
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.add(
+                egui::TextEdit::multiline(&mut self.editor_sources[cpu_i])
+                    .code_editor()
+                    .desired_rows(20)
+                    .layouter(&mut layouter),
+            );
+
+            if !loaded {
+                ui.label("Modified, load the programs to run it");
+            }
+            for err in errors {
+                ui.colored_label(Color32::RED, err.to_string());
+            }
+        });
+    }
+
     fn draw_alerts(&self, i: usize, ui: &mut Ui) {
         let default_color: Rgba = ui.visuals().window_fill().into();
 
@@ -458,8 +970,11 @@ impl AppState {
                 .width()
         };
 
-        let state_header_width = get_width(STATE_HEADER.to_owned());
-        let state_max_width = letter_size.width().max(state_header_width);
+        // Transient states take more than a letter
+        let state_max_width = CacheState::ALL
+            .iter()
+            .map(|state| get_width(state.get_letter().to_owned()))
+            .fold(get_width(STATE_HEADER.to_owned()), f32::max);
 
         let data_text_width = get_width(format!("{:#0data_width$X}", 0));
         let data_header_width = get_width(DATA_HEADER.to_owned());
@@ -533,8 +1048,9 @@ impl AppState {
             stroke,
         );
 
-        // center columns
-        x_locs[0] += (state_max_width - letter_size.width()) / 2.0;
+        // center columns, states are centered on their own as their widths
+        // differ
+        x_locs[0] += state_max_width / 2.0;
         x_locs[1] += (address_max_width - address_text_width) / 2.0;
         x_locs[2] += (data_max_width - data_text_width) / 2.0;
 
@@ -555,12 +1071,17 @@ impl AppState {
                 + spacing.y * ((line_i + 1) * 2 + 1) as f32
                 + letter_size.height() * (line_i + 1) as f32;
 
+            // Lines with a transaction going on stand out
+            let state_color = match cache_line.state.is_transient() {
+                true => Color32::GOLD,
+                false => text_color,
+            };
             painter.text(
                 Pos2 { x: x_locs[0], y },
-                Align2::LEFT_TOP,
+                Align2::CENTER_TOP,
                 cache_line.state.get_letter(),
                 font_id.clone(),
-                text_color,
+                state_color,
             );
 
             painter.text(
@@ -581,7 +1102,84 @@ impl AppState {
         }
     }
 
+    // Lines of a fully associative buffer next to the cache, like the victim
+    // cache, whose tags hold the whole block number
+    fn draw_block_lines(
+        &self,
+        i: usize,
+        name: &str,
+        lines: &[CacheLine],
+        entries: usize,
+        ui: &mut Ui,
+    ) {
+        let address_width = self.address_bits + 2;
+        let data_width = size_of::<Data>() * 2 + 2;
+
+        ui.label(format!("{name} ({}/{entries}):", lines.len()));
+        egui::Grid::new(format!("{name}_{i}"))
+            .striped(true)
+            .show(ui, |ui| {
+                for line in lines {
+                    let address = line.tag << self.offset_bits;
+                    let state = RichText::new(line.state.get_letter());
+                    ui.label(match line.state.is_transient() {
+                        true => state.color(Color32::GOLD),
+                        false => state,
+                    });
+                    ui.monospace(format!("{address:#0address_width$b}"));
+                    ui.monospace(format!("{:#0data_width$X}", line.data));
+                    ui.end_row();
+                }
+            });
+    }
+
+    // Every NUMA node apart, with the processors local to it
     fn draw_memory(&mut self, ui: &mut Ui) {
+        let numa = self.system_props.numa;
+        let dram = self.system_props.dram;
+        ui.label(format!(
+            "{} banks per node, {} row policy, tRCD-tCAS-tRP {}-{}-{}",
+            dram.banks,
+            dram.row_policy.get_name(),
+            dram.t_rcd,
+            dram.t_cas,
+            dram.t_rp
+        ));
+        if numa.nodes > 1 {
+            ui.label(format!(
+                "Interleave: {} blocks, remote delay: {} ms",
+                numa.interleave,
+                numa.remote_delay.as_millis()
+            ));
+        }
+
+        ui.horizontal_top(|ui| {
+            for node in 0..numa.nodes {
+                let blocks = numa
+                    .blocks_of_node(node, self.system_props.main_memory_blocks);
+                let processors: Vec<String> = numa
+                    .processors_of_node(node, self.system_props.num_processors)
+                    .iter()
+                    .map(|i| format!("P{}", i + 1))
+                    .collect();
+                ui.vertical(|ui| {
+                    ui.label(format!(
+                        "Node {node} ({})",
+                        processors.join(", ")
+                    ));
+                    let accesses = self.node_accesses[node];
+                    ui.label(format!(
+                        "Local: {}, remote: {}",
+                        accesses.local, accesses.remote
+                    ));
+                    self.draw_memory_node(&blocks, ui);
+                    self.draw_dram(node, ui);
+                });
+            }
+        });
+    }
+
+    fn draw_memory_node(&self, blocks: &[usize], ui: &mut Ui) {
         let spacing = self.ctx.style().spacing.item_spacing;
 
         let address_width = self.address_bits + 2;
@@ -617,7 +1215,7 @@ impl AppState {
 
         let grid_width = data_max_width + address_max_width + spacing.x * 4.0;
         let grid_height = (letter_size.height() + spacing.y * 2.0)
-            * (self.main_memory.len() + 1) as f32;
+            * (blocks.len() + 1) as f32;
         let grid_size = Vec2 {
             x: grid_width,
             y: grid_height,
@@ -668,7 +1266,8 @@ impl AppState {
         x_locs[0] += (address_max_width - address_text_width) / 2.0;
         x_locs[1] += (data_max_width - data_text_width) / 2.0;
 
-        for (i, data) in self.main_memory.iter().enumerate() {
+        for (row, &i) in blocks.iter().enumerate() {
+            let data = self.main_memory[i];
             let red_portion =
                 self.ctx.animate_bool(self.get_mem_line_id(i), false);
             let default_color: Rgba = default_color.into();
@@ -679,8 +1278,8 @@ impl AppState {
             let address = i << self.offset_bits;
 
             let y = grid_rect.top()
-                + spacing.y * ((i + 1) * 2 + 1) as f32
-                + letter_size.height() * (i + 1) as f32;
+                + spacing.y * ((row + 1) * 2 + 1) as f32
+                + letter_size.height() * (row + 1) as f32;
 
             painter.text(
                 Pos2 { x: x_locs[0], y },
@@ -700,6 +1299,429 @@ impl AppState {
         }
     }
 
+    fn draw_dram(&self, node: usize, ui: &mut Ui) {
+        ui.label(format!("Queued requests: {}", self.memory_queued[node]));
+
+        egui::Grid::new(format!("bank_stats_{node}"))
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("Bank");
+                ui.label("Accesses");
+                ui.label("Hits");
+                ui.label("Misses");
+                ui.label("Conflicts");
+                ui.label("Busy cycles");
+                ui.label("Avg wait");
+                ui.end_row();
+
+                for (i, stats) in self.bank_stats[node].iter().enumerate() {
+                    ui.label(format!("{i}"));
+                    ui.label(format!("{}", stats.accesses));
+                    ui.label(format!("{}", stats.row_hits));
+                    ui.label(format!("{}", stats.row_misses));
+                    ui.label(format!("{}", stats.row_conflicts));
+                    ui.label(format!("{}", stats.busy_cycles));
+                    ui.label(format!(
+                        "{} ms",
+                        stats.average_wait().as_millis()
+                    ));
+                    ui.end_row();
+                }
+            });
+    }
+
+    fn format_bus_signal(&self, signal: &BusSignal) -> String {
+        let address_width = self.address_bits + 2;
+        format!(
+            "CPU{} {:?} {:#0address_width$b}{}",
+            signal.origin + 1,
+            signal.action,
+            signal.address,
+            if signal.prefetch { " (prefetch)" } else { "" }
+        )
+    }
+
+    // Prefetches stand out from the transactions instructions wait for
+    fn bus_signal_label(&self, signal: &BusSignal, ui: &mut Ui) {
+        match signal.prefetch {
+            true => ui.colored_label(
+                Color32::LIGHT_BLUE,
+                self.format_bus_signal(signal),
+            ),
+            false => ui.label(self.format_bus_signal(signal)),
+        };
+    }
+
+    fn draw_bus(&self, ui: &mut Ui) {
+        ui.heading("Bus");
+        ui.label(format!(
+            "Arbitration: {}",
+            self.system_props.arbitration.get_name()
+        ));
+        ui.label(format!(
+            "Protocol: {}, {}{}",
+            self.system_props.protocol.get_name(),
+            self.system_props.write_policy.get_name(),
+            match self.system_props.write_allocate {
+                true => "",
+                false => ", no write allocate",
+            }
+        ));
+        ui.label(format!(
+            "Replacement: {}",
+            self.system_props.replacement.get_name()
+        ));
+
+        ui.add_space(self.ctx.style().spacing.item_spacing.y);
+        ui.label("Queue:");
+        match self.bus_granted {
+            Some(ref granted) => ui.colored_label(
+                Color32::GREEN,
+                format!("{} (last granted)", self.format_bus_signal(granted)),
+            ),
+            None => ui.label("Idle"),
+        };
+        for signal in &self.bus_pending {
+            self.bus_signal_label(signal, ui);
+        }
+
+        ui.add_space(self.ctx.style().spacing.item_spacing.y);
+        ui.label("In flight:");
+        if self.bus_in_flight.is_empty() {
+            ui.label("None");
+        }
+        for signal in &self.bus_in_flight {
+            self.bus_signal_label(signal, ui);
+        }
+
+        ui.add_space(self.ctx.style().spacing.item_spacing.y);
+        ui.label("Timeline:");
+        if self.bus_hist.is_empty() {
+            ui.label("None");
+        }
+        for signal in &self.bus_hist {
+            self.bus_signal_label(signal, ui);
+        }
+
+        ui.add_space(self.ctx.style().spacing.item_spacing.y);
+        egui::Grid::new("bus_wait_stats")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("CPU");
+                ui.label("Requests");
+                ui.label("Avg wait");
+                ui.label("Max wait");
+                ui.label("Max bypassed");
+                ui.end_row();
+
+                for (i, stats) in self.bus_wait_stats.iter().enumerate() {
+                    ui.label(format!("{}", i + 1));
+                    ui.label(format!("{}", stats.requests));
+                    ui.label(format!(
+                        "{} ms",
+                        stats.average_wait().as_millis()
+                    ));
+                    ui.label(format!("{} ms", stats.max_wait.as_millis()));
+                    ui.label(format!("{}", stats.max_bypassed));
+                    ui.end_row();
+                }
+            });
+
+        ui.add_space(self.ctx.style().spacing.item_spacing.y);
+        let traffic = self.bus_traffic;
+        egui::Grid::new("bus_traffic").striped(true).show(ui, |ui| {
+            for (name, count) in [
+                ("Read misses", traffic.read_misses),
+                ("Read exclusives", traffic.read_exclusives),
+                ("Invalidates", traffic.invalidates),
+                ("Write backs", traffic.write_backs),
+                ("Write throughs", traffic.write_throughs),
+                ("Cache to cache", traffic.data_transfers),
+                ("Memory reads", traffic.memory_reads),
+                ("Memory writes", traffic.memory_writes),
+                ("Snoops", traffic.snoops),
+            ] {
+                ui.label(name);
+                ui.label(format!("{}", count));
+                ui.end_row();
+            }
+        });
+
+        ui.add_space(self.ctx.style().spacing.item_spacing.y);
+        let mut activity = CacheActivity::default();
+        for cache_activity in &self.cache_activity {
+            activity.add(cache_activity);
+        }
+        let cycles = self
+            .instructions_executed
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0);
+        let energy = self.system_props.energy.report(
+            &activity,
+            &traffic,
+            self.system_props.cache_lines(),
+            cycles,
+        );
+        ui.label("Energy");
+        egui::Grid::new("energy").striped(true).show(ui, |ui| {
+            for (name, picojoules) in [
+                ("Caches", energy.caches),
+                ("Bus", energy.bus),
+                ("Memory", energy.memory),
+                ("Leakage", energy.leakage),
+                ("Total", energy.total()),
+            ] {
+                ui.label(name);
+                ui.label(format!("{:.2} nJ", picojoules / 1000.0));
+                ui.end_row();
+            }
+        });
+    }
+
+    // Where every node goes in the topology view, within a unit square. The
+    // home node is the last one.
+    fn node_position(&self, node: usize) -> Pos2 {
+        let num_nodes = self.system_props.num_processors + 1;
+        let home = num_nodes - 1;
+        match self.system_props.topology {
+            // Processors above the bus and memory below it
+            Topology::Bus if node == home => Pos2::new(0.5, 0.9),
+            Topology::Bus => Pos2::new(
+                (node as f32 + 0.5) / self.system_props.num_processors as f32,
+                0.1,
+            ),
+            Topology::Mesh => {
+                let width = interconnect::mesh_width(num_nodes);
+                let height = num_nodes.div_ceil(width);
+                Pos2::new(
+                    ((node % width) as f32 + 0.5) / width as f32,
+                    ((node / width) as f32 + 0.5) / height as f32,
+                )
+            }
+            // Around a circle, the crossbar's switch sits in the middle
+            Topology::Ring | Topology::Crossbar => {
+                let angle =
+                    std::f32::consts::TAU * node as f32 / num_nodes as f32;
+                Pos2::new(0.5 + 0.4 * angle.sin(), 0.5 - 0.4 * angle.cos())
+            }
+        }
+    }
+
+    // Point to point links of a ring or a mesh, the bus and the crossbar
+    // are drawn on their own
+    fn topology_links(&self) -> Vec<Link> {
+        let num_nodes = self.system_props.num_processors + 1;
+        let mut links = Vec::new();
+        match self.system_props.topology {
+            Topology::Bus | Topology::Crossbar => (),
+            Topology::Ring => {
+                for node in 0..num_nodes {
+                    links.push((node, (node + 1) % num_nodes));
+                }
+            }
+            // To the right and below every node
+            Topology::Mesh => {
+                let width = interconnect::mesh_width(num_nodes);
+                for node in 0..num_nodes {
+                    if node % width + 1 < width && node + 1 < num_nodes {
+                        links.push((node, node + 1));
+                    }
+                    if node + width < num_nodes {
+                        links.push((node, node + width));
+                    }
+                }
+            }
+        }
+        links
+    }
+
+    fn draw_interconnect(&mut self, ui: &mut Ui) {
+        const VIEW_SIZE: f32 = 220.0;
+        const NODE_RADIUS: f32 = 14.0;
+
+        let now = Instant::now();
+        self.network_messages.retain(|message| {
+            message.arrival().is_some_and(|arrival| arrival > now)
+        });
+
+        ui.heading("Interconnect");
+        ui.label(format!(
+            "Topology: {}, coherence: {}",
+            self.system_props.topology.get_name(),
+            self.system_props.coherence.get_name()
+        ));
+
+        let (response, painter) = ui.allocate_painter(
+            Vec2::splat(VIEW_SIZE),
+            Sense {
+                click: false,
+                drag: false,
+                focusable: false,
+            },
+        );
+        let rect = response.rect;
+        let to_screen =
+            |position: Pos2| rect.min + (position.to_vec2() * VIEW_SIZE);
+        let position = |node| to_screen(self.node_position(node));
+        let stroke = ui.visuals().window_stroke;
+        let font_id = TextStyle::Monospace.resolve(&self.ctx.style());
+        let text_color = ui.visuals().text_color();
+        let home = self.system_props.num_processors;
+        let node_name = |node| match node == home {
+            true => "Mem".to_owned(),
+            false => format!("P{}", node + 1),
+        };
+
+        match self.system_props.topology {
+            Topology::Bus => {
+                let y = rect.center().y;
+                painter.hline(rect.x_range(), y, stroke);
+                for node in 0..=home {
+                    painter.line_segment(
+                        [position(node), Pos2::new(position(node).x, y)],
+                        stroke,
+                    );
+                }
+            }
+            Topology::Crossbar => {
+                for node in 0..=home {
+                    painter
+                        .line_segment([position(node), rect.center()], stroke);
+                }
+            }
+            Topology::Ring | Topology::Mesh => {
+                for (from, to) in self.topology_links() {
+                    painter
+                        .line_segment([position(from), position(to)], stroke);
+                }
+            }
+        }
+
+        for node in 0..=home {
+            painter.circle(
+                position(node),
+                NODE_RADIUS,
+                ui.visuals().extreme_bg_color,
+                stroke,
+            );
+            painter.text(
+                position(node),
+                Align2::CENTER_CENTER,
+                node_name(node),
+                font_id.clone(),
+                text_color,
+            );
+        }
+
+        // Messages move along the hop they're crossing, the ones waiting for
+        // a link sit at its start
+        for message in &self.network_messages {
+            let hop =
+                message.hops.iter().find(|&&(_, _, end)| end > now).unwrap();
+            let ((from, to), start, end) = *hop;
+            let progress = match now.checked_duration_since(start) {
+                Some(elapsed) => {
+                    elapsed.as_secs_f32() / (end - start).as_secs_f32()
+                }
+                None => 0.0,
+            };
+            let between = |a: Pos2, b: Pos2, t: f32| a + (b - a) * t;
+            let point = match self.system_props.topology {
+                // Through the switch
+                Topology::Crossbar if progress < 0.5 => {
+                    between(position(from), rect.center(), progress * 2.0)
+                }
+                Topology::Crossbar => {
+                    between(rect.center(), position(to), progress * 2.0 - 1.0)
+                }
+                _ => between(position(from), position(to), progress),
+            };
+            let color = match message.label {
+                "data" => Color32::GREEN,
+                "snoop" | "answer" => Color32::GOLD,
+                _ => Color32::LIGHT_BLUE,
+            };
+            painter.circle_filled(point, 5.0, color);
+        }
+        if !self.network_messages.is_empty() {
+            self.ctx.request_repaint();
+        }
+
+        let stats = self.interconnect_stats;
+        egui::Grid::new("interconnect_stats").show(ui, |ui| {
+            ui.label("Messages");
+            ui.label(format!("{}", stats.messages));
+            ui.end_row();
+            ui.label("Hops");
+            ui.label(format!("{}", stats.hops));
+            ui.end_row();
+            ui.label("Link contention");
+            ui.label(format!("{} ms", stats.contention.as_millis()));
+            ui.end_row();
+        });
+        for message in &self.network_messages {
+            ui.label(format!(
+                "{} -> {}: {}",
+                node_name(message.from),
+                node_name(message.to),
+                message.label
+            ));
+        }
+        ui.horizontal(|ui| {
+            ui.colored_label(Color32::LIGHT_BLUE, "request/ack");
+            ui.colored_label(Color32::GOLD, "snoop");
+            ui.colored_label(Color32::GREEN, "data");
+        });
+    }
+
+    fn draw_registers(&self, i: usize, ui: &mut Ui) {
+        const REGISTERS_PER_ROW: usize = 4;
+
+        let data_width = size_of::<Data>() * 2 + 2;
+        let default_color: Rgba = ui.visuals().text_color().into();
+
+        egui::Grid::new(format!("registers_{i}")).show(ui, |ui| {
+            for register in 0..NUM_REGISTERS {
+                let red_portion = self
+                    .ctx
+                    .animate_bool(self.get_register_id(i, register), false);
+                let mixed_color = default_color * (1.0 - red_portion)
+                    + Rgba::RED * red_portion;
+
+                ui.monospace(format!("r{register}"));
+                ui.colored_label(
+                    Color32::from(mixed_color),
+                    egui::RichText::new(format!(
+                        "{:#0data_width$X}",
+                        self.registers[i][register]
+                    ))
+                    .monospace(),
+                );
+
+                if (register + 1) % REGISTERS_PER_ROW == 0 {
+                    ui.end_row();
+                }
+            }
+        });
+
+        let flags = self.flags[i];
+        ui.label(format!(
+            "Flags: Z={} L={}",
+            flags.zero as u8, flags.less as u8
+        ));
+
+        let program = &self.assemblies[i].program;
+        match program.get(self.pcs[i]) {
+            Some(instruction) => {
+                ui.label(format!("PC: {} ({instruction})", self.pcs[i]))
+            }
+            None if program.is_empty() => ui.label("No program loaded"),
+            None => ui.label(format!("PC: {} (halted)", self.pcs[i])),
+        };
+    }
+
     fn draw_processor(&mut self, i: usize, ui: &mut Ui) {
         let spacing = self.ctx.style().spacing.item_spacing;
         let width = (ui.available_width()
@@ -716,11 +1738,77 @@ impl AppState {
                 self.draw_alerts(i, ui);
 
                 self.draw_cache(i, ui);
+                if self.system_props.victim_cache > 0 {
+                    self.draw_block_lines(
+                        i,
+                        "Victim cache",
+                        &self.victim_caches[i],
+                        self.system_props.victim_cache,
+                        ui,
+                    );
+                }
+                self.draw_block_lines(
+                    i,
+                    "Write-back buffer",
+                    &self.write_back_buffers[i],
+                    self.system_props.write_back_buffer,
+                    ui,
+                );
+                if self.system_props.prefetch != PrefetchPolicy::Off {
+                    let stats = self.prefetch_stats[i];
+                    ui.label(format!(
+                        "Prefetches: {} issued, {} useful, {} late, {} useless",
+                        stats.issued, stats.useful, stats.late, stats.useless
+                    ));
+                }
 
                 ui.add_space(spacing.y * 2.0);
                 let label = ui.heading("Last Instruction: ");
                 ui.label(format!("{}", self.last_instructions[i]))
                     .labelled_by(label.id);
+
+                ui.add_space(spacing.y * 2.0);
+                self.draw_registers(i, ui);
+
+                let address_width = self.address_bits + 2;
+                let label = ui.heading("Reservation: ");
+                match self.reservations[i] {
+                    Some(address) => {
+                        ui.label(format!("{address:#0address_width$b}"))
+                    }
+                    None => ui.label("None"),
+                }
+                .labelled_by(label.id);
+
+                if self.consistency_model.uses_store_buffer() {
+                    let label = ui.heading("Store Buffer: ");
+                    let data_width = size_of::<Data>() * 2 + 2;
+                    let entries: Vec<String> = self.store_buffers[i]
+                        .iter()
+                        .map(|(address, data)| {
+                            format!(
+                                "{address:#0address_width$b} <- \
+                                 {data:#0data_width$X}"
+                            )
+                        })
+                        .collect();
+                    if entries.is_empty() {
+                        ui.label("Empty")
+                    } else {
+                        ui.label(entries.join("\n"))
+                    }
+                    .labelled_by(label.id);
+                }
+
+                match self.sc_results[i] {
+                    Some(true) => {
+                        ui.colored_label(Color32::GREEN, "Last SC succeeded");
+                    }
+                    Some(false) => {
+                        ui.colored_label(Color32::RED, "Last SC failed");
+                    }
+                    None => (),
+                }
             })
         });
     }
@@ -741,6 +1829,18 @@ impl eframe::App for AppState {
                         true,
                     );
                 }
+                Event::PrefetchStats { processor_i, stats } => {
+                    self.prefetch_stats[processor_i] = stats;
+                }
+                Event::CacheActivity { cache_i, activity } => {
+                    self.cache_activity[cache_i] = activity;
+                }
+                Event::VictimCache { cache_i, lines } => {
+                    self.victim_caches[cache_i] = lines;
+                }
+                Event::WriteBackBuffer { cache_i, lines } => {
+                    self.write_back_buffers[cache_i] = lines;
+                }
                 Event::MemWrite { block_i, data } => {
                     self.main_memory[block_i] = data;
                     self.ctx.animate_bool(self.get_mem_line_id(block_i), true);
@@ -761,17 +1861,118 @@ impl eframe::App for AppState {
                     self.ctx
                         .animate_bool(self.get_alert_id(processor_i, op), true);
                 }
+                Event::StoreConditional {
+                    processor_i,
+                    success,
+                } => {
+                    self.sc_results[processor_i] = Some(success);
+                }
+                Event::Reservation {
+                    processor_i,
+                    address,
+                } => {
+                    self.reservations[processor_i] = address;
+                }
+                Event::RegisterWrite {
+                    processor_i,
+                    register,
+                    data,
+                } => {
+                    self.registers[processor_i][register] = data;
+                    self.ctx.animate_bool(
+                        self.get_register_id(processor_i, register),
+                        true,
+                    );
+                }
+                Event::Flags { processor_i, flags } => {
+                    self.flags[processor_i] = flags;
+                }
+                Event::ProgramCounter { processor_i, pc } => {
+                    self.pcs[processor_i] = pc;
+                }
+                Event::StoreBuffer {
+                    processor_i,
+                    entries,
+                } => {
+                    self.store_buffers[processor_i] = entries;
+                }
+                Event::Fetch {
+                    processor_i,
+                    instruction,
+                } => {
+                    self.save_instruction(processor_i, instruction);
+                }
+                Event::BusArbitration {
+                    granted,
+                    pending,
+                    wait_stats,
+                } => {
+                    self.bus_granted = Some(granted);
+                    self.bus_hist.push_front(granted);
+                    self.bus_hist.truncate(BUS_HIST);
+                    self.bus_pending = pending;
+                    self.bus_wait_stats = wait_stats;
+                }
+                Event::BusInFlight { in_flight } => {
+                    self.bus_in_flight = in_flight;
+                }
+                Event::NetworkMessage(message) => {
+                    self.network_messages.push(message);
+                }
+                Event::InterconnectStats(stats) => {
+                    self.interconnect_stats = stats;
+                }
+                Event::BusTraffic(traffic) => {
+                    self.bus_traffic = traffic;
+                }
+                Event::MemoryStats {
+                    node,
+                    banks,
+                    queued,
+                    accesses,
+                } => {
+                    self.bank_stats[node] = banks;
+                    self.memory_queued[node] = queued;
+                    self.node_accesses[node] = accesses;
+                }
+                Event::Halted { processor_i } => {
+                    self.halted[processor_i] = true;
+                    // Nothing left to run
+                    if self.halted.iter().all(|&halted| halted) {
+                        self.running_program = false;
+                    }
+                }
+                Event::Error(error) => {
+                    self.running_program = false;
+                    self.error = Some(error);
+                }
+                Event::InstructionRetired { processor_i, .. } => {
+                    self.instructions_executed[processor_i] += 1;
+                }
+                // The GUI draws the state these lead to, they're for other
+                // tools observing the simulation
+                Event::BusTransactionStart(_)
+                | Event::BusTransactionEnd(_)
+                | Event::SnoopResponse { .. }
+                | Event::StateTransition { .. }
+                | Event::Eviction { .. }
+                | Event::WriteBack { .. } => (),
             },
-            Err(TryRecvError::Empty) => (),
-            Err(TryRecvError::Disconnected) => {
-                panic!("One of the system threads died unexpectedly")
-            }
+            // The threads only go away once the system failed, and the
+            // failure was reported already
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => (),
         }
+        self.error_dialog(ctx);
 
         egui::SidePanel::right("controls_panel").show(ctx, |ui| {
             ui.vertical_centered_justified(|ui| self.controls_panel(ui))
         });
 
+        if self.mode == ExecutionMode::Program {
+            egui::SidePanel::left("editor_panel")
+                .show(ctx, |ui| self.editor_panel(ui));
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
                 ui.allocate_ui_with_layout(
@@ -792,10 +1993,19 @@ impl eframe::App for AppState {
                     (ui.available_width(), ui.available_height()).into(),
                     Layout::top_down(Align::Center),
                     |ui| {
-                        ui.group(|ui| {
-                            ui.heading("Mem");
-
-                            self.draw_memory(ui);
+                        ui.horizontal_top(|ui| {
+                            ui.group(|ui| {
+                                ui.vertical(|ui| {
+                                    ui.heading("Mem");
+                                    self.draw_memory(ui);
+                                });
+                            });
+                            ui.group(|ui| {
+                                ui.vertical(|ui| self.draw_bus(ui));
+                            });
+                            ui.group(|ui| {
+                                ui.vertical(|ui| self.draw_interconnect(ui));
+                            });
                         });
                     },
                 );
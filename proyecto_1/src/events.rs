@@ -0,0 +1,440 @@
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex,
+};
+
+use crate::models::{
+    arbiter::WaitStats,
+    bus::{BusSignal, TrafficStats},
+    cache::{CacheActivity, CacheLine, CacheState},
+    dram::BankStats,
+    error::SimError,
+    instructions::{Flags, Instruction, Register},
+    interconnect::{InterconnectStats, NetworkMessage},
+    numa::NodeStats,
+    prefetcher::PrefetchStats,
+    Data, MemOp,
+};
+
+/// Everything a running system reports, sent by its components as they go.
+/// Statistics are running totals, and the state of a cache line, register or
+/// memory block is sent whole every time it changes.
+#[derive(Clone)]
+pub enum Event {
+    CacheWrite {
+        cache_i: usize,
+        block_i: usize,
+        line: CacheLine,
+    },
+    PrefetchStats {
+        processor_i: usize,
+        stats: PrefetchStats,
+    },
+    /// Whole victim cache, tags hold the block number
+    VictimCache {
+        cache_i: usize,
+        lines: Vec<CacheLine>,
+    },
+    /// Whole write-back buffer, tags hold the block number
+    WriteBackBuffer {
+        cache_i: usize,
+        lines: Vec<CacheLine>,
+    },
+    MemWrite {
+        block_i: usize,
+        data: Data,
+    },
+    /// Assumes a miss
+    Alert {
+        processor_i: usize,
+        address: usize,
+        op: MemOp,
+    },
+    StoreConditional {
+        processor_i: usize,
+        success: bool,
+    },
+    /// Address reserved by a load-linked, `None` once the reservation is lost
+    Reservation {
+        processor_i: usize,
+        address: Option<usize>,
+    },
+    RegisterWrite {
+        processor_i: usize,
+        register: Register,
+        data: Data,
+    },
+    Flags {
+        processor_i: usize,
+        flags: Flags,
+    },
+    ProgramCounter {
+        processor_i: usize,
+        pc: usize,
+    },
+    /// Pending writes, oldest first
+    StoreBuffer {
+        processor_i: usize,
+        entries: Vec<(usize, Data)>,
+    },
+    /// Instruction fetched from the loaded program, about to be executed
+    Fetch {
+        processor_i: usize,
+        instruction: Instruction,
+    },
+    /// Stepped past the end of its program with every write performed
+    Halted {
+        processor_i: usize,
+    },
+    BusArbitration {
+        granted: BusSignal,
+        pending: Vec<BusSignal>,
+        wait_stats: Vec<WaitStats>,
+    },
+    /// Transactions past their address phase, waiting for their data
+    BusInFlight {
+        in_flight: Vec<BusSignal>,
+    },
+    NetworkMessage(NetworkMessage),
+    MemoryStats {
+        node: usize,
+        banks: Vec<BankStats>,
+        queued: usize,
+        accesses: NodeStats,
+    },
+    InterconnectStats(InterconnectStats),
+    /// Transactions the home ordered so far, by kind
+    BusTraffic(TrafficStats),
+    CacheActivity {
+        cache_i: usize,
+        activity: CacheActivity,
+    },
+    /// A component failed, the whole system is shutting down
+    Error(SimError),
+    /// A transaction got the bus, its address phase starts
+    BusTransactionStart(BusSignal),
+    /// The requester installed the data of the transaction, or memory took
+    /// the block of a posted write back
+    BusTransactionEnd(BusSignal),
+    /// A cache answered a snooped transaction, with the state its line was in
+    /// and the data it supplied
+    SnoopResponse {
+        cache_i: usize,
+        signal: BusSignal,
+        state: CacheState,
+        data: Option<Data>,
+    },
+    /// The block at `address` changed its state in a cache, wherever its line
+    /// is: in its set, the victim cache or the write-back buffer
+    StateTransition {
+        cache_i: usize,
+        address: usize,
+        old: CacheState,
+        new: CacheState,
+    },
+    /// A line left its set to make room for another block and won't stay in
+    /// the victim cache. Dirty ones go on to the write-back buffer.
+    Eviction {
+        cache_i: usize,
+        address: usize,
+        state: CacheState,
+        data: Data,
+    },
+    /// Memory took a dirty block from the write-back buffer of a cache
+    WriteBack {
+        cache_i: usize,
+        address: usize,
+        data: Data,
+    },
+    /// The CPU is done with the instruction. Stores may still wait in the
+    /// store buffer under a relaxed model.
+    InstructionRetired {
+        processor_i: usize,
+        instruction: Instruction,
+    },
+}
+
+/// Kinds of events, to pick the ones a listener gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    CacheWrite,
+    PrefetchStats,
+    VictimCache,
+    WriteBackBuffer,
+    MemWrite,
+    Alert,
+    StoreConditional,
+    Reservation,
+    RegisterWrite,
+    Flags,
+    ProgramCounter,
+    StoreBuffer,
+    Fetch,
+    Halted,
+    BusArbitration,
+    BusInFlight,
+    NetworkMessage,
+    MemoryStats,
+    InterconnectStats,
+    BusTraffic,
+    CacheActivity,
+    Error,
+    BusTransactionStart,
+    BusTransactionEnd,
+    SnoopResponse,
+    StateTransition,
+    Eviction,
+    WriteBack,
+    InstructionRetired,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 29] = [
+        EventKind::CacheWrite,
+        EventKind::PrefetchStats,
+        EventKind::VictimCache,
+        EventKind::WriteBackBuffer,
+        EventKind::MemWrite,
+        EventKind::Alert,
+        EventKind::StoreConditional,
+        EventKind::Reservation,
+        EventKind::RegisterWrite,
+        EventKind::Flags,
+        EventKind::ProgramCounter,
+        EventKind::StoreBuffer,
+        EventKind::Fetch,
+        EventKind::Halted,
+        EventKind::BusArbitration,
+        EventKind::BusInFlight,
+        EventKind::NetworkMessage,
+        EventKind::MemoryStats,
+        EventKind::InterconnectStats,
+        EventKind::BusTraffic,
+        EventKind::CacheActivity,
+        EventKind::Error,
+        EventKind::BusTransactionStart,
+        EventKind::BusTransactionEnd,
+        EventKind::SnoopResponse,
+        EventKind::StateTransition,
+        EventKind::Eviction,
+        EventKind::WriteBack,
+        EventKind::InstructionRetired,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            EventKind::CacheWrite => "cache-write",
+            EventKind::PrefetchStats => "prefetch-stats",
+            EventKind::VictimCache => "victim-cache",
+            EventKind::WriteBackBuffer => "write-back-buffer",
+            EventKind::MemWrite => "mem-write",
+            EventKind::Alert => "alert",
+            EventKind::StoreConditional => "store-conditional",
+            EventKind::Reservation => "reservation",
+            EventKind::RegisterWrite => "register-write",
+            EventKind::Flags => "flags",
+            EventKind::ProgramCounter => "program-counter",
+            EventKind::StoreBuffer => "store-buffer",
+            EventKind::Fetch => "fetch",
+            EventKind::Halted => "halted",
+            EventKind::BusArbitration => "bus-arbitration",
+            EventKind::BusInFlight => "bus-in-flight",
+            EventKind::NetworkMessage => "network-message",
+            EventKind::MemoryStats => "memory-stats",
+            EventKind::InterconnectStats => "interconnect-stats",
+            EventKind::BusTraffic => "bus-traffic",
+            EventKind::CacheActivity => "cache-activity",
+            EventKind::Error => "error",
+            EventKind::BusTransactionStart => "bus-transaction-start",
+            EventKind::BusTransactionEnd => "bus-transaction-end",
+            EventKind::SnoopResponse => "snoop-response",
+            EventKind::StateTransition => "state-transition",
+            EventKind::Eviction => "eviction",
+            EventKind::WriteBack => "write-back",
+            EventKind::InstructionRetired => "instruction-retired",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        EventKind::ALL
+            .into_iter()
+            .find(|kind| kind.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::CacheWrite { .. } => EventKind::CacheWrite,
+            Event::PrefetchStats { .. } => EventKind::PrefetchStats,
+            Event::VictimCache { .. } => EventKind::VictimCache,
+            Event::WriteBackBuffer { .. } => EventKind::WriteBackBuffer,
+            Event::MemWrite { .. } => EventKind::MemWrite,
+            Event::Alert { .. } => EventKind::Alert,
+            Event::StoreConditional { .. } => EventKind::StoreConditional,
+            Event::Reservation { .. } => EventKind::Reservation,
+            Event::RegisterWrite { .. } => EventKind::RegisterWrite,
+            Event::Flags { .. } => EventKind::Flags,
+            Event::ProgramCounter { .. } => EventKind::ProgramCounter,
+            Event::StoreBuffer { .. } => EventKind::StoreBuffer,
+            Event::Fetch { .. } => EventKind::Fetch,
+            Event::Halted { .. } => EventKind::Halted,
+            Event::BusArbitration { .. } => EventKind::BusArbitration,
+            Event::BusInFlight { .. } => EventKind::BusInFlight,
+            Event::NetworkMessage(_) => EventKind::NetworkMessage,
+            Event::MemoryStats { .. } => EventKind::MemoryStats,
+            Event::InterconnectStats(_) => EventKind::InterconnectStats,
+            Event::BusTraffic(_) => EventKind::BusTraffic,
+            Event::CacheActivity { .. } => EventKind::CacheActivity,
+            Event::Error(_) => EventKind::Error,
+            Event::BusTransactionStart(_) => EventKind::BusTransactionStart,
+            Event::BusTransactionEnd(_) => EventKind::BusTransactionEnd,
+            Event::SnoopResponse { .. } => EventKind::SnoopResponse,
+            Event::StateTransition { .. } => EventKind::StateTransition,
+            Event::Eviction { .. } => EventKind::Eviction,
+            Event::WriteBack { .. } => EventKind::WriteBack,
+            Event::InstructionRetired { .. } => EventKind::InstructionRetired,
+        }
+    }
+}
+
+type Filter = Box<dyn Fn(&Event) -> bool + Send>;
+
+struct Listener {
+    tx: Sender<Event>,
+    filter: Filter,
+}
+
+/// Hands every event a system sends to the listeners interested in it. The
+/// components of the system share it, so listeners can subscribe at any
+/// time, they get the events sent from then on.
+#[derive(Clone, Default)]
+pub struct EventHub {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl EventHub {
+    pub fn new() -> Self { EventHub::default() }
+
+    /// Every event
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.subscribe_filtered(|_| true)
+    }
+
+    /// Events of the given kinds only
+    pub fn subscribe_to(&self, kinds: &[EventKind]) -> Receiver<Event> {
+        let kinds = kinds.to_vec();
+        self.subscribe_filtered(move |event| kinds.contains(&event.kind()))
+    }
+
+    /// Events `filter` accepts only, like the ones of a single CPU or
+    /// address
+    pub fn subscribe_filtered(
+        &self,
+        filter: impl Fn(&Event) -> bool + Send + 'static,
+    ) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.add_listener(tx, Box::new(filter));
+        rx
+    }
+
+    fn add_listener(&self, tx: Sender<Event>, filter: Filter) {
+        self.listeners.lock().unwrap().push(Listener { tx, filter });
+    }
+
+    /// Listeners whose receiver is gone are dropped. Events nobody listens
+    /// to are lost, the system keeps running until it's shut down.
+    pub fn send(&self, event: Event) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.retain(|listener| {
+            !(listener.filter)(&event)
+                || listener.tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// A single listener that gets every event
+impl From<Sender<Event>> for EventHub {
+    fn from(tx: Sender<Event>) -> Self {
+        let hub = EventHub::new();
+        hub.add_listener(tx, Box::new(|_| true));
+        hub
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::models::{
+        instructions::{Address, Instruction},
+        processor::CpuCommand,
+        system::{System, DEFAULT_PROPS},
+    };
+
+    fn read(rd: usize) -> CpuCommand {
+        CpuCommand::Execute(Instruction::Read {
+            rd,
+            address: Address::from(0),
+        })
+    }
+
+    // Retirement of the read into `rd`, anything else is skipped
+    fn wait_retired(events_rx: &Receiver<Event>, rd: usize) {
+        loop {
+            let event = events_rx.recv_timeout(Duration::from_secs(10));
+            match event.unwrap() {
+                Event::InstructionRetired {
+                    instruction: Instruction::Read { rd: read_rd, .. },
+                    ..
+                } if read_rd == rd => return,
+                Event::Error(err) => panic!("{err}"),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn systems_run_without_listeners() {
+        let events = EventHub::new();
+        let mut system =
+            System::new(DEFAULT_PROPS.headless(), &[(0, 3)], events.clone())
+                .unwrap();
+        system.send(0, read(1)).unwrap();
+
+        // A filtered listener that goes away leaves none again
+        let halted = events.subscribe_to(&[EventKind::Halted]);
+        drop(halted);
+        system.send(0, read(2)).unwrap();
+
+        let events_rx = events.subscribe();
+        system.send(0, read(3)).unwrap();
+        wait_retired(&events_rx, 3);
+        system.shutdown();
+    }
+
+    #[test]
+    fn filtered_listeners_get_their_events_only() {
+        let events = EventHub::new();
+        let retired = events.subscribe_to(&[EventKind::InstructionRetired]);
+        let cpu_1 = events.subscribe_filtered(|event| {
+            matches!(event, Event::RegisterWrite { processor_i: 1, .. })
+        });
+        let mut system =
+            System::new(DEFAULT_PROPS.headless(), &[], events).unwrap();
+        system.send(0, read(1)).unwrap();
+        system.send(1, read(2)).unwrap();
+        system.shutdown();
+
+        assert!(retired
+            .try_iter()
+            .all(|event| event.kind() == EventKind::InstructionRetired));
+        let writes: Vec<Event> = cpu_1.try_iter().collect();
+        assert!(!writes.is_empty());
+        assert!(writes.iter().all(|event| matches!(
+            event,
+            Event::RegisterWrite { processor_i: 1, .. }
+        )));
+    }
+}
@@ -0,0 +1,61 @@
+//! Simulator of a multiprocessor with coherent caches. Every CPU, cache
+//! controller, the bus and the memory controllers run on threads of their
+//! own and talk to each other through channels, like the hardware does
+//! through wires.
+//!
+//! - Systems are built from a [`SocProperties`](models::system::SocProperties)
+//!   with [`System::new`](models::system::System::new), starting from
+//!   [`DEFAULT_PROPS`](models::system::DEFAULT_PROPS) is the easiest way. A
+//!   `System` can be reset, rebuilt with other properties and shut down.
+//! - Instructions are given to a CPU with
+//!   [`System::send`](models::system::System::send), one at a time with
+//!   [`CpuCommand::Execute`](models::processor::CpuCommand::Execute) or as a
+//!   whole program with
+//!   [`CpuCommand::LoadProgram`](models::processor::CpuCommand::LoadProgram),
+//!   stepped with [`CpuCommand::Step`](models::processor::CpuCommand::Step).
+//!   Programs are assembled with [`models::assembler::assemble`].
+//! - Everything the system does is reported as an [`events::Event`] on the
+//!   channel it was built with.
+//! - [`state::SystemState`] rebuilds the contents of the caches, memory and
+//!   registers from those events, along with the statistics of the run.
+//!
+//! ```
+//! use std::sync::mpsc::channel;
+//!
+//! use proyecto_1::{
+//!     models::{
+//!         instructions::{Address, Instruction},
+//!         processor::CpuCommand,
+//!         system::{System, DEFAULT_PROPS},
+//!     },
+//!     state::SystemState,
+//! };
+//!
+//! let props = DEFAULT_PROPS.headless();
+//! let (events_tx, events_rx) = channel();
+//! let mut system = System::new(props, &[(0, 42)], events_tx).unwrap();
+//! let read = Instruction::Read {
+//!     rd: 1,
+//!     address: Address::from(0),
+//! };
+//! system.send(0, CpuCommand::Execute(read)).unwrap();
+//! system.shutdown();
+//!
+//! let mut state = SystemState::new(&props);
+//! state.pump(&events_rx);
+//! assert_eq!(state.registers[0][1], 42);
+//! ```
+//!
+//! The GUI, the litmus runner and the design-space sweeps are built on this
+//! API.
+
+pub mod events;
+pub mod litmus;
+pub mod models;
+pub mod programs;
+pub mod random;
+pub mod scenarios;
+pub mod state;
+pub mod sweep;
+pub mod trace;
+pub mod workload;
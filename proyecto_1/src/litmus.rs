@@ -0,0 +1,571 @@
+//! Litmus tests run on the threaded system. A test file looks like:
+//!
+//! ```text
+//! ; Store buffering
+//! name SB
+//! init 0b0000 = 0
+//! cpu 0
+//!     write 0b0000, 1
+//!     read r1, 0b0010
+//! cpu 1
+//!     write 0b0010, 1
+//!     read r1, 0b0000
+//! exists 0:r1 = 0, 1:r1 = 0
+//! ```
+//!
+//! Every `cpu` section holds the program of that processor in the assembler's
+//! syntax. `exists` is the final state predicate, a list of register
+//! (`cpu:rN`) or memory (address) locations with the value they must end up
+//! holding. The outcomes are tabulated over the locations it mentions.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::mpsc::{channel, Receiver},
+};
+
+use crate::{
+    events::Event,
+    models::{
+        assembler,
+        error::SimError,
+        instructions::{parse_number, parse_register, Program, NUM_REGISTERS},
+        processor::CpuCommand,
+        system::{SocProperties, System},
+        Data,
+    },
+    random::UniformRng,
+    state::SystemState,
+};
+
+// Runs that take more steps than this are counted as timed out
+const MAX_STEPS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Register { processor_i: usize, register: usize },
+    Memory(usize),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Register {
+                processor_i,
+                register,
+            } => write!(f, "{processor_i}:r{register}"),
+            Location::Memory(address) => write!(f, "{address:#04b}"),
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once(':') {
+            Some((processor_i, register)) => {
+                let processor_i = processor_i.trim().parse().map_err(|_| {
+                    format!("invalid processor \"{processor_i}\"")
+                })?;
+                let register =
+                    parse_register(register.trim()).ok_or_else(|| {
+                        format!(
+                            "expected a register between r0 and r{}, got \
+                             \"{register}\"",
+                            NUM_REGISTERS - 1
+                        )
+                    })?;
+                Ok(Location::Register {
+                    processor_i,
+                    register,
+                })
+            }
+            None => parse_number(text)
+                .map(Location::Memory)
+                .map_err(|err| format!("invalid location \"{text}\" ({err})")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LitmusTest {
+    pub name: String,
+    pub init: Vec<(usize, Data)>,
+    /// Indexed by processor, missing ones run an empty program
+    pub programs: Vec<Program>,
+    pub exists: Vec<(Location, Data)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseLitmusError(pub String);
+
+impl fmt::Display for ParseLitmusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseLitmusError {}
+
+impl LitmusTest {
+    /// Whether the test fits in a valid system with `props`, every program and
+    /// every register `exists` names needs its processor. Memory addresses
+    /// wrap around like the rest of the system's do.
+    pub fn check(&self, props: &SocProperties) -> Result<(), String> {
+        props.validate().map_err(|err| err.to_string())?;
+        if self.programs.len() > props.num_processors {
+            return Err(format!(
+                "the test needs {} processors, the system has {}",
+                self.programs.len(),
+                props.num_processors
+            ));
+        }
+        for (location, _) in &self.exists {
+            if let Location::Register { processor_i, .. } = *location {
+                if processor_i >= props.num_processors {
+                    return Err(format!(
+                        "exists names {location}, the system has {} \
+                         processors",
+                        props.num_processors
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// `location = value`
+fn parse_constraint(text: &str) -> Result<(Location, Data), String> {
+    let (location, value) = text.split_once('=').ok_or_else(|| {
+        format!("expected \"location = value\", got \"{text}\"")
+    })?;
+    let value = parse_number(value)
+        .map_err(|err| format!("invalid value \"{}\" ({err})", value.trim()))?;
+    Ok((location.trim().parse()?, value))
+}
+
+impl FromStr for LitmusTest {
+    type Err = ParseLitmusError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut test = LitmusTest::default();
+        // Source of every processor's program, along with the line of the
+        // file each of its lines comes from
+        let mut sources: BTreeMap<usize, (Vec<usize>, String)> =
+            BTreeMap::new();
+        let mut current_cpu = None;
+
+        for (line_i, line) in text.lines().enumerate() {
+            let error = |message: String| {
+                ParseLitmusError(format!("line {}: {message}", line_i + 1))
+            };
+            let code = line.split(';').next().unwrap_or("").trim();
+            let (keyword, rest) =
+                code.split_once(char::is_whitespace).unwrap_or((code, ""));
+            let rest = rest.trim();
+
+            match keyword {
+                "name" => test.name = rest.to_owned(),
+                "init" => test.init.push(
+                    match parse_constraint(rest).map_err(error)? {
+                        (Location::Memory(address), value) => (address, value),
+                        (location, _) => {
+                            return Err(error(format!(
+                                "only memory can be initialized, got \
+                                 {location}"
+                            )))
+                        }
+                    },
+                ),
+                "cpu" => {
+                    let processor_i = rest.parse().map_err(|_| {
+                        error(format!("invalid processor \"{rest}\""))
+                    })?;
+                    if sources.contains_key(&processor_i) {
+                        return Err(error(format!(
+                            "processor {processor_i} already has a program"
+                        )));
+                    }
+                    sources.insert(processor_i, (Vec::new(), String::new()));
+                    current_cpu = Some(processor_i);
+                }
+                "exists" => {
+                    for constraint in rest.split(',') {
+                        test.exists
+                            .push(parse_constraint(constraint).map_err(error)?);
+                    }
+                }
+                "" => (),
+                _ => match current_cpu {
+                    Some(processor_i) => {
+                        let (lines, source) =
+                            sources.get_mut(&processor_i).unwrap();
+                        lines.push(line_i);
+                        source.push_str(line);
+                        source.push('\n');
+                    }
+                    None => {
+                        return Err(error(format!(
+                            "\"{code}\" is outside of a cpu section"
+                        )))
+                    }
+                },
+            }
+        }
+
+        if test.exists.is_empty() {
+            return Err(ParseLitmusError("missing exists clause".to_owned()));
+        }
+
+        let num_programs = sources.keys().next_back().map_or(0, |&i| i + 1);
+        test.programs = vec![Program::default(); num_programs];
+        for (processor_i, (lines, source)) in sources {
+            let assembly = assembler::assemble(&source).map_err(|errors| {
+                let err = &errors[0];
+                ParseLitmusError(format!(
+                    "line {}: {}",
+                    lines[err.line] + 1,
+                    err.message
+                ))
+            })?;
+            test.programs[processor_i] = assembly.program;
+        }
+
+        Ok(test)
+    }
+}
+
+// Final values of the locations a test looks at, in `exists` order
+type Outcome = Vec<Data>;
+
+pub struct LitmusResults {
+    pub test: LitmusTest,
+    pub runs: usize,
+    pub timeouts: usize,
+    /// Why every run whose system failed did, a broken protocol rather than
+    /// a slow one
+    pub errors: Vec<SimError>,
+    pub outcomes: BTreeMap<Outcome, usize>,
+}
+
+impl LitmusResults {
+    fn satisfies(&self, outcome: &Outcome) -> bool {
+        self.test
+            .exists
+            .iter()
+            .zip(outcome)
+            .all(|((_, expected), value)| expected == value)
+    }
+
+    /// Runs in which the predicate held
+    pub fn matching(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|(outcome, _)| self.satisfies(outcome))
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+impl fmt::Display for LitmusResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Test {}", self.test.name)?;
+        for (outcome, count) in &self.outcomes {
+            let outcome_text: Vec<String> = self
+                .test
+                .exists
+                .iter()
+                .zip(outcome)
+                .map(|((location, _), value)| format!("{location}={value}"))
+                .collect();
+            let marker = if self.satisfies(outcome) { " *" } else { "" };
+            writeln!(f, "{count:>8} {}{marker}", outcome_text.join(" "))?;
+        }
+        if self.timeouts > 0 {
+            writeln!(f, "{:>8} timed out", self.timeouts)?;
+        }
+        if let Some(error) = self.errors.first() {
+            writeln!(
+                f,
+                "{:>8} failed, first with: {error}",
+                self.errors.len()
+            )?;
+        }
+        write!(
+            f,
+            "Exists: {} of {} runs (marked with *)",
+            self.matching(),
+            self.runs
+        )
+    }
+}
+
+// Value of `location` once every processor halted
+fn value(state: &SystemState, location: Location) -> Data {
+    match location {
+        Location::Register {
+            processor_i,
+            register,
+        } => state.registers[processor_i][register],
+        Location::Memory(address) => state.memory_value(address),
+    }
+}
+
+// Runs the test once, stepping a random processor at a time. Returns `None`
+// if it didn't finish within `MAX_STEPS`, and the error of the component that
+// failed if the system stopped.
+fn run_once(
+    test: &LitmusTest,
+    props: SocProperties,
+) -> Result<Option<Outcome>, SimError> {
+    let (events_tx, events_rx) = channel();
+    let mut system =
+        System::new(props, &test.init, events_tx).expect("checked by `run`");
+    let mut state = SystemState::new(&props);
+    let result = steps(test, &system, &mut state, &events_rx);
+    if result.is_err() {
+        // The failed component reports why once everything stopped
+        system.shutdown();
+        state.pump(&events_rx);
+    }
+    match state.error.take() {
+        Some(error) => Err(error),
+        None => result,
+    }
+}
+
+fn steps(
+    test: &LitmusTest,
+    system: &System,
+    state: &mut SystemState,
+    events_rx: &Receiver<Event>,
+) -> Result<Option<Outcome>, SimError> {
+    let num_processors = system.props().num_processors;
+    for i in 0..num_processors {
+        let program = test.programs.get(i).cloned().unwrap_or_default();
+        system.send(i, CpuCommand::LoadProgram(program))?;
+    }
+
+    let mut rng = UniformRng::from_seed(system.props().seed);
+    for _ in 0..MAX_STEPS {
+        state.pump(events_rx);
+        if let Some(error) = state.error.take() {
+            return Err(error);
+        }
+        let running: Vec<usize> =
+            (0..num_processors).filter(|&i| !state.halted[i]).collect();
+        if running.is_empty() {
+            return Ok(Some(
+                test.exists
+                    .iter()
+                    .map(|&(location, _)| value(state, location))
+                    .collect(),
+            ));
+        }
+
+        let cpu_i = running[rng.gen_range(0..running.len() as u32) as usize];
+        system.send(cpu_i, CpuCommand::Step)?;
+    }
+    Ok(None)
+}
+
+/// Every run gets its own system and seed, starting at `props.seed`. Fails if
+/// the test doesn't pass `check` with `props`.
+pub fn run(
+    test: &LitmusTest,
+    props: SocProperties,
+    runs: usize,
+) -> Result<LitmusResults, String> {
+    test.check(&props)?;
+    let mut results = LitmusResults {
+        test: test.clone(),
+        runs,
+        timeouts: 0,
+        errors: Vec::new(),
+        outcomes: BTreeMap::new(),
+    };
+
+    for run_i in 0..runs {
+        let props = SocProperties {
+            seed: props.seed.wrapping_add(run_i as u32),
+            ..props
+        };
+        match run_once(test, props) {
+            Ok(Some(outcome)) => {
+                *results.outcomes.entry(outcome).or_insert(0) += 1
+            }
+            Ok(None) => results.timeouts += 1,
+            Err(error) => results.errors.push(error),
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+    use crate::models::{
+        instructions::{Address, Instruction, Operand},
+        system::DEFAULT_PROPS,
+    };
+
+    const STORE_BUFFERING: &str = "\
+; Store buffering
+name SB
+init 0b0000 = 0
+init 0x2 = 7 ; hex addresses work too
+cpu 1
+    write 0b0010, 1
+    read r1, 0b0000
+cpu 0
+    write 0b0000, 1
+    read r1, 0b0010
+exists 0:r1 = 0, 1 : r1=0, 0b0010 = 0x1
+";
+
+    fn parse_error(text: &str) -> String {
+        text.parse::<LitmusTest>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_test() {
+        let test: LitmusTest = STORE_BUFFERING.parse().unwrap();
+        assert_eq!(test.name, "SB");
+        assert_eq!(test.init, [(0, 0), (2, 7)]);
+        assert_eq!(test.programs.len(), 2);
+        assert_eq!(
+            test.programs[0].get(0),
+            Some(&Instruction::Write {
+                address: Address::from(0),
+                src: Operand::Immediate(1),
+            })
+        );
+        assert_eq!(
+            test.exists,
+            [
+                (
+                    Location::Register {
+                        processor_i: 0,
+                        register: 1
+                    },
+                    0
+                ),
+                (
+                    Location::Register {
+                        processor_i: 1,
+                        register: 1
+                    },
+                    0
+                ),
+                (Location::Memory(2), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_processors_run_empty_programs() {
+        let test: LitmusTest =
+            "cpu 2\n    calc\nexists 2:r0 = 0".parse().unwrap();
+        assert_eq!(test.programs.len(), 3);
+        assert!(test.programs[0].is_empty());
+        assert!(!test.programs[2].is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_exists_clauses() {
+        let cpu = "cpu 0\n    calc\n";
+        assert_eq!(parse_error(cpu), "missing exists clause");
+        assert_eq!(
+            parse_error(&format!("{cpu}exists 0:r1")),
+            "line 3: expected \"location = value\", got \"0:r1\""
+        );
+        assert_eq!(
+            parse_error(&format!("{cpu}exists 0:r8 = 1")),
+            "line 3: expected a register between r0 and r7, got \"r8\""
+        );
+        assert_eq!(
+            parse_error(&format!("{cpu}exists x:r1 = 1")),
+            "line 3: invalid processor \"x\""
+        );
+        assert!(parse_error(&format!("{cpu}exists 0:r1 = 1,"))
+            .starts_with("line 3: expected \"location = value\""));
+        assert!(parse_error(&format!("{cpu}exists 0:r1 = one"))
+            .starts_with("line 3: invalid value \"one\""));
+        assert!(parse_error(&format!("{cpu}exists r1 = 1"))
+            .starts_with("line 3: invalid location \"r1\""));
+    }
+
+    #[test]
+    fn rejects_bad_sections() {
+        assert_eq!(
+            parse_error("init 0:r1 = 1\nexists 0 = 0"),
+            "line 1: only memory can be initialized, got 0:r1"
+        );
+        assert_eq!(
+            parse_error("    calc\nexists 0 = 0"),
+            "line 1: \"calc\" is outside of a cpu section"
+        );
+        assert_eq!(
+            parse_error("cpu 0\ncpu 0\nexists 0 = 0"),
+            "line 2: processor 0 already has a program"
+        );
+        assert_eq!(
+            parse_error("cpu zero\nexists 0 = 0"),
+            "line 1: invalid processor \"zero\""
+        );
+    }
+
+    #[test]
+    fn assembler_errors_point_at_the_file_line() {
+        let text = "cpu 0\n    calc\ncpu 1\n    calc\n    jmp r1\nexists 0 = 0";
+        assert_eq!(parse_error(text), "line 5: unknown instruction \"jmp\"");
+    }
+
+    #[test]
+    fn checks_the_test_fits_the_system() {
+        let props = DEFAULT_PROPS.headless();
+        let test: LitmusTest = STORE_BUFFERING.parse().unwrap();
+        assert!(test.check(&props).is_ok());
+
+        let test: LitmusTest =
+            format!("{STORE_BUFFERING}exists 4:r1 = 0").parse().unwrap();
+        assert_eq!(
+            test.check(&props).unwrap_err(),
+            "exists names 4:r1, the system has 4 processors"
+        );
+
+        let test: LitmusTest = "cpu 4\n    calc\nexists 0 = 0".parse().unwrap();
+        assert_eq!(
+            test.check(&props).unwrap_err(),
+            "the test needs 5 processors, the system has 4"
+        );
+    }
+
+    #[test]
+    fn memory_locations_wrap_around() {
+        let mut state = SystemState::new(&DEFAULT_PROPS.headless());
+        state.memory[1] = 9;
+        let address_space = state.memory.len() * size_of::<Data>();
+        let location = Location::Memory(address_space + size_of::<Data>());
+        assert_eq!(value(&state, location), 9);
+    }
+
+    #[test]
+    fn runs_count_every_outcome() {
+        let props = DEFAULT_PROPS.headless();
+        let test: LitmusTest = STORE_BUFFERING.parse().unwrap();
+        let results = run(&test, props, 5).unwrap();
+        assert!(results.errors.is_empty());
+        assert_eq!(results.timeouts, 0);
+        assert_eq!(results.outcomes.values().sum::<usize>(), 5);
+
+        let test: LitmusTest = "cpu 4\n    calc\nexists 0 = 0".parse().unwrap();
+        assert!(run(&test, props, 5).is_err());
+    }
+}
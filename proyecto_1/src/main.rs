@@ -1,37 +1,484 @@
-#![feature(mutex_unlock)]
-#![feature(variant_count)]
 mod app;
 
-mod models;
-mod random;
-
-use std::sync::mpsc::channel;
+use std::{
+    env, error::Error, fs, num::NonZeroUsize, process, sync::mpsc::channel,
+    thread,
+};
 
 use app::AppState;
-use models::system::{self, SocProperties};
-
-const SYSTEM_PROPS: SocProperties = SocProperties {
-    num_processors: 4,
-    cache_associativity: 2,
-    cache_sets: 2,
-    main_memory_blocks: 8,
+use proyecto_1::{
+    litmus::{self, LitmusTest},
+    models::{
+        arbiter::ArbitrationPolicy,
+        cache::{CoherenceProtocol, ReplacementPolicy, WritePolicy},
+        consistency::ConsistencyModel,
+        dram::RowPolicy,
+        energy::EnergyModel,
+        interconnect::{CoherenceScheme, Topology},
+        prefetcher::PrefetchPolicy,
+        system::{SocProperties, System, DEFAULT_PROPS},
+    },
+    scenarios::SharingPattern,
+    state::Stats,
+    sweep::{self, Simulator, SweepConfig, SweepInput, SweepResults},
+    trace::Trace,
+    workload::{
+        AddressDistribution, InstructionKind, WorkloadConfig, DEFAULT_WORKLOAD,
+    },
 };
 
+const SYSTEM_OPTIONS: &str = "[--processors N] [--associativity N] \
+                              [--sets N] [--memory-blocks N] [--seed N] \
+                              [--arbitration \
+                              round-robin|fixed-priority|fcfs|lottery] \
+                              [--topology bus|ring|mesh|crossbar] \
+                              [--coherence snooping|directory] [--banks N] \
+                              [--row-policy open|closed] [--nodes N] \
+                              [--interleave BLOCKS] [--victim-cache N] \
+                              [--write-back-buffer N] \
+                              [--prefetch off|next-line|stride] \
+                              [--prefetch-degree N] \
+                              [--write-policy write-back|write-through] \
+                              [--no-write-allocate] \
+                              [--protocol MOESI|MESI|MSI|VI] \
+                              [--replacement state|lru|fifo] \
+                              [--energy EVENT=PICOJOULES,...]";
+
+const WORKLOAD_OPTIONS: &str = "[--scenario \
+                                migratory|producer-consumer|read-mostly|\
+                                wide-sharing|false-sharing] \
+                                [--mix KIND=WEIGHT,...] [--addresses \
+                                uniform|binomial|zipf|hot-cold] \
+                                [--binomial-percent P] [--zipf-exponent S] \
+                                [--hot-cold BLOCKS%,ACCESSES%] \
+                                [--private-blocks N] [--shared-percent P] \
+                                [--locality P]";
+
+const SWEEP_USAGE: &str =
+    "usage: proyecto_1 sweep [--vary OPTION=VALUES]... [--instructions N] \
+     [--trace FILE] [--engine fast|threaded] [--jobs N] [--output FILE] \
+     [--model sc|tso|pso] <system options> <workload options>\nVALUES is a comma separated list of values and \
+     ranges, A..B goes up by one and A..B*F multiplies by F";
+
+const LITMUS_USAGE: &str =
+    "usage: proyecto_1 litmus <test file> [--runs N] [--model sc|tso|pso] \
+     <system options>";
+
+// Options of the simulated system shared by the GUI and the litmus runner.
+// Returns false if `arg` isn't one of them.
+fn system_option<'a>(
+    props: &mut SocProperties,
+    arg: &str,
+    value: impl FnOnce() -> Result<&'a String, String>,
+) -> Result<bool, Box<dyn Error>> {
+    match arg {
+        "--processors" => props.num_processors = value()?.parse()?,
+        "--associativity" => props.cache_associativity = value()?.parse()?,
+        "--sets" => props.cache_sets = value()?.parse()?,
+        "--memory-blocks" => props.main_memory_blocks = value()?.parse()?,
+        "--seed" => props.seed = value()?.parse()?,
+        "--arbitration" => {
+            let name = value()?;
+            props.arbitration = ArbitrationPolicy::from_name(name)
+                .ok_or(format!("unknown arbitration policy \"{name}\""))?;
+        }
+        "--topology" => {
+            let name = value()?;
+            props.topology = Topology::from_name(name)
+                .ok_or(format!("unknown topology \"{name}\""))?;
+        }
+        "--coherence" => {
+            let name = value()?;
+            props.coherence = CoherenceScheme::from_name(name)
+                .ok_or(format!("unknown coherence scheme \"{name}\""))?;
+        }
+        "--banks" => props.dram.banks = value()?.parse()?,
+        "--row-policy" => {
+            let name = value()?;
+            props.dram.row_policy = RowPolicy::from_name(name)
+                .ok_or(format!("unknown row policy \"{name}\""))?;
+        }
+        "--nodes" => props.numa.nodes = value()?.parse()?,
+        "--interleave" => props.numa.interleave = value()?.parse()?,
+        "--victim-cache" => props.victim_cache = value()?.parse()?,
+        "--write-back-buffer" => props.write_back_buffer = value()?.parse()?,
+        "--prefetch" => {
+            let name = value()?;
+            props.prefetch = PrefetchPolicy::from_name(name)
+                .ok_or(format!("unknown prefetch policy \"{name}\""))?;
+        }
+        "--prefetch-degree" => props.prefetch_degree = value()?.parse()?,
+        "--write-policy" => {
+            let name = value()?;
+            props.write_policy = WritePolicy::from_name(name)
+                .ok_or(format!("unknown write policy \"{name}\""))?;
+        }
+        "--no-write-allocate" => props.write_allocate = false,
+        "--protocol" => {
+            let name = value()?;
+            props.protocol = CoherenceProtocol::from_name(name)
+                .ok_or(format!("unknown coherence protocol \"{name}\""))?;
+        }
+        "--replacement" => {
+            let name = value()?;
+            props.replacement = ReplacementPolicy::from_name(name)
+                .ok_or(format!("unknown replacement policy \"{name}\""))?;
+        }
+        // Events left out keep their energy
+        "--energy" => {
+            for entry in value()?.split(',') {
+                let (key, picojoules) = entry.split_once('=').ok_or(
+                    format!("expected EVENT=PICOJOULES, got \"{entry}\""),
+                )?;
+                if !props.energy.set(key, picojoules.parse()?) {
+                    return Err(format!(
+                        "unknown energy event \"{key}\", expected one of {}",
+                        EnergyModel::KEYS.join(", ")
+                    )
+                    .into());
+                }
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Checks the options once all of them are known, some depend on others
+fn check_props(
+    props: &SocProperties,
+    workload: &WorkloadConfig,
+) -> Result<(), Box<dyn Error>> {
+    props.validate()?;
+    workload.validate(props)?;
+    Ok(())
+}
+
+// Runs a litmus test file, under every consistency model unless one is given
+fn litmus_cli(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut runs = 1000;
+    let mut props = DEFAULT_PROPS.headless();
+    let mut models = ConsistencyModel::ALL.to_vec();
+
+    let usage = format!("{LITMUS_USAGE}\nsystem options: {SYSTEM_OPTIONS}");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(usage.clone());
+        if system_option(&mut props, arg, &mut value)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--runs" => runs = value()?.parse()?,
+            "--model" => {
+                let name = value()?;
+                models = vec![ConsistencyModel::from_name(name)
+                    .ok_or(format!("unknown consistency model \"{name}\""))?];
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let path = path.ok_or(usage)?;
+    check_props(&props, &DEFAULT_WORKLOAD)?;
+    let test: LitmusTest = fs::read_to_string(path)?.parse()?;
+    test.check(&props)?;
+
+    for consistency_model in models {
+        let props = SocProperties {
+            consistency_model,
+            ..props
+        };
+        println!("Model {}", consistency_model.get_name());
+        println!("{}\n", litmus::run(&test, props, runs)?);
+    }
+    Ok(())
+}
+
+// Options of the workload the GUI's automatic mode generates. Returns false
+// if `arg` isn't one of them.
+fn workload_option<'a>(
+    config: &mut WorkloadConfig,
+    arg: &str,
+    value: impl FnOnce() -> Result<&'a String, String>,
+) -> Result<bool, Box<dyn Error>> {
+    match arg {
+        "--scenario" => {
+            let name = value()?;
+            config.scenario = Some(
+                SharingPattern::from_name(name)
+                    .ok_or(format!("unknown scenario \"{name}\""))?,
+            );
+        }
+        // Kinds left out don't show up
+        "--mix" => {
+            config.mix = [0; InstructionKind::ALL.len()];
+            for entry in value()?.split(',') {
+                let (name, weight) = entry
+                    .split_once('=')
+                    .ok_or(format!("expected KIND=WEIGHT, got \"{entry}\""))?;
+                let kind = InstructionKind::from_name(name)
+                    .ok_or(format!("unknown instruction kind \"{name}\""))?;
+                config.mix[kind as usize] = weight.parse()?;
+            }
+        }
+        "--addresses" => {
+            let name = value()?;
+            config.distribution = AddressDistribution::from_name(name)
+                .ok_or(format!("unknown address distribution \"{name}\""))?;
+        }
+        "--binomial-percent" => config.binomial_percent = value()?.parse()?,
+        "--zipf-exponent" => config.zipf_exponent = value()?.parse()?,
+        "--hot-cold" => {
+            let text = value()?;
+            let (blocks, accesses) = text
+                .split_once(',')
+                .ok_or(format!("expected BLOCKS%,ACCESSES%, got \"{text}\""))?;
+            config.hot_blocks_percent = blocks.trim_end_matches('%').parse()?;
+            config.hot_accesses_percent =
+                accesses.trim_end_matches('%').parse()?;
+        }
+        "--private-blocks" => config.private_blocks = value()?.parse()?,
+        "--shared-percent" => config.shared_percent = value()?.parse()?,
+        "--locality" => config.locality_percent = value()?.parse()?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn gui_props(
+    args: &[String],
+) -> Result<(SocProperties, WorkloadConfig), Box<dyn Error>> {
+    let usage =
+        format!("usage: proyecto_1 {SYSTEM_OPTIONS}\n{WORKLOAD_OPTIONS}");
+    let mut props = DEFAULT_PROPS;
+    let mut workload = DEFAULT_WORKLOAD;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(usage.clone());
+        if !system_option(&mut props, arg, &mut value)?
+            && !workload_option(&mut workload, arg, &mut value)?
+        {
+            return Err(usage.into());
+        }
+    }
+
+    check_props(&props, &workload)?;
+    Ok((props, workload))
+}
+
+// Expands the values of a swept option, see `SWEEP_USAGE`
+fn sweep_values(text: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut values = Vec::new();
+    for item in text.split(',') {
+        let Some((start, end)) = item.split_once("..") else {
+            values.push(item.to_owned());
+            continue;
+        };
+        let (end, factor) = match end.split_once('*') {
+            Some((end, factor)) => (end, Some(factor.parse::<usize>()?)),
+            None => (end, None),
+        };
+        if factor.is_some_and(|factor| factor < 2) {
+            return Err(format!("\"{item}\" never gets to its end").into());
+        }
+        let (mut value, end): (usize, usize) = (start.parse()?, end.parse()?);
+        while value <= end {
+            values.push(value.to_string());
+            value = factor.map_or(value + 1, |factor| value * factor);
+            if value == 0 {
+                return Err(format!("\"{item}\" never gets to its end").into());
+            }
+        }
+    }
+    Ok(values)
+}
+
+// Options of a single point of the design space
+fn sweep_option<'a>(
+    props: &mut SocProperties,
+    workload: &mut WorkloadConfig,
+    arg: &str,
+    value: impl FnOnce() -> Result<&'a String, String>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut value = Some(value);
+    let mut value = || value.take().unwrap()();
+    if system_option(props, arg, &mut value)?
+        || workload_option(workload, arg, &mut value)?
+    {
+        return Ok(true);
+    }
+    match arg {
+        "--model" => {
+            let name = value()?;
+            props.consistency_model = ConsistencyModel::from_name(name)
+                .ok_or(format!("unknown consistency model \"{name}\""))?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Runs the workload on every combination of the swept options and writes
+// their statistics as CSV
+fn sweep_cli(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut props = DEFAULT_PROPS.headless();
+    let mut workload = DEFAULT_WORKLOAD;
+    let mut instructions = 1000;
+    let mut trace = None;
+    let mut simulator = Simulator::Fast;
+    let mut jobs = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let mut output = None;
+    // Option and its values, outermost first
+    let mut axes: Vec<(String, Vec<String>)> = Vec::new();
+
+    let usage = format!(
+        "{SWEEP_USAGE}\nsystem options: {SYSTEM_OPTIONS}\nworkload options: \
+         {WORKLOAD_OPTIONS}"
+    );
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(usage.clone());
+        if sweep_option(&mut props, &mut workload, arg, &mut value)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--vary" => {
+                let text = value()?;
+                let (option, values) = text
+                    .split_once('=')
+                    .ok_or(format!("expected OPTION=VALUES, got \"{text}\""))?;
+                let option = option.trim_start_matches("--");
+                axes.push((option.to_owned(), sweep_values(values)?));
+            }
+            "--instructions" => instructions = value()?.parse()?,
+            "--trace" => {
+                let path = value()?;
+                trace = Some(fs::read_to_string(path)?.parse::<Trace>()?);
+            }
+            "--engine" => {
+                let name = value()?;
+                simulator = Simulator::from_name(name)
+                    .ok_or(format!("unknown engine \"{name}\""))?;
+            }
+            "--jobs" => match value()?.parse()? {
+                0 => return Err("there must be at least one job".into()),
+                n => jobs = n,
+            },
+            "--output" => output = Some(value()?),
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let mut configs = vec![SweepConfig {
+        values: Vec::new(),
+        props,
+        workload,
+    }];
+    for (option, values) in &axes {
+        let arg = format!("--{option}");
+        let mut expanded = Vec::with_capacity(configs.len() * values.len());
+        for config in &configs {
+            for value in values {
+                let mut config = config.clone();
+                let applied = sweep_option(
+                    &mut config.props,
+                    &mut config.workload,
+                    &arg,
+                    || Ok(value),
+                )
+                .map_err(|err| format!("{arg} {value}: {err}"))?;
+                if !applied {
+                    return Err(
+                        format!("can't vary unknown option {arg}").into()
+                    );
+                }
+                config.values.push(value.clone());
+                expanded.push(config);
+            }
+        }
+        configs = expanded;
+    }
+
+    let input = match trace {
+        Some(trace) => SweepInput::Trace(trace),
+        None => SweepInput::Workload(instructions),
+    };
+    // Combinations that make no sense are left out of the table
+    configs.retain(|config| match config.validate(&input) {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("Skipping {}: {err}", config.values.join(", "));
+            false
+        }
+    });
+
+    let results = sweep::run(&configs, &input, simulator, jobs, |i, stats| {
+        let values = configs[i].values.join(", ");
+        match stats {
+            Ok(_) => eprintln!(
+                "Configuration {}/{} done: {values}",
+                i + 1,
+                configs.len()
+            ),
+            Err(err) => eprintln!("Skipping {values}: {err}"),
+        }
+    });
+    // Failed configurations are left out of the table too
+    let (configs, stats): (Vec<SweepConfig>, Vec<Stats>) = configs
+        .into_iter()
+        .zip(results)
+        .filter_map(|(config, stats)| Some((config, stats.ok()?)))
+        .unzip();
+    let options: Vec<String> =
+        axes.into_iter().map(|(option, _)| option).collect();
+    let csv = SweepResults {
+        simulator,
+        options: &options,
+        configs: &configs,
+        stats: &stats,
+    }
+    .to_string();
+    match output {
+        Some(path) => fs::write(path, csv)?,
+        None => print!("{csv}"),
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), eframe::Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("litmus") => Some(litmus_cli(&args[1..])),
+        Some("sweep") => Some(sweep_cli(&args[1..])),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(err) = result {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let (props, workload) = gui_props(&args).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
+
     let (gui_events_tx, gui_events_rx) = channel();
 
-    let instruction_inputs = system::init_system(SYSTEM_PROPS, gui_events_tx);
+    let system = System::new(props, &[], gui_events_tx).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
 
     eframe::run_native(
         "Cache Sim",
         eframe::NativeOptions::default(),
-        Box::new(|cc| {
-            Box::new(AppState::new(
-                cc,
-                gui_events_rx,
-                instruction_inputs,
-                SYSTEM_PROPS,
-            ))
+        Box::new(move |cc| {
+            Box::new(AppState::new(cc, gui_events_rx, system, workload))
         }),
     )
 }
@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use crate::{models::bus::BusSignal, random::UniformRng};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArbitrationPolicy {
+    /// Takes turns starting after the last processor that got the bus
+    RoundRobin,
+    /// Lower processor numbers always win, the rest can starve
+    FixedPriority,
+    FirstComeFirstServed,
+    /// Every pending request has the same chance of winning
+    Lottery,
+}
+
+impl ArbitrationPolicy {
+    pub const ALL: [ArbitrationPolicy; 4] = [
+        ArbitrationPolicy::RoundRobin,
+        ArbitrationPolicy::FixedPriority,
+        ArbitrationPolicy::FirstComeFirstServed,
+        ArbitrationPolicy::Lottery,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            ArbitrationPolicy::RoundRobin => "round-robin",
+            ArbitrationPolicy::FixedPriority => "fixed-priority",
+            ArbitrationPolicy::FirstComeFirstServed => "fcfs",
+            ArbitrationPolicy::Lottery => "lottery",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ArbitrationPolicy> {
+        ArbitrationPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// How long a processor's requests waited for the bus
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WaitStats {
+    pub requests: usize,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    /// Most requests granted ahead of a single one of this processor's
+    pub max_bypassed: usize,
+}
+
+impl WaitStats {
+    pub fn average_wait(&self) -> Duration {
+        match self.requests {
+            0 => Duration::ZERO,
+            requests => self.total_wait / requests as u32,
+        }
+    }
+}
+
+struct PendingRequest {
+    signal: BusSignal,
+    arrival: Instant,
+    bypassed: usize,
+}
+
+pub struct Arbiter {
+    policy: ArbitrationPolicy,
+    // In arrival order
+    pending: Vec<PendingRequest>,
+    last_granted: Option<usize>,
+    rng: UniformRng,
+    stats: Vec<WaitStats>,
+}
+
+impl Arbiter {
+    pub fn new(
+        policy: ArbitrationPolicy,
+        num_processors: usize,
+        seed: u32,
+    ) -> Self {
+        Arbiter {
+            policy,
+            pending: Vec::new(),
+            last_granted: None,
+            rng: UniformRng::from_seed(seed),
+            stats: vec![WaitStats::default(); num_processors],
+        }
+    }
+
+    pub fn push(&mut self, signal: BusSignal) {
+        self.pending.push(PendingRequest {
+            signal,
+            arrival: Instant::now(),
+            bypassed: 0,
+        });
+    }
+
+    pub fn pending(&self) -> Vec<BusSignal> {
+        self.pending.iter().map(|request| request.signal).collect()
+    }
+
+    pub fn stats(&self) -> &[WaitStats] { &self.stats }
+
+    /// Whether any pending request is allowed to go, see `grant_next`
+    pub fn can_grant(&self, can_go: impl Fn(&BusSignal) -> bool) -> bool {
+        self.pending.iter().any(|request| can_go(&request.signal))
+    }
+
+    /// Removes the request that gets the bus next according to the policy.
+    /// Only the requests `can_go` accepts compete, the rest keep waiting.
+    pub fn grant_next(
+        &mut self,
+        can_go: impl Fn(&BusSignal) -> bool,
+    ) -> Option<BusSignal> {
+        let num_processors = self.stats.len();
+        // Indices into `pending` along with their origin, in arrival order
+        let candidates: Vec<(usize, usize)> = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, request)| can_go(&request.signal))
+            .map(|(i, request)| (i, request.signal.origin))
+            .collect();
+        let chosen = match self.policy {
+            _ if candidates.is_empty() => None,
+            ArbitrationPolicy::FirstComeFirstServed => Some(candidates[0]),
+            ArbitrationPolicy::FixedPriority => {
+                candidates.iter().copied().min_by_key(|&(_, origin)| origin)
+            }
+            ArbitrationPolicy::RoundRobin => {
+                let next = self.last_granted.map_or(0, |last| last + 1);
+                candidates.iter().copied().min_by_key(|&(_, origin)| {
+                    (origin + num_processors - next) % num_processors
+                })
+            }
+            ArbitrationPolicy::Lottery => Some(
+                candidates
+                    [self.rng.gen_range(0..candidates.len() as u32) as usize],
+            ),
+        }
+        .map(|(i, _)| i)?;
+
+        let request = self.pending.remove(chosen);
+        for other in &mut self.pending {
+            other.bypassed += 1;
+        }
+
+        let wait = request.arrival.elapsed();
+        let stats = &mut self.stats[request.signal.origin];
+        stats.requests += 1;
+        stats.total_wait += wait;
+        stats.max_wait = stats.max_wait.max(wait);
+        stats.max_bypassed = stats.max_bypassed.max(request.bypassed);
+
+        self.last_granted = Some(request.signal.origin);
+        Some(request.signal)
+    }
+}
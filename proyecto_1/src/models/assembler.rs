@@ -0,0 +1,245 @@
+use std::{collections::HashSet, fmt};
+
+use crate::models::instructions::{Instruction, Program};
+
+// Everything after this character in a line is ignored
+const COMMENT_CHAR: char = ';';
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+    /// Zero based line of the source the error was found in
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line + 1, self.message)
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+/// An assembled program along with the source line every instruction came
+/// from, so the program counter can be traced back to the text
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    pub program: Program,
+    pub source_lines: Vec<usize>,
+}
+
+impl Assembly {
+    pub fn source_line(&self, pc: usize) -> Option<usize> {
+        self.source_lines.get(pc).copied()
+    }
+}
+
+// Splits a line into its statements, a label can share its line with an
+// instruction (`loop: add r1, r1, 1`)
+fn statements(line: &str) -> Vec<&str> {
+    let line = match line.split_once(COMMENT_CHAR) {
+        Some((code, _comment)) => code,
+        None => line,
+    }
+    .trim();
+
+    match line.split_once(':') {
+        Some((label, rest)) if !rest.trim().is_empty() => {
+            vec![&line[..=label.len()], rest.trim()]
+        }
+        _ if line.is_empty() => vec![],
+        _ => vec![line],
+    }
+}
+
+/// Assembles a whole source text, one statement per line. Every error in the
+/// text is reported instead of stopping at the first one.
+pub fn assemble(source: &str) -> Result<Assembly, Vec<AssemblyError>> {
+    let mut instructions = Vec::new();
+    let mut source_lines = Vec::new();
+    let mut errors = Vec::new();
+
+    for (line_i, line) in source.lines().enumerate() {
+        for statement in statements(line) {
+            match statement.parse::<Instruction>() {
+                Ok(instruction) => {
+                    instructions.push(instruction);
+                    source_lines.push(line_i);
+                }
+                Err(err) => errors.push(AssemblyError {
+                    line: line_i,
+                    message: err.to_string(),
+                }),
+            }
+        }
+    }
+
+    // Labels are checked here rather than left to `Program::new` to be able
+    // to point at the offending line
+    let mut labels = HashSet::new();
+    for (instruction, &line) in instructions.iter().zip(&source_lines) {
+        if let Instruction::Label(label) = instruction {
+            if !labels.insert(label.as_str()) {
+                errors.push(AssemblyError {
+                    line,
+                    message: format!("label \"{label}\" is already defined"),
+                });
+            }
+        }
+    }
+    for (instruction, &line) in instructions.iter().zip(&source_lines) {
+        if let Instruction::Branch { label, .. } = instruction {
+            if !labels.contains(label.as_str()) {
+                errors.push(AssemblyError {
+                    line,
+                    message: format!("undefined label \"{label}\""),
+                });
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|err| err.line);
+        return Err(errors);
+    }
+
+    match Program::new(instructions) {
+        Ok(program) => Ok(Assembly {
+            program,
+            source_lines,
+        }),
+        Err(err) => Err(vec![AssemblyError {
+            line: 0,
+            message: err.to_string(),
+        }]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::instructions::{Address, AluOp, Condition, Operand};
+
+    fn lines(errors: &[AssemblyError]) -> Vec<usize> {
+        errors.iter().map(|err| err.line).collect()
+    }
+
+    #[test]
+    fn assembles_labels_comments_and_addresses() {
+        let source = "\
+; counts down from 3
+    mov r1, 3
+loop: sub r1, r1, 1 ; label and instruction share the line
+    read r2, 0x10(r1)
+    write 8, r2
+    cmp r1, 0
+    bne loop
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.source_lines, [1, 2, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            assembly.program.get(1),
+            Some(&Instruction::Label("loop".to_owned()))
+        );
+        assert_eq!(
+            assembly.program.get(2),
+            Some(&Instruction::Alu {
+                op: AluOp::Sub,
+                rd: 1,
+                rs: 1,
+                operand: Operand::Immediate(1),
+            })
+        );
+        assert_eq!(
+            assembly.program.get(3),
+            Some(&Instruction::Read {
+                rd: 2,
+                address: Address {
+                    offset: 0x10,
+                    index: Some(1),
+                },
+            })
+        );
+        assert_eq!(
+            assembly.program.get(4),
+            Some(&Instruction::Write {
+                address: Address::from(8),
+                src: Operand::Register(2),
+            })
+        );
+        assert_eq!(
+            assembly.program.get(6),
+            Some(&Instruction::Branch {
+                condition: Condition::NotEqual,
+                label: "loop".to_owned(),
+            })
+        );
+        assert_eq!(assembly.source_line(3), Some(3));
+    }
+
+    #[test]
+    fn empty_and_comment_only_sources_assemble() {
+        assert!(assemble("").unwrap().program.is_empty());
+        assert!(assemble("; nothing\n\n   ; here\n")
+            .unwrap()
+            .program
+            .is_empty());
+    }
+
+    #[test]
+    fn mnemonics_are_case_insensitive() {
+        let assembly = assemble("READ r0, 4").unwrap();
+        assert_eq!(
+            assembly.program.get(0),
+            Some(&Instruction::Read {
+                rd: 0,
+                address: Address::from(4),
+            })
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_line() {
+        let source = "\
+    jmp r1
+    mov r1, 2
+    read r8, 0
+    write 0(r1, r2
+    add r1, r2
+    mov r1, zero
+";
+        let errors = assemble(source).unwrap_err();
+        assert_eq!(lines(&errors), [0, 2, 3, 4, 5]);
+        assert!(errors[0].message.contains("unknown instruction"));
+        assert!(errors[1].message.contains("expected a register"));
+        assert!(errors[3].message.contains("takes 3 operands, got 2"));
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1: unknown instruction \"jmp\""
+        );
+    }
+
+    #[test]
+    fn rejects_bad_addresses() {
+        let errors = assemble("read r1, 4(r2\nread r1, x(r2)").unwrap_err();
+        assert_eq!(lines(&errors), [0, 1]);
+        assert!(errors[0].message.contains("missing \")\""));
+        assert!(errors[1].message.contains("expected an address"));
+    }
+
+    #[test]
+    fn rejects_duplicate_and_undefined_labels() {
+        let source = "\
+top:
+    calc
+top: calc
+    b bottom
+    2nd:
+";
+        let errors = assemble(source).unwrap_err();
+        assert_eq!(lines(&errors), [2, 3, 4]);
+        assert_eq!(errors[0].message, "label \"top\" is already defined");
+        assert_eq!(errors[1].message, "undefined label \"bottom\"");
+        assert_eq!(errors[2].message, "invalid label \"2nd\"");
+    }
+}
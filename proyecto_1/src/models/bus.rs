@@ -1,82 +1,302 @@
-use std::sync::mpsc::{Receiver, RecvError, SendError, SyncSender};
+use std::{
+    sync::mpsc::{
+        Receiver, RecvError, RecvTimeoutError, SendError, SyncSender,
+        TryRecvError,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::models::{cache::CacheState, processor::Processor, Data};
+use crate::{
+    events::{Event, EventHub},
+    models::{
+        arbiter::Arbiter,
+        cache::CacheState,
+        dram::MemoryRequest,
+        error::{Shutdown, SimError},
+        numa::NumaConfig,
+        processor::Processor,
+        Data,
+    },
+};
 
 #[derive(Clone, Copy, Debug)]
 pub struct BusSignal {
     pub origin: usize,
     pub address: usize,
     pub action: BusAction,
+    /// Speculative read a prefetcher issued, no instruction waits for it
+    pub prefetch: bool,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BusAction {
     Invalidate,
     ReadMiss,
-    WriteMem(Data),
+    /// Read with intent to modify, other caches hand over their copy and
+    /// invalidate it
+    ReadExclusive,
+    /// Write back, the data is taken from the writer's write-back buffer when
+    /// the bus gets to it. Posted, the writer doesn't wait for it.
+    WriteMem,
+    /// Write of a whole block straight to memory, from write-through caches
+    /// and write misses that don't allocate. Other copies are invalidated.
+    WriteThrough(Data),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrafficStats {
+    pub read_misses: usize,
+    pub read_exclusives: usize,
+    pub invalidates: usize,
+    pub write_backs: usize,
+    pub write_throughs: usize,
+    /// Blocks that crossed the bus, from caches or to and from memory
+    pub data_transfers: usize,
+    pub memory_reads: usize,
+    pub memory_writes: usize,
+    /// Caches that looked a transaction up
+    pub snoops: usize,
+}
+
+impl TrafficStats {
+    pub fn transactions(&self) -> usize {
+        self.read_misses
+            + self.read_exclusives
+            + self.invalidates
+            + self.write_backs
+            + self.write_throughs
+    }
+}
+
+/// What the CPUs send to the bus thread
+#[derive(Clone, Copy, Debug)]
+pub enum BusMessage {
+    Request(BusSignal),
+    /// The data phase of the transaction is over and its line installed, the
+    /// block can be used by other transactions again
+    Done(BusSignal),
+}
+
+/// Data on its way to a CPU, delivered by the data bus once `ready`
+#[derive(Clone, Copy)]
+pub struct DataPhase {
+    pub origin: usize,
+    pub state: CacheState,
+    pub data: Data,
+    pub ready: Instant,
+}
+
+/// The CPU's end of the bus
+pub struct BusPort {
+    signal_output: SyncSender<BusMessage>,
+    grant_input: Receiver<()>,
+    data_input: Receiver<(CacheState, Data)>,
+    // Notices from the controller that a line left the write-back buffer
+    write_back_input: Receiver<()>,
+    shutdown: Shutdown,
+}
+
+impl BusPort {
+    pub fn new(
+        signal_output: SyncSender<BusMessage>,
+        grant_input: Receiver<()>,
+        data_input: Receiver<(CacheState, Data)>,
+        write_back_input: Receiver<()>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            signal_output,
+            grant_input,
+            data_input,
+            write_back_input,
+            shutdown,
+        }
+    }
+
+    /// Returns once the address phase of the request starts. No other
+    /// transaction on the block goes ahead until it's marked as done.
+    pub fn request(&self, signal: BusSignal) -> Result<(), SimError> {
+        self.signal_output
+            .send(BusMessage::Request(signal))
+            .map_err(|err| SimError::from(err).during(signal))?;
+        self.shutdown
+            .recv(&self.grant_input)
+            .map_err(|err| SimError::from(err).during(signal))
+    }
+
+    /// Queues the write back of a line in the write-back buffer without
+    /// waiting for the bus, the buffer answers for the block until then
+    pub fn post(&self, signal: BusSignal) -> Result<(), SimError> {
+        self.signal_output
+            .send(BusMessage::Request(signal))
+            .map_err(|err| SimError::from(err).during(signal))
+    }
+
+    /// Returns once a line left the write-back buffer, or right away if one
+    /// left since the last call
+    pub fn wait_write_back(&self) -> Result<(), RecvError> {
+        self.shutdown.recv(&self.write_back_input)
+    }
+
+    pub fn complete(&self, signal: BusSignal) -> Result<(), SimError> {
+        self.signal_output
+            .send(BusMessage::Done(signal))
+            .map_err(|err| SimError::from(err).during(signal))
+    }
+
+    /// Data phase of `signal`, once its request was granted
+    pub fn recv_data(
+        &self,
+        signal: BusSignal,
+    ) -> Result<(CacheState, Data), SimError> {
+        self.shutdown
+            .recv(&self.data_input)
+            .map_err(|err| SimError::from(err).during(signal))
+    }
 }
 
+/// Address bus, arbitrates the requests and snoops the caches. With a network
+/// in place of a bus this is the home node ordering the transactions.
 pub struct Bus {
     cache_data_input: Receiver<Option<Data>>,
-    signal_input: Receiver<BusSignal>,
+    signal_input: Receiver<BusMessage>,
     controllers: Vec<SyncSender<BusSignal>>,
-    data_inputs: Vec<SyncSender<(CacheState, Data)>>,
+    grants: Vec<SyncSender<()>>,
+    data_phase_output: SyncSender<DataPhase>,
+    // One memory controller per NUMA node
+    memory_outputs: Vec<SyncSender<MemoryRequest>>,
+    numa: NumaConfig,
+    gui_tx: Option<EventHub>,
+    shutdown: Shutdown,
 }
 
 impl Bus {
     pub fn new(
-        bus_signal_receiver: Receiver<BusSignal>,
+        bus_signal_receiver: Receiver<BusMessage>,
         bus_data_receiver: Receiver<Option<Data>>,
+        data_phase_sender: SyncSender<DataPhase>,
+        memory_senders: Vec<SyncSender<MemoryRequest>>,
+        numa: NumaConfig,
+        shutdown: Shutdown,
     ) -> Self {
         Self {
             signal_input: bus_signal_receiver,
             cache_data_input: bus_data_receiver,
             controllers: Vec::new(),
-            data_inputs: Vec::new(),
+            grants: Vec::new(),
+            data_phase_output: data_phase_sender,
+            memory_outputs: memory_senders,
+            numa,
+            gui_tx: None,
+            shutdown,
         }
     }
 
+    pub fn register_gui_listener(&mut self, gui_tx: EventHub) {
+        self.gui_tx = Some(gui_tx);
+    }
+
     pub fn register_processor(&mut self, processor: &Processor) {
         self.controllers.push(processor.controller_signal_input());
-        self.data_inputs.push(processor.cpu_data_input());
+        self.grants.push(processor.cpu_grant_input());
     }
 
-    // This will panick on error but threads should just silently die i think
-    pub fn recv_signal(&self) -> Result<BusSignal, RecvError> {
-        self.signal_input.recv()
+    pub fn recv_message(&self) -> Result<BusMessage, RecvError> {
+        self.shutdown.recv(&self.signal_input)
     }
 
-    pub fn recv_data(&self) -> Result<Option<Data>, RecvError> {
-        self.cache_data_input.recv()
+    /// Waits for the messages of an address phase to get where they're going
+    pub fn wait_until(&self, deadline: Instant) -> Result<(), RecvError> {
+        self.shutdown.sleep_until(deadline)
+    }
+
+    pub fn try_recv_message(&self) -> Result<BusMessage, TryRecvError> {
+        self.signal_input.try_recv()
+    }
+
+    pub fn send_traffic(&self, traffic: TrafficStats) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::BusTraffic(traffic));
+        }
+    }
+
+    pub fn send_in_flight(&self, in_flight: Vec<BusSignal>) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::BusInFlight { in_flight });
+        }
     }
 
-    pub fn propagate_signal(
+    pub fn send_transaction_end(&self, signal: BusSignal) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::BusTransactionEnd(signal));
+        }
+    }
+
+    /// Memory took the block the write back carried
+    pub fn send_write_back(&self, signal: BusSignal, data: Data) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::WriteBack {
+                cache_i: signal.origin,
+                address: signal.address,
+                data,
+            });
+        }
+    }
+
+    /// Lets the CPU know its request is the one being served
+    pub fn grant(
         &self,
         signal: BusSignal,
-    ) -> Result<(), SendError<BusSignal>> {
-        for (i, sender) in self.controllers.iter().enumerate() {
-            if i != signal.origin {
-                sender.send(signal)?;
-            }
+        arbiter: &Arbiter,
+    ) -> Result<(), SendError<()>> {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::BusArbitration {
+                granted: signal,
+                pending: arbiter.pending(),
+                wait_stats: arbiter.stats().to_vec(),
+            });
+            sender.send(Event::BusTransactionStart(signal));
         }
-        Ok(())
+        // Nobody waits for a posted write back
+        match signal.action {
+            BusAction::WriteMem => Ok(()),
+            _ => self.grants[signal.origin].send(()),
+        }
+    }
+
+    pub fn recv_data(&self) -> Result<Option<Data>, RecvError> {
+        self.shutdown.recv(&self.cache_data_input)
     }
 
+    /// Sends the signal to the controllers of `targets`, their answers are
+    /// collected with `check_cache_data`
     pub fn request_cache_data(
         &self,
-        read_miss_signal: BusSignal,
+        signal: BusSignal,
+        targets: &[usize],
     ) -> Result<(), SendError<BusSignal>> {
-        for i in 0..self.controllers.len() {
-            if i != read_miss_signal.origin {
-                self.controllers[i].send(read_miss_signal)?;
-            }
+        for &target in targets {
+            self.controllers[target].send(signal)?;
         }
         Ok(())
     }
 
-    pub fn check_cache_data(&self) -> Result<Option<Data>, RecvError> {
+    /// Asks the cache writing back a line for its data, there's none if
+    /// another cache took the block over while the write back waited
+    pub fn request_write_back_data(
+        &self,
+        write_back_signal: BusSignal,
+    ) -> Result<Option<Data>, SimError> {
+        self.controllers[write_back_signal.origin].send(write_back_signal)?;
+        Ok(self.recv_data()?)
+    }
+
+    pub fn check_cache_data(
+        &self,
+        answers: usize,
+    ) -> Result<Option<Data>, RecvError> {
         let mut result = None;
-        for _ in 0..self.controllers.len() - 1 {
+        for _ in 0..answers {
             if let Some(data) = self.recv_data()? {
                 result = Some(data);
             }
@@ -84,12 +304,88 @@ impl Bus {
         Ok(result)
     }
 
-    pub fn send_data_to_cpu(
+    /// Hands the data phase over to the data bus, it reaches the CPU after
+    /// `latency`
+    pub fn schedule_data(
         &self,
         cpu: usize,
-        status: CacheState,
+        state: CacheState,
+        data: Data,
+        latency: Duration,
+    ) -> Result<(), SendError<DataPhase>> {
+        self.data_phase_output.send(DataPhase {
+            origin: cpu,
+            state,
+            data,
+            ready: Instant::now() + latency,
+        })
+    }
+
+    /// Queues the block access in the memory controller of its node, which
+    /// starts the data phase once the DRAM is done with it
+    pub fn request_memory(
+        &self,
+        signal: BusSignal,
+        block: usize,
+        state: CacheState,
         data: Data,
+    ) -> Result<(), SimError> {
+        let node = self.numa.node_of_block(block);
+        let num_processors = self.controllers.len();
+        let remote = self.numa.is_remote(signal.origin, num_processors, block);
+        self.memory_outputs[node].send(MemoryRequest {
+            block: self.numa.node_block(block),
+            write: matches!(
+                signal.action,
+                BusAction::WriteMem | BusAction::WriteThrough(_)
+            ),
+            posted: signal.action == BusAction::WriteMem,
+            origin: signal.origin,
+            remote,
+            state,
+            data,
+            arrival: Instant::now(),
+        })?;
+        Ok(())
+    }
+}
+
+/// Delivers the data phases, in the order they become ready rather than the
+/// order their address phases happened in
+pub struct DataBus {
+    data_phase_input: Receiver<DataPhase>,
+    data_inputs: Vec<SyncSender<(CacheState, Data)>>,
+    shutdown: Shutdown,
+}
+
+impl DataBus {
+    pub fn new(
+        data_phase_receiver: Receiver<DataPhase>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            data_phase_input: data_phase_receiver,
+            data_inputs: Vec::new(),
+            shutdown,
+        }
+    }
+
+    pub fn register_processor(&mut self, processor: &Processor) {
+        self.data_inputs.push(processor.cpu_data_input());
+    }
+
+    /// Waits until `deadline` if there's one
+    pub fn recv_data_phase_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<DataPhase, RecvTimeoutError> {
+        self.shutdown.recv_until(&self.data_phase_input, deadline)
+    }
+
+    pub fn send_data_to_cpu(
+        &self,
+        phase: DataPhase,
     ) -> Result<(), SendError<(CacheState, u16)>> {
-        self.data_inputs[cpu].send((status, data))
+        self.data_inputs[phase.origin].send((phase.state, phase.data))
     }
 }
@@ -1,18 +1,72 @@
-use std::{mem::size_of, ops::Range, slice::SliceIndex, sync::mpsc::Sender};
-
-use crate::{app::Event, models::Data};
-
-#[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq)]
+use std::{collections::BTreeSet, mem::size_of, ops::Range, slice::SliceIndex};
+
+use crate::{
+    events::{Event, EventHub},
+    models::{bus::BusSignal, prefetcher::PrefetchStats, Data},
+};
+
+/// Transient states are named after the stable state the line comes from and
+/// the one it goes to, followed by what it waits for: its own request getting
+/// the bus (A) and the data (D). The same notation as the tables in Sorin, Hill
+/// and Wood's "A Primer on Memory Consistency and Cache Coherence".
+///
+/// Discriminants represent priority to not be replaced, lines with a
+/// transaction going on are never replaced.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub enum CacheState {
-    // Discriminants represent priority to not be replaced
     Invalid = 0,
     Shared = 1,
     Exclusive = 2,
     Modified = 3,
     Owned = 4,
+    /// Read miss waiting for the bus and then its data
+    IS_AD = 5,
+    /// Read miss waiting for its data
+    IS_D = 6,
+    /// Write miss waiting for the bus and then its data
+    IM_AD = 7,
+    /// Write miss waiting for its data
+    IM_D = 8,
+    /// Upgrade of a Shared line, still readable and snooped until its own
+    /// request gets the bus
+    SM_AD = 9,
+    /// Upgrade of a Shared line waiting for its data
+    SM_D = 10,
+    /// Upgrade of an Owned line, like `SM_AD`
+    OM_AD = 11,
+    /// Upgrade of an Owned line waiting for its data
+    OM_D = 12,
+    /// Write back of a Modified line waiting in the write-back buffer for the
+    /// bus, the line supplies its data meanwhile
+    MI_A = 13,
+    /// Write back of an Owned line, like `MI_A`
+    OI_A = 14,
+    /// Write back that another cache took ownership from, memory must not be
+    /// written once it gets the bus
+    II_A = 15,
 }
 
 impl CacheState {
+    pub const ALL: [CacheState; 16] = [
+        CacheState::Invalid,
+        CacheState::Shared,
+        CacheState::Exclusive,
+        CacheState::Modified,
+        CacheState::Owned,
+        CacheState::IS_AD,
+        CacheState::IS_D,
+        CacheState::IM_AD,
+        CacheState::IM_D,
+        CacheState::SM_AD,
+        CacheState::SM_D,
+        CacheState::OM_AD,
+        CacheState::OM_D,
+        CacheState::MI_A,
+        CacheState::OI_A,
+        CacheState::II_A,
+    ];
+
     pub fn get_letter(&self) -> &str {
         match self {
             CacheState::Modified => "M",
@@ -20,10 +74,174 @@ impl CacheState {
             CacheState::Exclusive => "E",
             CacheState::Shared => "S",
             CacheState::Invalid => "I",
+            CacheState::IS_AD => "IS_AD",
+            CacheState::IS_D => "IS_D",
+            CacheState::IM_AD => "IM_AD",
+            CacheState::IM_D => "IM_D",
+            CacheState::SM_AD => "SM_AD",
+            CacheState::SM_D => "SM_D",
+            CacheState::OM_AD => "OM_AD",
+            CacheState::OM_D => "OM_D",
+            CacheState::MI_A => "MI_A",
+            CacheState::OI_A => "OI_A",
+            CacheState::II_A => "II_A",
+        }
+    }
+
+    /// Holds data the CPU can read
+    pub fn is_valid(&self) -> bool {
+        matches!(
+            self,
+            CacheState::Modified
+                | CacheState::Owned
+                | CacheState::Exclusive
+                | CacheState::Shared
+        )
+    }
+
+    pub fn is_transient(&self) -> bool {
+        !self.is_valid() && *self != CacheState::Invalid
+    }
+
+    /// State once the line's own request got the bus, from then on it only
+    /// waits for the data
+    pub fn ordered(&self) -> CacheState {
+        match self {
+            CacheState::IS_AD => CacheState::IS_D,
+            CacheState::IM_AD => CacheState::IM_D,
+            CacheState::SM_AD => CacheState::SM_D,
+            CacheState::OM_AD => CacheState::OM_D,
+            state => *state,
+        }
+    }
+}
+
+/// Which stable states the caches use. Without an Owned state, memory gets
+/// the data of a dirty line when another cache reads it.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoherenceProtocol {
+    MOESI,
+    MESI,
+    MSI,
+    /// A single copy of every block, reads take it away from the other caches.
+    /// Exclusive and Modified play the part of a clean and a dirty V.
+    VI,
+}
+
+impl CoherenceProtocol {
+    pub const ALL: [CoherenceProtocol; 4] = [
+        CoherenceProtocol::MOESI,
+        CoherenceProtocol::MESI,
+        CoherenceProtocol::MSI,
+        CoherenceProtocol::VI,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            CoherenceProtocol::MOESI => "MOESI",
+            CoherenceProtocol::MESI => "MESI",
+            CoherenceProtocol::MSI => "MSI",
+            CoherenceProtocol::VI => "VI",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CoherenceProtocol> {
+        CoherenceProtocol::ALL
+            .into_iter()
+            .find(|protocol| protocol.get_name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn has_owned(&self) -> bool { *self == CoherenceProtocol::MOESI }
+
+    /// State a read miss installs its line in, depending on whether another
+    /// cache supplied the data
+    pub fn read_state(&self, from_cache: bool) -> CacheState {
+        match (self, from_cache) {
+            (CoherenceProtocol::VI, _) => CacheState::Exclusive,
+            (CoherenceProtocol::MSI, _) | (_, true) => CacheState::Shared,
+            _ => CacheState::Exclusive,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    /// Writes stay in the cache until the line is evicted
+    WriteBack,
+    /// Writes to clean lines and misses go to memory right away, the lines
+    /// never get dirty. Atomics still take their line in Modified state.
+    WriteThrough,
+}
+
+impl WritePolicy {
+    pub const ALL: [WritePolicy; 2] =
+        [WritePolicy::WriteBack, WritePolicy::WriteThrough];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            WritePolicy::WriteBack => "write-back",
+            WritePolicy::WriteThrough => "write-through",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WritePolicy> {
+        WritePolicy::ALL
+            .into_iter()
+            .find(|policy| policy.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Which line of a full set makes room for a new block. Lines with a
+/// transaction going on are never picked, and free lines always go first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplacementPolicy {
+    /// The line in the state with the lowest priority, see `CacheState`
+    StatePriority,
+    /// The least recently used line
+    Lru,
+    /// The line that was brought in first
+    Fifo,
+}
+
+impl ReplacementPolicy {
+    pub const ALL: [ReplacementPolicy; 3] = [
+        ReplacementPolicy::StatePriority,
+        ReplacementPolicy::Lru,
+        ReplacementPolicy::Fifo,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            ReplacementPolicy::StatePriority => "state",
+            ReplacementPolicy::Lru => "lru",
+            ReplacementPolicy::Fifo => "fifo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ReplacementPolicy> {
+        ReplacementPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Accesses to the arrays of a cache, what the energy model charges it for
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheActivity {
+    pub tag_lookups: usize,
+    pub data_reads: usize,
+    pub data_writes: usize,
+}
+
+impl CacheActivity {
+    pub fn add(&mut self, other: &CacheActivity) {
+        self.tag_lookups += other.tag_lookups;
+        self.data_reads += other.data_reads;
+        self.data_writes += other.data_writes;
+    }
+}
+
 #[derive(Clone)]
 pub struct CacheLine {
     pub state: CacheState,
@@ -51,7 +269,32 @@ pub struct Cache {
     index_bits: usize,
     index_mask: usize,
     storage: Vec<CacheLine>,
-    gui_tx: Option<Sender<Event>>,
+    replacement: ReplacementPolicy,
+    // Ticks on every access, stamps the lines in `last_used` and `filled`
+    clock: u64,
+    last_used: Vec<u64>,
+    // When the block in the line was brought in
+    filled: Vec<u64>,
+    // Fully associative, catches the lines the sets evict. Oldest first, tags
+    // hold the whole block number.
+    victims: Vec<CacheLine>,
+    victim_entries: usize,
+    // Dirty lines evicted and waiting for the bus to take them to memory,
+    // oldest first. Tags hold the whole block number, the controller snoops
+    // them like any other line.
+    write_backs: Vec<CacheLine>,
+    write_back_entries: usize,
+    write_policy: WritePolicy,
+    // Write misses bring the line in
+    write_allocate: bool,
+    // Address reserved by the last load-linked, lost when its line leaves the
+    // cache
+    reservation: Option<usize>,
+    // Blocks a prefetch brought in that no demand access used yet
+    prefetched: BTreeSet<usize>,
+    prefetch_stats: PrefetchStats,
+    activity: CacheActivity,
+    gui_tx: Option<EventHub>,
 }
 
 #[allow(dead_code)]
@@ -60,6 +303,10 @@ impl Cache {
         processor_id: usize,
         associativity: usize,
         sets: usize,
+        victim_entries: usize,
+        write_back_entries: usize,
+        write_policy: WritePolicy,
+        write_allocate: bool,
     ) -> Self {
         let mut index_bits = 0;
         let mut x = sets - 1;
@@ -90,20 +337,42 @@ impl Cache {
             associativity,
             sets,
             storage: vec![CacheLine::new_cold(); sets * associativity],
+            replacement: ReplacementPolicy::StatePriority,
+            clock: 0,
+            last_used: vec![0; sets * associativity],
+            filled: vec![0; sets * associativity],
+            victims: Vec::with_capacity(victim_entries),
+            victim_entries,
+            write_backs: Vec::with_capacity(write_back_entries),
+            write_back_entries,
+            write_policy,
+            write_allocate,
+            reservation: None,
+            prefetched: BTreeSet::new(),
+            prefetch_stats: PrefetchStats::default(),
+            activity: CacheActivity::default(),
             gui_tx: None,
         }
     }
 
-    pub fn register_gui_listener(&mut self, gui_tx: Sender<Event>) {
+    pub fn register_gui_listener(&mut self, gui_tx: EventHub) {
         self.gui_tx = Some(gui_tx);
     }
 
+    pub fn set_replacement_policy(&mut self, policy: ReplacementPolicy) {
+        self.replacement = policy;
+    }
+
     pub fn associativity(&self) -> usize { self.associativity }
 
     pub fn sets(&self) -> usize { self.sets }
 
     pub fn blocks(&self) -> usize { self.sets * self.associativity }
 
+    pub fn write_policy(&self) -> WritePolicy { self.write_policy }
+
+    pub fn write_allocate(&self) -> bool { self.write_allocate }
+
     pub fn get_storage<I: SliceIndex<[CacheLine]>>(
         &self,
         index: I,
@@ -122,6 +391,10 @@ impl Cache {
 
     fn get_offset(&self, address: usize) -> usize { address & self.offset_mask }
 
+    pub fn get_line_address(&self, tag: usize, index: usize) -> usize {
+        ((tag << self.index_bits) | index) << self.offset_bits
+    }
+
     pub fn get_set(&self, index: usize) -> Option<&[CacheLine]> {
         let set_range =
             index * self.associativity..(index + 1) * self.associativity;
@@ -140,18 +413,350 @@ impl Cache {
 
     fn write(&mut self, block_index: usize, line: CacheLine) {
         if let Some(ref sender) = self.gui_tx {
-            sender
-                .send(Event::CacheWrite {
-                    cache_i: self.processor_id,
-                    block_i: block_index,
-                    line: line.clone(),
-                })
-                .ok();
+            sender.send(Event::CacheWrite {
+                cache_i: self.processor_id,
+                block_i: block_index,
+                line: line.clone(),
+            });
         }
+        // Changing the state alone, like invalidating or waiting in a
+        // transient state, only touches the tags
+        let old = &self.storage[block_index];
+        if line.state.is_valid()
+            && (!old.state.is_valid()
+                || old.tag != line.tag
+                || old.data != line.data)
+        {
+            self.activity.data_writes += 1;
+            self.send_activity();
+        }
+        self.clock += 1;
+        if old.state == CacheState::Invalid || old.tag != line.tag {
+            self.filled[block_index] = self.clock;
+        }
+        self.last_used[block_index] = self.clock;
         self.storage[block_index] = line;
     }
 
-    // Returns line that was replaced
+    fn send_victims(&self) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::VictimCache {
+                cache_i: self.processor_id,
+                lines: self.victims.clone(),
+            });
+        }
+    }
+
+    fn get_victim_position(&self, address: usize) -> Option<usize> {
+        let block = self.get_address_index(address);
+        self.victims.iter().position(|line| line.tag == block)
+    }
+
+    // Victim cache entry to make room in, the oldest among the lowest
+    // priority ones
+    fn get_victim_replacement(&self) -> Option<usize> {
+        let lowest_priority =
+            self.victims.iter().map(|line| line.state).min()?;
+        self.victims
+            .iter()
+            .position(|line| line.state == lowest_priority)
+    }
+
+    // Moves a line evicted from its set to the victim cache, where it's still
+    // coherent. The line the victim cache drops to make room leaves the
+    // cache, dirty ones must have been written back already.
+    fn push_victim(&mut self, address: usize, line: CacheLine) {
+        if self.victim_entries == 0 {
+            self.line_left(address);
+            self.send_eviction(address, &line);
+            self.send_transition(address, line.state);
+            return;
+        }
+        if self.victims.len() == self.victim_entries {
+            let dropped = self.get_victim_replacement().unwrap();
+            let dropped = self.victims.remove(dropped);
+            let dropped_address = dropped.tag << self.offset_bits;
+            self.line_left(dropped_address);
+            self.send_eviction(dropped_address, &dropped);
+            self.send_transition(dropped_address, dropped.state);
+        }
+        self.victims.push(CacheLine {
+            tag: self.get_address_index(address),
+            ..line
+        });
+        self.send_victims();
+    }
+
+    /// Swaps a line in the victim cache back into its set, the line in its way
+    /// takes its place
+    pub fn swap_victim(&mut self, address: usize) {
+        let Some(position) = self
+            .get_victim_position(address)
+            .filter(|&i| self.victims[i].state.is_valid())
+        else {
+            return;
+        };
+        let line = self.victims.remove(position);
+
+        let block_index = self.get_replacement_index(address);
+        let replaced = self.storage[block_index].clone();
+        if replaced.state != CacheState::Invalid {
+            let replaced_address =
+                self.get_line_address(replaced.tag, self.get_index(address));
+            self.victims.push(CacheLine {
+                tag: self.get_address_index(replaced_address),
+                ..replaced
+            });
+        }
+        self.write(
+            block_index,
+            CacheLine {
+                tag: self.get_tag(address),
+                ..line
+            },
+        );
+        self.send_victims();
+    }
+
+    fn send_write_backs(&self) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::WriteBackBuffer {
+                cache_i: self.processor_id,
+                lines: self.write_backs.clone(),
+            });
+        }
+    }
+
+    fn get_write_back_position(&self, address: usize) -> Option<usize> {
+        let block = self.get_address_index(address);
+        self.write_backs.iter().position(|line| line.tag == block)
+    }
+
+    pub fn write_back_full(&self) -> bool {
+        self.write_backs.len() == self.write_back_entries
+    }
+
+    /// The block is in the write-back buffer, memory doesn't have it yet
+    pub fn write_back_pending(&self, address: usize) -> bool {
+        self.get_write_back_position(address).is_some()
+    }
+
+    /// Moves a dirty line out of the cache into the write-back buffer, where
+    /// it waits for its write back to get the bus
+    pub fn push_write_back(&mut self, address: usize) {
+        let line = self.lookup(address).unwrap().clone();
+        let state = match line.state {
+            CacheState::Owned => CacheState::OI_A,
+            _ => CacheState::MI_A,
+        };
+        self.data_read();
+        self.set_state_address(address, CacheState::Invalid);
+        self.write_backs.push(CacheLine {
+            state,
+            tag: self.get_address_index(address),
+            data: line.data,
+        });
+        self.send_write_backs();
+        self.send_eviction(address, &line);
+        self.send_transition(address, line.state);
+    }
+
+    fn send_eviction(&self, address: usize, line: &CacheLine) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::Eviction {
+                cache_i: self.processor_id,
+                address,
+                state: line.state,
+                data: line.data,
+            });
+        }
+    }
+
+    // Lets the listeners know the block at `address` isn't in state `old`
+    // anymore, if it changed
+    fn send_transition(&self, address: usize, old: CacheState) {
+        let new = self.get_state(address);
+        if new == old {
+            return;
+        }
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::StateTransition {
+                cache_i: self.processor_id,
+                address: self.get_address_index(address) << self.offset_bits,
+                old,
+                new,
+            });
+        }
+    }
+
+    /// Answer of this cache to a snooped transaction, `state` is the one its
+    /// line was in
+    pub fn send_snoop_response(
+        &self,
+        signal: &BusSignal,
+        state: CacheState,
+        data: Option<Data>,
+    ) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::SnoopResponse {
+                cache_i: self.processor_id,
+                signal: *signal,
+                state,
+                data,
+            });
+        }
+    }
+
+    // The line holding `address` is gone from the cache
+    fn line_left(&mut self, address: usize) {
+        self.clear_reservation_for(address);
+        if self.prefetched.remove(&self.get_address_index(address)) {
+            self.prefetch_stats.useless += 1;
+            self.send_prefetch_stats();
+        }
+    }
+
+    fn send_prefetch_stats(&self) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::PrefetchStats {
+                processor_i: self.processor_id,
+                stats: self.prefetch_stats,
+            });
+        }
+    }
+
+    pub fn prefetch_stats(&self) -> PrefetchStats { self.prefetch_stats }
+
+    pub fn activity(&self) -> CacheActivity { self.activity }
+
+    fn send_activity(&self) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::CacheActivity {
+                cache_i: self.processor_id,
+                activity: self.activity,
+            });
+        }
+    }
+
+    /// The data array was read for something else than a demand access, like
+    /// supplying a snooped block
+    pub fn data_read(&mut self) {
+        self.activity.data_reads += 1;
+        self.send_activity();
+    }
+
+    /// Called once a prefetched line is installed
+    pub fn prefetch_issued(&mut self, address: usize) {
+        self.prefetched.insert(self.get_address_index(address));
+        self.prefetch_stats.issued += 1;
+        self.send_prefetch_stats();
+    }
+
+    pub fn prefetch_late(&mut self) {
+        self.prefetch_stats.late += 1;
+        self.send_prefetch_stats();
+    }
+
+    /// A demand access to `address`, the prefetch that brought its line in
+    /// paid off. Tags and data are read in parallel, hit or miss.
+    pub fn demand_access(&mut self, address: usize) {
+        self.activity.tag_lookups += 1;
+        self.activity.data_reads += 1;
+        self.send_activity();
+        if let Some(i) =
+            self.get_set_range(self.get_index(address)).find(|&i| {
+                self.storage[i].tag == self.get_tag(address)
+                    && self.storage[i].state.is_valid()
+            })
+        {
+            self.clock += 1;
+            self.last_used[i] = self.clock;
+        }
+        if self.prefetched.remove(&self.get_address_index(address)) {
+            self.prefetch_stats.useful += 1;
+            self.send_prefetch_stats();
+        }
+    }
+
+    pub fn reservation(&self) -> Option<usize> { self.reservation }
+
+    pub fn has_reservation(&self, address: usize) -> bool {
+        self.reservation
+            .map(|reserved| self.get_address_index(reserved))
+            == Some(self.get_address_index(address))
+    }
+
+    pub fn set_reservation(&mut self, address: Option<usize>) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::Reservation {
+                processor_i: self.processor_id,
+                address,
+            });
+        }
+        self.reservation = address;
+    }
+
+    pub fn clear_reservation_for(&mut self, address: usize) {
+        if self.has_reservation(address) {
+            self.set_reservation(None);
+        }
+    }
+
+    // Block that would hold `address` if it was stored now
+    fn get_replacement_index(&self, address: usize) -> usize {
+        let index = self.get_index(address);
+
+        // first determine if address is already in set, then replace it
+        for i in self.get_set_range(index) {
+            if self.storage[i].tag == self.get_tag(address) {
+                return i;
+            }
+        }
+
+        let set = self.get_set_range(index);
+        if let Some(i) = set
+            .clone()
+            .find(|&i| self.storage[i].state == CacheState::Invalid)
+        {
+            return i;
+        }
+
+        // Only stable lines can be replaced, if there are none the line with
+        // the lowest priority state goes as before
+        let stable = set.clone().filter(|&i| self.storage[i].state.is_valid());
+        let replaced = match self.replacement {
+            ReplacementPolicy::StatePriority => None,
+            ReplacementPolicy::Lru => stable.min_by_key(|&i| self.last_used[i]),
+            ReplacementPolicy::Fifo => stable.min_by_key(|&i| self.filled[i]),
+        };
+        replaced.unwrap_or_else(|| {
+            set.min_by_key(|&i| self.storage[i].state).unwrap()
+        })
+    }
+
+    /// Line that storing `address` would push out of the cache, with its
+    /// address. With a victim cache that's the line the victim cache drops.
+    pub fn get_victim(&self, address: usize) -> Option<(usize, &CacheLine)> {
+        let victim = &self.storage[self.get_replacement_index(address)];
+        if victim.tag == self.get_tag(address)
+            || victim.state == CacheState::Invalid
+        {
+            return None;
+        }
+
+        if self.victim_entries == 0 {
+            let victim_address =
+                self.get_line_address(victim.tag, self.get_index(address));
+            return Some((victim_address, victim));
+        }
+        if self.victims.len() < self.victim_entries {
+            return None;
+        }
+        let victim = &self.victims[self.get_victim_replacement()?];
+        Some((victim.tag << self.offset_bits, victim))
+    }
+
+    /// Returns line that was replaced
     pub fn store_line(
         &mut self,
         address: usize,
@@ -163,45 +768,20 @@ impl Cache {
             state,
             data,
         };
-
-        let index = self.get_index(address);
-
-        // first determine if address is already in set
-        let address_exists: bool = self
-            .get_set(index)
-            .unwrap()
-            .iter()
-            .any(|line| line.tag == self.get_tag(address));
-
-        // replace existing block
-        if address_exists {
-            for i in self.get_set_range(index) {
-                if self.storage[i].tag == self.get_tag(address) {
-                    let replaced_block = self.storage[i].clone();
-                    self.write(i, line);
-                    return replaced_block;
-                }
-            }
-        }
-
-        // else determine lowest priority state in set
-        let lowest_priority: CacheState = self
-            .get_set(index)
-            .unwrap()
-            .iter()
-            .map(|line| line.state)
-            .min()
-            .unwrap();
-
-        for i in self.get_set_range(index) {
-            if self.storage[i].state == lowest_priority {
-                let replaced_block = self.storage[i].clone();
-                self.write(i, line);
-                return replaced_block;
-            }
+        let old = self.get_state(address);
+
+        let block_index = self.get_replacement_index(address);
+        let replaced_block = self.storage[block_index].clone();
+        if replaced_block.tag != line.tag
+            && replaced_block.state != CacheState::Invalid
+        {
+            let replaced_address = self
+                .get_line_address(replaced_block.tag, self.get_index(address));
+            self.push_victim(replaced_address, replaced_block.clone());
         }
-
-        unreachable!();
+        self.write(block_index, line);
+        self.send_transition(address, old);
+        replaced_block
     }
 
     pub fn get_address_index(&self, address: usize) -> usize {
@@ -213,42 +793,61 @@ impl Cache {
     }
 
     pub fn change_state_address(&mut self, address: usize, state: CacheState) {
+        let old = self.get_state(address);
+        self.set_state_address(address, state);
+        self.send_transition(address, old);
+    }
+
+    fn set_state_address(&mut self, address: usize, state: CacheState) {
         let index = self.get_index(address);
 
+        if state == CacheState::Invalid {
+            self.line_left(address);
+        }
+
+        // Invalid lines don't hold the block even if their tag matches, cold
+        // ones all start with tag 0
         for i in self.get_set_range(index) {
-            if self.storage[i].tag == self.get_tag(address) {
+            if self.storage[i].tag == self.get_tag(address)
+                && self.storage[i].state != CacheState::Invalid
+            {
                 self.storage[i].state = state;
                 if let Some(ref sender) = self.gui_tx {
-                    sender
-                        .send(Event::CacheWrite {
-                            cache_i: self.processor_id,
-                            block_i: i,
-                            line: self.storage[i].clone(),
-                        })
-                        .ok();
+                    sender.send(Event::CacheWrite {
+                        cache_i: self.processor_id,
+                        block_i: i,
+                        line: self.storage[i].clone(),
+                    });
                 }
             }
         }
-    }
 
-    pub fn get_address(&self, address: usize) -> Option<&CacheLine> {
-        let index = self.get_index(address);
+        if let Some(position) = self.get_victim_position(address) {
+            match state {
+                CacheState::Invalid => {
+                    self.victims.remove(position);
+                }
+                state => self.victims[position].state = state,
+            }
+            self.send_victims();
+        }
 
-        for i in self.get_set_range(index) {
-            if self.storage[i].tag == self.get_tag(address)
-                && (match self.storage[i].state {
-                    CacheState::Modified => true,
-                    CacheState::Owned => true,
-                    CacheState::Exclusive => true,
-                    CacheState::Shared => true,
-                    CacheState::Invalid => false,
-                })
-            {
-                return Some(&self.storage[i]);
+        if let Some(position) = self.get_write_back_position(address) {
+            match state {
+                CacheState::Invalid => {
+                    self.write_backs.remove(position);
+                }
+                state => self.write_backs[position].state = state,
             }
+            self.send_write_backs();
         }
+    }
 
-        None
+    /// Line holding `address` in its set in a state the CPU can read. Lines in
+    /// the victim cache have to be swapped back first.
+    pub fn get_address(&self, address: usize) -> Option<&CacheLine> {
+        self.lookup_set(address)
+            .filter(|line| line.state.is_valid())
     }
 
     pub fn get_address_mut(
@@ -259,13 +858,7 @@ impl Cache {
 
         for i in self.get_set_range(index) {
             if self.storage[i].tag == self.get_tag(address)
-                && (match self.storage[i].state {
-                    CacheState::Modified => true,
-                    CacheState::Owned => true,
-                    CacheState::Exclusive => true,
-                    CacheState::Shared => true,
-                    CacheState::Invalid => false,
-                })
+                && self.storage[i].state.is_valid()
             {
                 let cache_line = &mut self.storage[i];
                 return Some(cache_line);
@@ -274,4 +867,32 @@ impl Cache {
 
         None
     }
+
+    fn lookup_set(&self, address: usize) -> Option<&CacheLine> {
+        let index = self.get_index(address);
+
+        self.get_set(index).unwrap().iter().find(|line| {
+            line.tag == self.get_tag(address)
+                && line.state != CacheState::Invalid
+        })
+    }
+
+    /// Line holding `address` in any state but Invalid, transient ones, the
+    /// victim cache and the write-back buffer included
+    pub fn lookup(&self, address: usize) -> Option<&CacheLine> {
+        self.lookup_set(address)
+            .or_else(|| {
+                self.get_victim_position(address)
+                    .map(|position| &self.victims[position])
+            })
+            .or_else(|| {
+                self.get_write_back_position(address)
+                    .map(|position| &self.write_backs[position])
+            })
+    }
+
+    pub fn get_state(&self, address: usize) -> CacheState {
+        self.lookup(address)
+            .map_or(CacheState::Invalid, |line| line.state)
+    }
 }
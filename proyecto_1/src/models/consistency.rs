@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use crate::{models::Data, random::UniformRng};
+
+/// Writes that don't fit get the oldest drainable one drained first
+pub const STORE_BUFFER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsistencyModel {
+    /// Writes complete before the next instruction, no store buffer
+    Sequential,
+    /// Writes go through a FIFO store buffer, reads can pass them
+    TotalStoreOrder,
+    /// Like TSO but writes to different addresses may drain out of order
+    PartialStoreOrder,
+}
+
+impl ConsistencyModel {
+    pub const ALL: [ConsistencyModel; 3] = [
+        ConsistencyModel::Sequential,
+        ConsistencyModel::TotalStoreOrder,
+        ConsistencyModel::PartialStoreOrder,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            ConsistencyModel::Sequential => "SC",
+            ConsistencyModel::TotalStoreOrder => "TSO",
+            ConsistencyModel::PartialStoreOrder => "PSO",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ConsistencyModel> {
+        ConsistencyModel::ALL
+            .into_iter()
+            .find(|model| model.get_name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn uses_store_buffer(&self) -> bool {
+        *self != ConsistencyModel::Sequential
+    }
+}
+
+/// Pending writes of a CPU, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct StoreBuffer {
+    entries: VecDeque<(usize, Data)>,
+}
+
+impl StoreBuffer {
+    pub fn entries(&self) -> Vec<(usize, Data)> {
+        self.entries.iter().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    pub fn is_full(&self) -> bool { self.entries.len() >= STORE_BUFFER_SIZE }
+
+    pub fn push(&mut self, address: usize, data: Data) {
+        self.entries.push_back((address, data));
+    }
+
+    /// Reads see the youngest pending write to their address
+    pub fn forward(&self, address: usize) -> Option<Data> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(entry_address, _)| *entry_address == address)
+            .map(|(_, data)| *data)
+    }
+
+    /// Takes the next write to perform. Under PSO that's any write that is the
+    /// oldest one for its address, chosen with `rng`.
+    pub fn pop(
+        &mut self,
+        model: ConsistencyModel,
+        rng: &mut UniformRng,
+    ) -> Option<(usize, Data)> {
+        match model {
+            ConsistencyModel::PartialStoreOrder => {
+                let drainable: Vec<usize> = (0..self.entries.len())
+                    .filter(|&i| {
+                        self.entries
+                            .iter()
+                            .take(i)
+                            .all(|(address, _)| *address != self.entries[i].0)
+                    })
+                    .collect();
+                if drainable.is_empty() {
+                    return None;
+                }
+                let chosen = rng.gen_range(0..drainable.len() as u32) as usize;
+                self.entries.remove(drainable[chosen])
+            }
+            _ => self.entries.pop_front(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(
+        buffer: &mut StoreBuffer,
+        model: ConsistencyModel,
+        rng: &mut UniformRng,
+    ) -> Vec<(usize, Data)> {
+        std::iter::from_fn(|| buffer.pop(model, rng)).collect()
+    }
+
+    #[test]
+    fn reads_see_the_youngest_write() {
+        let mut buffer = StoreBuffer::default();
+        buffer.push(1, 10);
+        buffer.push(2, 20);
+        buffer.push(1, 11);
+        assert_eq!(buffer.forward(1), Some(11));
+        assert_eq!(buffer.forward(2), Some(20));
+        assert_eq!(buffer.forward(3), None);
+
+        let mut rng = UniformRng::from_seed(0);
+        let writes =
+            drain(&mut buffer, ConsistencyModel::TotalStoreOrder, &mut rng);
+        assert_eq!(writes, [(1, 10), (2, 20), (1, 11)]);
+        assert_eq!(buffer.forward(1), None);
+    }
+
+    #[test]
+    fn pso_only_keeps_the_order_of_each_address() {
+        let mut reordered = false;
+        for seed in 0..32 {
+            let mut buffer = StoreBuffer::default();
+            buffer.push(1, 10);
+            buffer.push(2, 20);
+            buffer.push(1, 11);
+            let mut rng = UniformRng::from_seed(seed);
+            let writes = drain(
+                &mut buffer,
+                ConsistencyModel::PartialStoreOrder,
+                &mut rng,
+            );
+            let ones: Vec<Data> = writes
+                .iter()
+                .filter(|(address, _)| *address == 1)
+                .map(|(_, data)| *data)
+                .collect();
+            assert_eq!(ones, [10, 11]);
+            reordered |= writes[0] == (2, 20);
+        }
+        assert!(reordered);
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeSet;
+
+use crate::models::bus::{BusAction, BusSignal};
+
+/// Caches that may hold a copy of every block, kept by the home node. Clean
+/// copies are dropped without telling it, so it can list caches that don't
+/// have the block anymore but never misses one that does.
+pub struct Directory {
+    sharers: Vec<BTreeSet<usize>>,
+}
+
+impl Directory {
+    pub fn new(blocks: usize) -> Self {
+        Directory {
+            sharers: vec![BTreeSet::new(); blocks],
+        }
+    }
+
+    /// Caches other than the requester that have to see the transaction. The
+    /// writer of a write back is asked for its data apart.
+    pub fn targets(&self, block: usize, signal: &BusSignal) -> Vec<usize> {
+        match signal.action {
+            BusAction::WriteMem => Vec::new(),
+            _ => self.sharers[block]
+                .iter()
+                .copied()
+                .filter(|&cache| cache != signal.origin)
+                .collect(),
+        }
+    }
+
+    /// Records who holds the block once the transaction is ordered
+    pub fn update(&mut self, block: usize, signal: &BusSignal) {
+        let sharers = &mut self.sharers[block];
+        match signal.action {
+            BusAction::ReadMiss => {
+                sharers.insert(signal.origin);
+            }
+            // A write through may leave the writer without a copy, the
+            // directory can't tell
+            BusAction::ReadExclusive
+            | BusAction::Invalidate
+            | BusAction::WriteThrough(_) => {
+                sharers.clear();
+                sharers.insert(signal.origin);
+            }
+            BusAction::WriteMem => {
+                sharers.remove(&signal.origin);
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::time::{Duration, Instant};
+
+use crate::models::{cache::CacheState, numa::NodeStats, Data};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowPolicy {
+    /// The row stays in the row buffer, the next access to it only pays tCAS
+    Open,
+    /// The row is precharged right after every access
+    Closed,
+}
+
+impl RowPolicy {
+    pub const ALL: [RowPolicy; 2] = [RowPolicy::Open, RowPolicy::Closed];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            RowPolicy::Open => "open",
+            RowPolicy::Closed => "closed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RowPolicy> {
+        RowPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Latencies are in memory cycles of `cycle` each
+#[derive(Debug, Clone, Copy)]
+pub struct DramTiming {
+    pub banks: usize,
+    /// Consecutive blocks that share a row, the next ones go to the next bank
+    pub row_blocks: usize,
+    pub row_policy: RowPolicy,
+    /// Activate, opens a row
+    pub t_rcd: u32,
+    /// Column access, reads or writes the open row
+    pub t_cas: u32,
+    /// Precharge, closes the open row
+    pub t_rp: u32,
+    pub cycle: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BankStats {
+    pub accesses: usize,
+    pub row_hits: usize,
+    /// The bank had no open row
+    pub row_misses: usize,
+    /// Another row had to be closed first
+    pub row_conflicts: usize,
+    pub busy_cycles: u64,
+    /// Time requests spent in the queue
+    pub total_wait: Duration,
+}
+
+impl BankStats {
+    pub fn average_wait(&self) -> Duration {
+        match self.accesses {
+            0 => Duration::ZERO,
+            accesses => self.total_wait / accesses as u32,
+        }
+    }
+}
+
+/// Block access on its way to the DRAM. The requester gets `state` and `data`
+/// once it's done.
+pub struct MemoryRequest {
+    /// Index of the block within the node of the controller
+    pub block: usize,
+    pub write: bool,
+    /// Write back nobody waits for, it has no data phase
+    pub posted: bool,
+    pub origin: usize,
+    /// The requester isn't local to the node
+    pub remote: bool,
+    pub state: CacheState,
+    pub data: Data,
+    pub arrival: Instant,
+}
+
+struct Bank {
+    open_row: Option<usize>,
+    busy_until: Instant,
+}
+
+/// Memory controller queue. Only keeps track of time, the data itself is read
+/// and written when the transaction is ordered.
+pub struct MemoryController {
+    timing: DramTiming,
+    banks: Vec<Bank>,
+    // In arrival order
+    queue: Vec<MemoryRequest>,
+    stats: Vec<BankStats>,
+    accesses: NodeStats,
+}
+
+impl MemoryController {
+    pub fn new(timing: DramTiming) -> Self {
+        let now = Instant::now();
+        MemoryController {
+            timing,
+            banks: (0..timing.banks)
+                .map(|_| Bank {
+                    open_row: None,
+                    busy_until: now,
+                })
+                .collect(),
+            queue: Vec::new(),
+            stats: vec![BankStats::default(); timing.banks],
+            accesses: NodeStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, request: MemoryRequest) { self.queue.push(request); }
+
+    pub fn queued(&self) -> usize { self.queue.len() }
+
+    pub fn stats(&self) -> &[BankStats] { &self.stats }
+
+    pub fn accesses(&self) -> NodeStats { self.accesses }
+
+    // Bank and row of a block
+    fn location(&self, block: usize) -> (usize, usize) {
+        let row_block = block / self.timing.row_blocks;
+        (row_block % self.timing.banks, row_block / self.timing.banks)
+    }
+
+    /// Starts the request FR-FCFS picks among the ones whose bank is free at
+    /// `now`: the oldest one that hits its open row, or else the oldest one.
+    /// Returns it along with the time its access is over.
+    pub fn issue(&mut self, now: Instant) -> Option<(MemoryRequest, Instant)> {
+        let ready: Vec<usize> = (0..self.queue.len())
+            .filter(|&i| {
+                let (bank, _) = self.location(self.queue[i].block);
+                self.banks[bank].busy_until <= now
+            })
+            .collect();
+        let chosen = ready
+            .iter()
+            .copied()
+            .find(|&i| {
+                let (bank, row) = self.location(self.queue[i].block);
+                self.banks[bank].open_row == Some(row)
+            })
+            .or(ready.first().copied())?;
+
+        let request = self.queue.remove(chosen);
+        if request.remote {
+            self.accesses.remote += 1;
+        } else {
+            self.accesses.local += 1;
+        }
+        let (bank_i, row) = self.location(request.block);
+        let timing = self.timing;
+        let stats = &mut self.stats[bank_i];
+        stats.accesses += 1;
+        stats.total_wait += now.saturating_duration_since(request.arrival);
+
+        let (access_cycles, precharge_cycles) = match timing.row_policy {
+            RowPolicy::Open => match self.banks[bank_i].open_row {
+                Some(open) if open == row => {
+                    stats.row_hits += 1;
+                    (timing.t_cas, 0)
+                }
+                Some(_) => {
+                    stats.row_conflicts += 1;
+                    (timing.t_rp + timing.t_rcd + timing.t_cas, 0)
+                }
+                None => {
+                    stats.row_misses += 1;
+                    (timing.t_rcd + timing.t_cas, 0)
+                }
+            },
+            // The data is out before the precharge, the bank isn't
+            RowPolicy::Closed => {
+                stats.row_misses += 1;
+                (timing.t_rcd + timing.t_cas, timing.t_rp)
+            }
+        };
+        stats.busy_cycles += (access_cycles + precharge_cycles) as u64;
+
+        let done = now + timing.cycle * access_cycles;
+        let bank = &mut self.banks[bank_i];
+        bank.busy_until = done + timing.cycle * precharge_cycles;
+        bank.open_row = match timing.row_policy {
+            RowPolicy::Open => Some(row),
+            RowPolicy::Closed => None,
+        };
+        Some((request, done))
+    }
+
+    /// Next time a bank with requests waiting for it frees up
+    pub fn next_bank_free(&self) -> Option<Instant> {
+        self.queue
+            .iter()
+            .map(|request| {
+                let (bank, _) = self.location(request.block);
+                self.banks[bank].busy_until
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(block: usize, arrival: Instant) -> MemoryRequest {
+        MemoryRequest {
+            block,
+            write: false,
+            posted: false,
+            origin: 0,
+            remote: false,
+            state: CacheState::Shared,
+            data: 0,
+            arrival,
+        }
+    }
+
+    fn issued(controller: &mut MemoryController, now: Instant) -> usize {
+        controller.issue(now).unwrap().0.block
+    }
+
+    #[test]
+    fn row_hits_go_first() {
+        let timing = |row_policy| DramTiming {
+            banks: 1,
+            row_blocks: 4,
+            row_policy,
+            t_rcd: 1,
+            t_cas: 1,
+            t_rp: 1,
+            cycle: Duration::from_nanos(1),
+        };
+        let mut controller = MemoryController::new(timing(RowPolicy::Open));
+        let now = Instant::now();
+        controller.push(request(0, now));
+        assert_eq!(issued(&mut controller, now), 0);
+        // Another row came in first, but row 0 is still open
+        controller.push(request(8, now));
+        controller.push(request(1, now));
+        let now = controller.next_bank_free().unwrap();
+        assert_eq!(issued(&mut controller, now), 1);
+        let now = controller.next_bank_free().unwrap();
+        assert_eq!(issued(&mut controller, now), 8);
+        let stats = controller.stats()[0];
+        assert_eq!(
+            (stats.row_misses, stats.row_hits, stats.row_conflicts),
+            (1, 1, 1)
+        );
+
+        // Closed rows never hit, the oldest request goes first
+        let mut controller = MemoryController::new(timing(RowPolicy::Closed));
+        let now = Instant::now();
+        controller.push(request(0, now));
+        assert_eq!(issued(&mut controller, now), 0);
+        controller.push(request(8, now));
+        controller.push(request(1, now));
+        let now = controller.next_bank_free().unwrap();
+        assert_eq!(issued(&mut controller, now), 8);
+        // The bank is still precharging
+        assert!(controller.issue(now).is_none());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::models::{bus::TrafficStats, cache::CacheActivity};
+
+/// Energy every event takes, in picojoules
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyModel {
+    /// Tags and data are read in parallel on every demand access
+    pub tag_lookup: f32,
+    pub data_read: f32,
+    pub data_write: f32,
+    /// A transaction on the bus, and every cache that has to look it up
+    pub broadcast: f32,
+    pub snoop: f32,
+    /// A block read from or written to memory
+    pub memory_access: f32,
+    /// Static energy of a cache line per cycle
+    pub leakage: f32,
+}
+
+/// Ballpark figures for small SRAM arrays and a DRAM line access
+pub const DEFAULT_ENERGY: EnergyModel = EnergyModel {
+    tag_lookup: 1.0,
+    data_read: 5.0,
+    data_write: 6.0,
+    broadcast: 10.0,
+    snoop: 2.0,
+    memory_access: 500.0,
+    leakage: 0.01,
+};
+
+/// Energy of every component, in picojoules
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnergyReport {
+    pub caches: f32,
+    pub bus: f32,
+    pub memory: f32,
+    pub leakage: f32,
+}
+
+impl EnergyReport {
+    pub fn total(&self) -> f32 {
+        self.caches + self.bus + self.memory + self.leakage
+    }
+}
+
+impl EnergyModel {
+    /// Names of the energies on the command line
+    pub const KEYS: [&'static str; 7] = [
+        "tag",
+        "data-read",
+        "data-write",
+        "broadcast",
+        "snoop",
+        "memory",
+        "leakage",
+    ];
+
+    /// Returns false if there's no energy called `key`
+    pub fn set(&mut self, key: &str, picojoules: f32) -> bool {
+        let energy = match key {
+            "tag" => &mut self.tag_lookup,
+            "data-read" => &mut self.data_read,
+            "data-write" => &mut self.data_write,
+            "broadcast" => &mut self.broadcast,
+            "snoop" => &mut self.snoop,
+            "memory" => &mut self.memory_access,
+            "leakage" => &mut self.leakage,
+            _ => return false,
+        };
+        *energy = picojoules;
+        true
+    }
+
+    /// `activity` is the sum of every cache's, `lines` the lines of all of
+    /// them and `cycles` how long the run took
+    pub fn report(
+        &self,
+        activity: &CacheActivity,
+        traffic: &TrafficStats,
+        lines: usize,
+        cycles: usize,
+    ) -> EnergyReport {
+        EnergyReport {
+            caches: activity.tag_lookups as f32 * self.tag_lookup
+                + activity.data_reads as f32 * self.data_read
+                + activity.data_writes as f32 * self.data_write,
+            bus: traffic.transactions() as f32 * self.broadcast
+                + traffic.snoops as f32 * self.snoop,
+            memory: (traffic.memory_reads + traffic.memory_writes) as f32
+                * self.memory_access,
+            leakage: lines as f32 * cycles as f32 * self.leakage,
+        }
+    }
+}
@@ -0,0 +1,497 @@
+//! Runs the caches, memory and protocol of the threaded system on the calling
+//! thread, calling them directly instead of handing messages between threads.
+//! Every transaction completes before the next one starts, so lines never
+//! wait in transient states or in the write-back buffer. There's no timing
+//! and no store buffer either, the CPUs are sequentially consistent.
+
+use crate::models::{
+    bus::{BusAction, BusSignal, TrafficStats},
+    cache::{Cache, CacheActivity, CacheLine, CacheState, WritePolicy},
+    consistency::ConsistencyModel,
+    directory::Directory,
+    instructions::{Address, Instruction, NUM_REGISTERS},
+    interconnect::CoherenceScheme,
+    main_memory::Memory,
+    prefetcher::{PrefetchStats, Prefetcher},
+    processor::snoop,
+    system::{InvalidProps, SocProperties},
+    Data,
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EngineStats {
+    /// Memory instructions executed
+    pub accesses: usize,
+    pub read_misses: usize,
+    pub write_misses: usize,
+    pub traffic: TrafficStats,
+    /// Of all of the CPUs
+    pub prefetch: PrefetchStats,
+    /// Memory accesses from the processors of the block's node
+    pub local_accesses: usize,
+    /// Memory accesses from the processors of other nodes
+    pub remote_accesses: usize,
+    /// Of all of the caches
+    pub activity: CacheActivity,
+    /// Instructions of the CPU that executed the most
+    pub cycles: usize,
+}
+
+struct EngineCpu {
+    registers: [Data; NUM_REGISTERS],
+    prefetcher: Prefetcher,
+    executed: usize,
+}
+
+pub struct Engine {
+    props: SocProperties,
+    cpus: Vec<EngineCpu>,
+    caches: Vec<Cache>,
+    memory: Memory,
+    directory: Directory,
+    // Addresses wrap around at the end of main memory
+    address_space: usize,
+    stats: EngineStats,
+}
+
+impl Engine {
+    /// Fails if `props` isn't valid, or asks for a relaxed consistency model
+    /// the engine can't run
+    pub fn new(props: SocProperties) -> Result<Self, InvalidProps> {
+        props.validate()?;
+        if props.consistency_model != ConsistencyModel::Sequential {
+            return Err(InvalidProps(format!(
+                "the fast engine only runs SC, not {}",
+                props.consistency_model.get_name()
+            )));
+        }
+        let memory = Memory::new(props.main_memory_blocks);
+        let address_space = memory.address_space();
+        let caches = (0..props.num_processors)
+            .map(|i| {
+                let mut cache = Cache::new_cold(
+                    i,
+                    props.cache_associativity,
+                    props.cache_sets,
+                    props.victim_cache,
+                    props.write_back_buffer,
+                    props.write_policy,
+                    props.write_allocate,
+                );
+                cache.set_replacement_policy(props.replacement);
+                cache
+            })
+            .collect();
+        let cpus = (0..props.num_processors)
+            .map(|_| EngineCpu {
+                registers: [0; NUM_REGISTERS],
+                prefetcher: Prefetcher::new(
+                    props.prefetch,
+                    props.prefetch_degree,
+                    address_space,
+                ),
+                executed: 0,
+            })
+            .collect();
+
+        Ok(Engine {
+            props,
+            cpus,
+            caches,
+            memory,
+            directory: Directory::new(props.main_memory_blocks),
+            address_space,
+            stats: EngineStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        let mut stats = self.stats;
+        for cache in &self.caches {
+            let prefetch = cache.prefetch_stats();
+            stats.prefetch.issued += prefetch.issued;
+            stats.prefetch.useful += prefetch.useful;
+            stats.prefetch.late += prefetch.late;
+            stats.prefetch.useless += prefetch.useless;
+            stats.activity.add(&cache.activity());
+        }
+        stats.cycles =
+            self.cpus.iter().map(|cpu| cpu.executed).max().unwrap_or(0);
+        stats
+    }
+
+    fn memory_access(&mut self, origin: usize, address: usize) {
+        let block = self.memory.get_line(address);
+        match self.props.numa.is_remote(
+            origin,
+            self.props.num_processors,
+            block,
+        ) {
+            true => self.stats.remote_accesses += 1,
+            false => self.stats.local_accesses += 1,
+        }
+    }
+
+    // Address and data phases of a transaction at once, the same as
+    // `handle_signal` does. Returns the state the requester installs its
+    // line in and the data it gets.
+    fn transaction(&mut self, signal: BusSignal) -> (CacheState, Data) {
+        let block = self.memory.get_line(signal.address);
+        let targets = match self.props.coherence {
+            CoherenceScheme::Snooping => (0..self.props.num_processors)
+                .filter(|&i| i != signal.origin)
+                .collect(),
+            CoherenceScheme::Directory => {
+                self.directory.targets(block, &signal)
+            }
+        };
+        self.directory.update(block, &signal);
+        self.stats.traffic.snoops += targets.len();
+
+        // Every target has to see it, even once one of them supplied the data
+        let protocol = self.props.protocol;
+        let supplied = targets
+            .iter()
+            .map(|&target| {
+                // Lines never wait for their data here
+                snoop(&mut self.caches[target], &signal, protocol)
+                    .expect("the engine has no transient states")
+            })
+            .fold(None, Option::or);
+
+        let traffic = &mut self.stats.traffic;
+        match signal.action {
+            BusAction::ReadMiss => {
+                traffic.read_misses += 1;
+                traffic.data_transfers += 1;
+                match supplied {
+                    Some(data) => {
+                        // Without an Owned state the block can't stay dirty
+                        // in the cache that supplied it
+                        if !protocol.has_owned() {
+                            traffic.memory_writes += 1;
+                            self.memory.store_address(signal.address, data);
+                            self.memory_access(signal.origin, signal.address);
+                        }
+                        (protocol.read_state(true), data)
+                    }
+                    None => {
+                        traffic.memory_reads += 1;
+                        self.memory_access(signal.origin, signal.address);
+                        (
+                            protocol.read_state(false),
+                            self.memory.get_address(signal.address),
+                        )
+                    }
+                }
+            }
+            BusAction::ReadExclusive => {
+                traffic.read_exclusives += 1;
+                traffic.data_transfers += 1;
+                // An upgrading line has the newest data when nobody else
+                // supplies it, memory may be stale if it was Owned
+                let own = self.caches[signal.origin]
+                    .get_address(signal.address)
+                    .map(|line| line.data);
+                match supplied.or(own) {
+                    Some(data) => (CacheState::Modified, data),
+                    None => {
+                        traffic.memory_reads += 1;
+                        self.memory_access(signal.origin, signal.address);
+                        (
+                            CacheState::Modified,
+                            self.memory.get_address(signal.address),
+                        )
+                    }
+                }
+            }
+            BusAction::Invalidate => {
+                traffic.invalidates += 1;
+                (CacheState::Modified, supplied.unwrap_or_default())
+            }
+            BusAction::WriteThrough(data) => {
+                traffic.write_throughs += 1;
+                traffic.data_transfers += 1;
+                traffic.memory_writes += 1;
+                self.memory.store_address(signal.address, data);
+                self.memory_access(signal.origin, signal.address);
+                (CacheState::Shared, data)
+            }
+            BusAction::WriteMem => unreachable!("write backs go on their own"),
+        }
+    }
+
+    // Makes room for `address`, a dirty line in the way is written back
+    // right away. Clean lines are dropped silently when the new line is
+    // stored.
+    fn allocate(&mut self, cpu_i: usize, address: usize) {
+        let dirty = self.caches[cpu_i]
+            .get_victim(address)
+            .filter(|(_, victim)| {
+                matches!(victim.state, CacheState::Modified | CacheState::Owned)
+            })
+            .map(|(victim_address, victim)| (victim_address, victim.data));
+        let Some((victim_address, data)) = dirty else {
+            return;
+        };
+
+        self.caches[cpu_i].data_read();
+        self.caches[cpu_i].invalidate_address(victim_address);
+        let signal = BusSignal {
+            origin: cpu_i,
+            address: victim_address,
+            action: BusAction::WriteMem,
+            prefetch: false,
+        };
+        let block = self.memory.get_line(victim_address);
+        self.directory.update(block, &signal);
+
+        let traffic = &mut self.stats.traffic;
+        traffic.write_backs += 1;
+        traffic.data_transfers += 1;
+        traffic.memory_writes += 1;
+        self.memory.store_address(victim_address, data);
+        self.memory_access(cpu_i, victim_address);
+    }
+
+    // Runs a transaction of the CPU on `address`
+    fn request(
+        &mut self,
+        cpu_i: usize,
+        address: usize,
+        action: BusAction,
+        prefetch: bool,
+    ) -> (CacheState, Data) {
+        self.transaction(BusSignal {
+            origin: cpu_i,
+            address,
+            action,
+            prefetch,
+        })
+    }
+
+    fn read(&mut self, cpu_i: usize, address: usize, reserve: bool) -> Data {
+        let cache = &mut self.caches[cpu_i];
+        cache.swap_victim(address);
+        cache.demand_access(address);
+        let data = match cache.get_address(address) {
+            Some(&CacheLine { data, .. }) => data,
+            None => {
+                self.stats.read_misses += 1;
+                self.allocate(cpu_i, address);
+                let (state, data) =
+                    self.request(cpu_i, address, BusAction::ReadMiss, false);
+                self.caches[cpu_i].store_line(address, state, data);
+                data
+            }
+        };
+        if reserve {
+            self.caches[cpu_i].set_reservation(Some(address));
+        }
+        data
+    }
+
+    fn write(&mut self, cpu_i: usize, address: usize, data: Data) {
+        let cache = &mut self.caches[cpu_i];
+        cache.swap_victim(address);
+        cache.demand_access(address);
+        let write_through = cache.write_policy() == WritePolicy::WriteThrough;
+        let allocate = cache.write_allocate();
+        let state = cache.get_address(address).map(|line| line.state);
+        match state {
+            // Dirty lines of a write-through cache come from atomics, they
+            // stay dirty
+            Some(CacheState::Modified) => {
+                cache.store_line(address, CacheState::Modified, data);
+            }
+            Some(CacheState::Exclusive) if !write_through => {
+                cache.store_line(address, CacheState::Modified, data);
+            }
+            Some(CacheState::Shared | CacheState::Exclusive)
+                if write_through =>
+            {
+                let action = BusAction::WriteThrough(data);
+                let (state, _) = self.request(cpu_i, address, action, false);
+                self.caches[cpu_i].store_line(address, state, data);
+            }
+            None if write_through || !allocate => {
+                self.stats.write_misses += 1;
+                if allocate {
+                    self.allocate(cpu_i, address);
+                }
+                let action = BusAction::WriteThrough(data);
+                let (state, _) = self.request(cpu_i, address, action, false);
+                if allocate {
+                    self.caches[cpu_i].store_line(address, state, data);
+                }
+            }
+            // The block is a single word, invalidating the other copies is
+            // enough
+            _ => {
+                if state.is_none() {
+                    self.stats.write_misses += 1;
+                    self.allocate(cpu_i, address);
+                }
+                self.request(cpu_i, address, BusAction::Invalidate, false);
+                self.caches[cpu_i].store_line(
+                    address,
+                    CacheState::Modified,
+                    data,
+                );
+            }
+        }
+    }
+
+    // Gets the line in Modified state and applies `op` to its data, see
+    // `cpu_atomic_rmw`
+    fn atomic_rmw(
+        &mut self,
+        cpu_i: usize,
+        address: usize,
+        op: impl FnOnce(&mut Cache, Data) -> Option<Data>,
+    ) -> (Data, bool) {
+        let cache = &mut self.caches[cpu_i];
+        cache.swap_victim(address);
+        cache.demand_access(address);
+        let state = cache.get_address(address).map(|line| line.state);
+        let data = match state {
+            Some(CacheState::Modified | CacheState::Exclusive) => {
+                cache.get_address(address).unwrap().data
+            }
+            _ => {
+                if state.is_none() {
+                    self.stats.write_misses += 1;
+                    self.allocate(cpu_i, address);
+                }
+                let action = BusAction::ReadExclusive;
+                self.request(cpu_i, address, action, false).1
+            }
+        };
+
+        let cache = &mut self.caches[cpu_i];
+        let new_data = op(cache, data);
+        cache.store_line(
+            address,
+            CacheState::Modified,
+            new_data.unwrap_or(data),
+        );
+        (data, new_data.is_some())
+    }
+
+    // Brings in a block the prefetcher predicted, unless the cache holds it
+    // already
+    fn prefetch(&mut self, cpu_i: usize, address: usize) {
+        if self.caches[cpu_i].lookup(address).is_some() {
+            return;
+        }
+        self.allocate(cpu_i, address);
+        let (state, data) =
+            self.request(cpu_i, address, BusAction::ReadMiss, true);
+        let cache = &mut self.caches[cpu_i];
+        cache.store_line(address, state, data);
+        cache.prefetch_issued(address);
+    }
+
+    fn write_register(&mut self, cpu_i: usize, register: usize, data: Data) {
+        self.cpus[cpu_i].registers[register] = data;
+    }
+
+    /// Executes an instruction on a CPU. There's no program, so branches and
+    /// the flags they look at don't go anywhere.
+    pub fn execute(&mut self, cpu_i: usize, instruction: &Instruction) {
+        let registers = self.cpus[cpu_i].registers;
+        let address_space = self.address_space;
+        let resolve =
+            |address: &Address| address.resolve(&registers, address_space);
+        self.cpus[cpu_i].executed += 1;
+        if let Some(address) = instruction.address() {
+            self.stats.accesses += 1;
+            if self.cpus[cpu_i].prefetcher.access(resolve(&address)) {
+                self.caches[cpu_i].prefetch_late();
+            }
+        }
+
+        match instruction {
+            Instruction::Calc
+            | Instruction::Label(_)
+            | Instruction::Fence
+            | Instruction::Cmp { .. }
+            | Instruction::Branch { .. } => (),
+            Instruction::Read { rd, address } => {
+                let data = self.read(cpu_i, resolve(address), false);
+                self.write_register(cpu_i, *rd, data);
+            }
+            Instruction::Write { address, src } => {
+                self.write(cpu_i, resolve(address), src.value(&registers));
+            }
+            Instruction::TestAndSet { rd, address } => {
+                let (old, _) =
+                    self.atomic_rmw(cpu_i, resolve(address), |_, _| Some(1));
+                self.write_register(cpu_i, *rd, old);
+            }
+            Instruction::FetchAndAdd { rd, address, value } => {
+                let value = value.value(&registers);
+                let (old, _) =
+                    self.atomic_rmw(cpu_i, resolve(address), |_, data| {
+                        Some(data.wrapping_add(value))
+                    });
+                self.write_register(cpu_i, *rd, old);
+            }
+            Instruction::CompareAndSwap {
+                rd,
+                address,
+                expected,
+                new,
+            } => {
+                let expected = expected.value(&registers);
+                let new = new.value(&registers);
+                let (old, _) =
+                    self.atomic_rmw(cpu_i, resolve(address), |_, data| {
+                        Some(if data == expected { new } else { data })
+                    });
+                self.write_register(cpu_i, *rd, old);
+            }
+            Instruction::LoadLinked { rd, address } => {
+                let data = self.read(cpu_i, resolve(address), true);
+                self.write_register(cpu_i, *rd, data);
+            }
+            Instruction::StoreConditional { rd, address, src } => {
+                let address = resolve(address);
+                let data = src.value(&registers);
+                let success = match self.caches[cpu_i].has_reservation(address)
+                {
+                    true => {
+                        self.atomic_rmw(cpu_i, address, |cache, _| {
+                            let reserved = cache.has_reservation(address);
+                            cache.set_reservation(None);
+                            reserved.then_some(data)
+                        })
+                        .1
+                    }
+                    false => {
+                        self.caches[cpu_i].set_reservation(None);
+                        false
+                    }
+                };
+                self.write_register(cpu_i, *rd, success.into());
+            }
+            Instruction::Alu {
+                op,
+                rd,
+                rs,
+                operand,
+            } => {
+                let data = op.apply(registers[*rs], operand.value(&registers));
+                self.write_register(cpu_i, *rd, data);
+            }
+            Instruction::Mov { rd, operand } => {
+                self.write_register(cpu_i, *rd, operand.value(&registers));
+            }
+        }
+
+        // One prefetch after every instruction, like the threaded CPUs
+        if let Some(address) = self.cpus[cpu_i].prefetcher.next_prefetch() {
+            self.prefetch(cpu_i, address);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvError, RecvTimeoutError, SendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::{Event, EventHub},
+    models::{bus::BusSignal, cache::CacheState},
+};
+
+// How often blocked components check whether the system is shutting down
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+/// Parts of the system that run on a thread of their own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Component {
+    Cpu(usize),
+    Controller(usize),
+    Bus,
+    DataBus,
+    /// Of a memory node
+    MemoryController(usize),
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Component::Cpu(i) => write!(f, "CPU{}", i + 1),
+            Component::Controller(i) => {
+                write!(f, "cache controller of CPU{}", i + 1)
+            }
+            Component::Bus => write!(f, "bus"),
+            Component::DataBus => write!(f, "data bus"),
+            Component::MemoryController(node) => {
+                write!(f, "memory controller of node {node}")
+            }
+        }
+    }
+}
+
+/// Where an error happened, filled in on its way up to the thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorContext {
+    pub component: Option<Component>,
+    /// Transaction being handled
+    pub signal: Option<BusSignal>,
+    /// Address the instruction being executed accesses
+    pub address: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub enum SimError {
+    /// Another component went away while this one still needed it
+    Disconnected(ErrorContext),
+    /// A line was in a state the protocol doesn't allow for the transaction
+    /// it saw
+    Protocol {
+        context: ErrorContext,
+        state: CacheState,
+    },
+    Panicked {
+        context: ErrorContext,
+        message: String,
+    },
+}
+
+impl SimError {
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            SimError::Disconnected(context)
+            | SimError::Protocol { context, .. }
+            | SimError::Panicked { context, .. } => context,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        match self {
+            SimError::Disconnected(context)
+            | SimError::Protocol { context, .. }
+            | SimError::Panicked { context, .. } => context,
+        }
+    }
+
+    /// Fills in the component if the error doesn't know it yet, what was
+    /// found out closer to where it happened wins
+    pub fn in_component(mut self, component: Component) -> Self {
+        self.context_mut().component.get_or_insert(component);
+        self
+    }
+
+    /// Fills in the transaction, like `in_component`
+    pub fn during(mut self, signal: BusSignal) -> Self {
+        self.context_mut().signal.get_or_insert(signal);
+        self
+    }
+
+    /// Fills in the address, like `in_component`
+    pub fn at(mut self, address: usize) -> Self {
+        self.context_mut().address.get_or_insert(address);
+        self
+    }
+
+    fn panicked(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => (*message).to_owned(),
+                Err(_) => "unknown cause".to_owned(),
+            },
+        };
+        SimError::Panicked {
+            context: ErrorContext::default(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let context = self.context();
+        if let Some(component) = context.component {
+            write!(f, "{component}: ")?;
+        }
+        match self {
+            SimError::Disconnected(_) => {
+                write!(f, "another part of the system stopped")?
+            }
+            SimError::Protocol { state, .. } => {
+                write!(f, "snooped a line in state {}", state.get_letter())?
+            }
+            SimError::Panicked { message, .. } => {
+                write!(f, "panicked: {message}")?
+            }
+        }
+        if let Some(signal) = context.signal {
+            write!(
+                f,
+                ", during {:?} of address {:#x} from CPU{}",
+                signal.action,
+                signal.address,
+                signal.origin + 1
+            )?;
+        } else if let Some(address) = context.address {
+            write!(f, ", accessing address {address:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for SimError {}
+
+impl<T> From<SendError<T>> for SimError {
+    fn from(_: SendError<T>) -> Self {
+        SimError::Disconnected(ErrorContext::default())
+    }
+}
+
+impl From<RecvError> for SimError {
+    fn from(_: RecvError) -> Self {
+        SimError::Disconnected(ErrorContext::default())
+    }
+}
+
+/// Raised once a component fails or the system is stopped, every component
+/// blocked waiting for another one gives up then. Otherwise a component
+/// waiting on one that's gone would wait forever as long as a third one kept
+/// the channel open.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    /// Returns whether it was the first to raise it
+    pub fn raise(&self) -> bool { !self.0.swap(true, Ordering::SeqCst) }
+
+    pub fn is_raised(&self) -> bool { self.0.load(Ordering::SeqCst) }
+
+    /// Like `thread::sleep`, but wakes up to fail once the system shuts down
+    pub fn sleep_until(&self, deadline: Instant) -> Result<(), RecvError> {
+        loop {
+            if self.is_raised() {
+                return Err(RecvError);
+            }
+            let now = Instant::now();
+            if deadline <= now {
+                return Ok(());
+            }
+            thread::sleep((deadline - now).min(SHUTDOWN_POLL));
+        }
+    }
+
+    /// Like `Receiver::recv`, but also fails once the system shuts down
+    pub fn recv<T>(&self, rx: &Receiver<T>) -> Result<T, RecvError> {
+        self.recv_until(rx, None).map_err(|_| RecvError)
+    }
+
+    /// Waits until `deadline` if there's one, a shutdown shows up as a
+    /// disconnection
+    pub fn recv_until<T>(
+        &self,
+        rx: &Receiver<T>,
+        deadline: Option<Instant>,
+    ) -> Result<T, RecvTimeoutError> {
+        loop {
+            let timeout = deadline.map_or(SHUTDOWN_POLL, |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(SHUTDOWN_POLL)
+            });
+            match rx.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) if self.is_raised() => {
+                    return Err(RecvTimeoutError::Disconnected)
+                }
+                Err(RecvTimeoutError::Timeout)
+                    if deadline
+                        .is_some_and(|deadline| deadline <= Instant::now()) =>
+                {
+                    return Err(RecvTimeoutError::Timeout)
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Runs `body` on a thread of its own as `component`. The first component to
+/// fail or panic raises the shutdown and reports why, the rest only wind down.
+/// `state` is dropped after that, so its channels closing can't make another
+/// component report first.
+pub fn spawn_component<S: Send + 'static>(
+    component: Component,
+    shutdown: Shutdown,
+    gui_tx: EventHub,
+    mut state: S,
+    body: impl FnOnce(&mut S) -> Result<(), SimError> + Send + 'static,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| body(&mut state)))
+            .unwrap_or_else(|payload| Err(SimError::panicked(payload)));
+        if let Err(err) = result {
+            if shutdown.raise() {
+                let err = err.in_component(component);
+                gui_tx.send(Event::Error(err));
+            }
+        }
+        drop(state);
+    })
+}
@@ -1,12 +1,216 @@
-use std::fmt;
+use std::{collections::HashMap, fmt, num::ParseIntError, str::FromStr};
 
 use crate::models::Data;
 
+pub const NUM_REGISTERS: usize = 8;
+
+pub type Register = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Immediate(Data),
+}
+
+impl Operand {
+    pub fn value(&self, registers: &[Data]) -> Data {
+        match self {
+            Operand::Register(register) => registers[*register],
+            Operand::Immediate(data) => *data,
+        }
+    }
+}
+
+/// Memory operand, the effective address is `offset` plus the value of the
+/// `index` register if there's one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Address {
+    pub offset: usize,
+    pub index: Option<Register>,
+}
+
+impl Address {
+    /// Addresses wrap around at the end of the address space, offsets and
+    /// index registers of any size included
+    pub fn resolve(&self, registers: &[Data], address_space: usize) -> usize {
+        let offset = self.offset % address_space;
+        match self.index {
+            Some(register) => {
+                let index = registers[register] as usize % address_space;
+                (offset + index) % address_space
+            }
+            None => offset,
+        }
+    }
+}
+
+impl From<usize> for Address {
+    fn from(offset: usize) -> Self {
+        Address {
+            offset,
+            index: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl AluOp {
+    pub const ALL: [AluOp; 7] = [
+        AluOp::Add,
+        AluOp::Sub,
+        AluOp::And,
+        AluOp::Or,
+        AluOp::Xor,
+        AluOp::Shl,
+        AluOp::Shr,
+    ];
+
+    pub fn apply(&self, a: Data, b: Data) -> Data {
+        match self {
+            AluOp::Add => a.wrapping_add(b),
+            AluOp::Sub => a.wrapping_sub(b),
+            AluOp::And => a & b,
+            AluOp::Or => a | b,
+            AluOp::Xor => a ^ b,
+            AluOp::Shl => a.wrapping_shl(b.into()),
+            AluOp::Shr => a.wrapping_shr(b.into()),
+        }
+    }
+
+    pub fn get_mnemonic(&self) -> &str {
+        match self {
+            AluOp::Add => "add",
+            AluOp::Sub => "sub",
+            AluOp::And => "and",
+            AluOp::Or => "or",
+            AluOp::Xor => "xor",
+            AluOp::Shl => "shl",
+            AluOp::Shr => "shr",
+        }
+    }
+}
+
+/// Set by `cmp`, comparisons are unsigned
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Flags {
+    pub zero: bool,
+    pub less: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Always,
+    Equal,
+    NotEqual,
+    Less,
+    GreaterEqual,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 5] = [
+        Condition::Always,
+        Condition::Equal,
+        Condition::NotEqual,
+        Condition::Less,
+        Condition::GreaterEqual,
+    ];
+
+    pub fn holds(&self, flags: Flags) -> bool {
+        match self {
+            Condition::Always => true,
+            Condition::Equal => flags.zero,
+            Condition::NotEqual => !flags.zero,
+            Condition::Less => flags.less,
+            Condition::GreaterEqual => !flags.less,
+        }
+    }
+
+    pub fn get_mnemonic(&self) -> &str {
+        match self {
+            Condition::Always => "b",
+            Condition::Equal => "beq",
+            Condition::NotEqual => "bne",
+            Condition::Less => "blt",
+            Condition::GreaterEqual => "bge",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Calc,
-    Read { address: usize },
-    Write { address: usize, data: Data },
+    Read {
+        rd: Register,
+        address: Address,
+    },
+    Write {
+        address: Address,
+        src: Operand,
+    },
+    /// Atomic read-modify-write instructions, they acquire the line in
+    /// Modified state before updating it and leave the old value in `rd`.
+    /// This one writes 1.
+    TestAndSet {
+        rd: Register,
+        address: Address,
+    },
+    /// Adds `value`, atomically like `TestAndSet`
+    FetchAndAdd {
+        rd: Register,
+        address: Address,
+        value: Operand,
+    },
+    /// Writes `new` if the old value is `expected`, atomically like
+    /// `TestAndSet`
+    CompareAndSwap {
+        rd: Register,
+        address: Address,
+        expected: Operand,
+        new: Operand,
+    },
+    /// Reads the address and reserves it for a `StoreConditional`
+    LoadLinked {
+        rd: Register,
+        address: Address,
+    },
+    /// Only writes if the reservation of the last `LoadLinked` survived,
+    /// leaves 1 in `rd` on success and 0 otherwise
+    StoreConditional {
+        rd: Register,
+        address: Address,
+        src: Operand,
+    },
+    /// Waits for the store buffer to drain before going on
+    Fence,
+    Alu {
+        op: AluOp,
+        rd: Register,
+        rs: Register,
+        operand: Operand,
+    },
+    Mov {
+        rd: Register,
+        operand: Operand,
+    },
+    Cmp {
+        rs: Register,
+        operand: Operand,
+    },
+    Branch {
+        condition: Condition,
+        label: String,
+    },
+    Label(String),
 }
 
 impl Instruction {
@@ -15,6 +219,75 @@ impl Instruction {
             Instruction::Calc => "Calc",
             Instruction::Read { .. } => "Read",
             Instruction::Write { .. } => "Write",
+            Instruction::TestAndSet { .. } => "TestAndSet",
+            Instruction::FetchAndAdd { .. } => "FetchAndAdd",
+            Instruction::CompareAndSwap { .. } => "CompareAndSwap",
+            Instruction::LoadLinked { .. } => "LoadLinked",
+            Instruction::StoreConditional { .. } => "StoreConditional",
+            Instruction::Fence => "Fence",
+            Instruction::Alu { .. } => "Alu",
+            Instruction::Mov { .. } => "Mov",
+            Instruction::Cmp { .. } => "Cmp",
+            Instruction::Branch { .. } => "Branch",
+            Instruction::Label(_) => "Label",
+        }
+    }
+
+    pub fn address(&self) -> Option<Address> {
+        match self {
+            Instruction::Read { address, .. }
+            | Instruction::Write { address, .. }
+            | Instruction::TestAndSet { address, .. }
+            | Instruction::FetchAndAdd { address, .. }
+            | Instruction::CompareAndSwap { address, .. }
+            | Instruction::LoadLinked { address, .. }
+            | Instruction::StoreConditional { address, .. } => Some(*address),
+            _ => None,
+        }
+    }
+
+    pub fn address_mut(&mut self) -> Option<&mut Address> {
+        match self {
+            Instruction::Read { address, .. }
+            | Instruction::Write { address, .. }
+            | Instruction::TestAndSet { address, .. }
+            | Instruction::FetchAndAdd { address, .. }
+            | Instruction::CompareAndSwap { address, .. }
+            | Instruction::LoadLinked { address, .. }
+            | Instruction::StoreConditional { address, .. } => Some(address),
+            _ => None,
+        }
+    }
+
+    pub fn destination_mut(&mut self) -> Option<&mut Register> {
+        match self {
+            Instruction::Read { rd, .. }
+            | Instruction::TestAndSet { rd, .. }
+            | Instruction::FetchAndAdd { rd, .. }
+            | Instruction::CompareAndSwap { rd, .. }
+            | Instruction::LoadLinked { rd, .. }
+            | Instruction::StoreConditional { rd, .. }
+            | Instruction::Alu { rd, .. }
+            | Instruction::Mov { rd, .. } => Some(rd),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "r{register}"),
+            Operand::Immediate(data) => write!(f, "{data:#04X}"),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(register) => write!(f, "{:#04b}(r{register})", self.offset),
+            None => write!(f, "{:#04b}", self.offset),
         }
     }
 }
@@ -23,12 +296,343 @@ impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Instruction::Calc => write!(f, "calc"),
-            Instruction::Read { address } => {
-                write!(f, "read {address:#04b}")
+            Instruction::Fence => write!(f, "fence"),
+            Instruction::Read { rd, address } => {
+                write!(f, "read r{rd}, {address}")
+            }
+            Instruction::Write { address, src } => {
+                write!(f, "write {address}, {src}")
+            }
+            Instruction::TestAndSet { rd, address } => {
+                write!(f, "tas r{rd}, {address}")
             }
-            Instruction::Write { address, data } => {
-                write!(f, "write {address:#04b}; {data:#04X}")
+            Instruction::FetchAndAdd { rd, address, value } => {
+                write!(f, "faa r{rd}, {address}, {value}")
             }
+            Instruction::CompareAndSwap {
+                rd,
+                address,
+                expected,
+                new,
+            } => {
+                write!(f, "cas r{rd}, {address}, {expected}, {new}")
+            }
+            Instruction::LoadLinked { rd, address } => {
+                write!(f, "ll r{rd}, {address}")
+            }
+            Instruction::StoreConditional { rd, address, src } => {
+                write!(f, "sc r{rd}, {address}, {src}")
+            }
+            Instruction::Alu {
+                op,
+                rd,
+                rs,
+                operand,
+            } => {
+                write!(f, "{} r{rd}, r{rs}, {operand}", op.get_mnemonic())
+            }
+            Instruction::Mov { rd, operand } => {
+                write!(f, "mov r{rd}, {operand}")
+            }
+            Instruction::Cmp { rs, operand } => {
+                write!(f, "cmp r{rs}, {operand}")
+            }
+            Instruction::Branch { condition, label } => {
+                write!(f, "{} {label}", condition.get_mnemonic())
+            }
+            Instruction::Label(label) => write!(f, "{label}:"),
+        }
+    }
+}
+
+/// Parses numbers in decimal or with a 0x/0b prefix
+pub fn parse_number<T: FromStr<Err = ParseIntError>>(
+    text: &str,
+) -> Result<T, ParseIntError> {
+    let text = text.trim();
+    let (digits, radix) = if let Some(hex) =
+        text.strip_prefix("0x").or_else(|| text.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if let Some(bin) =
+        text.strip_prefix("0b").or_else(|| text.strip_prefix("0B"))
+    {
+        (bin, 2)
+    } else {
+        return text.parse();
+    };
+
+    // Going through the decimal representation gives the target type's
+    // overflow error for free
+    u64::from_str_radix(&digits.replace('_', ""), radix)?
+        .to_string()
+        .parse()
+}
+
+pub fn parse_register(text: &str) -> Option<Register> {
+    let register = text.trim().strip_prefix('r')?.parse().ok()?;
+    (register < NUM_REGISTERS).then_some(register)
+}
+
+impl FromStr for Operand {
+    type Err = ParseIntError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match parse_register(text) {
+            Some(register) => Ok(Operand::Register(register)),
+            None => Ok(Operand::Immediate(parse_number(text)?)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UndefinedLabel(pub String);
+
+impl fmt::Display for UndefinedLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "undefined label \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for UndefinedLabel {}
+
+/// A sequence of instructions with its labels resolved
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    labels: HashMap<String, usize>,
+}
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Result<Self, UndefinedLabel> {
+        let labels: HashMap<String, usize> = instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instruction)| match instruction {
+                Instruction::Label(label) => Some((label.clone(), i)),
+                _ => None,
+            })
+            .collect();
+
+        for instruction in &instructions {
+            if let Instruction::Branch { label, .. } = instruction {
+                if !labels.contains_key(label) {
+                    return Err(UndefinedLabel(label.clone()));
+                }
+            }
+        }
+
+        Ok(Program {
+            instructions,
+            labels,
+        })
+    }
+
+    pub fn get(&self, pc: usize) -> Option<&Instruction> {
+        self.instructions.get(pc)
+    }
+
+    pub fn is_empty(&self) -> bool { self.instructions.is_empty() }
+
+    pub fn label_target(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseInstructionError(pub String);
+
+impl fmt::Display for ParseInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseInstructionError {}
+
+fn expect_register(text: &str) -> Result<Register, ParseInstructionError> {
+    parse_register(text).ok_or_else(|| {
+        ParseInstructionError(format!(
+            "expected a register between r0 and r{}, got \"{text}\"",
+            NUM_REGISTERS - 1
+        ))
+    })
+}
+
+fn expect_operand(text: &str) -> Result<Operand, ParseInstructionError> {
+    text.parse().map_err(|err| {
+        ParseInstructionError(format!(
+            "expected a register or a value, got \"{text}\" ({err})"
+        ))
+    })
+}
+
+// Either `offset` or `offset(rN)`
+fn expect_address(text: &str) -> Result<Address, ParseInstructionError> {
+    let (offset, index) = match text.split_once('(') {
+        Some((offset, index)) => match index.strip_suffix(')') {
+            Some(index) => (offset, Some(expect_register(index)?)),
+            None => {
+                return Err(ParseInstructionError(format!(
+                    "missing \")\" in address \"{text}\""
+                )))
+            }
+        },
+        None => (text, None),
+    };
+    let offset = parse_number(offset).map_err(|err| {
+        ParseInstructionError(format!(
+            "expected an address, got \"{offset}\" ({err})"
+        ))
+    })?;
+    Ok(Address { offset, index })
+}
+
+fn is_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+impl FromStr for Instruction {
+    type Err = ParseInstructionError;
+
+    // Parses the same syntax `Display` produces
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        if let Some(label) = text.strip_suffix(':') {
+            return if is_label(label) {
+                Ok(Instruction::Label(label.to_owned()))
+            } else {
+                Err(ParseInstructionError(format!("invalid label \"{label}\"")))
+            };
         }
+
+        let (mnemonic, operands) =
+            text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mnemonic = mnemonic.to_lowercase();
+        let operands: Vec<&str> = match operands.trim() {
+            "" => Vec::new(),
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+
+        let expected_operands = match mnemonic.as_str() {
+            "calc" | "fence" => 0,
+            "b" | "beq" | "bne" | "blt" | "bge" => 1,
+            "read" | "write" | "tas" | "ll" | "mov" | "cmp" => 2,
+            "faa" | "sc" => 3,
+            "cas" => 4,
+            mnemonic
+                if AluOp::ALL
+                    .iter()
+                    .any(|op| op.get_mnemonic() == mnemonic) =>
+            {
+                3
+            }
+            mnemonic => {
+                return Err(ParseInstructionError(format!(
+                    "unknown instruction \"{mnemonic}\""
+                )))
+            }
+        };
+        if operands.len() != expected_operands {
+            return Err(ParseInstructionError(format!(
+                "\"{mnemonic}\" takes {expected_operands} operands, got {}",
+                operands.len()
+            )));
+        }
+
+        let instruction = match mnemonic.as_str() {
+            "calc" => Instruction::Calc,
+            "fence" => Instruction::Fence,
+            "read" => Instruction::Read {
+                rd: expect_register(operands[0])?,
+                address: expect_address(operands[1])?,
+            },
+            "write" => Instruction::Write {
+                address: expect_address(operands[0])?,
+                src: expect_operand(operands[1])?,
+            },
+            "tas" => Instruction::TestAndSet {
+                rd: expect_register(operands[0])?,
+                address: expect_address(operands[1])?,
+            },
+            "faa" => Instruction::FetchAndAdd {
+                rd: expect_register(operands[0])?,
+                address: expect_address(operands[1])?,
+                value: expect_operand(operands[2])?,
+            },
+            "cas" => Instruction::CompareAndSwap {
+                rd: expect_register(operands[0])?,
+                address: expect_address(operands[1])?,
+                expected: expect_operand(operands[2])?,
+                new: expect_operand(operands[3])?,
+            },
+            "ll" => Instruction::LoadLinked {
+                rd: expect_register(operands[0])?,
+                address: expect_address(operands[1])?,
+            },
+            "sc" => Instruction::StoreConditional {
+                rd: expect_register(operands[0])?,
+                address: expect_address(operands[1])?,
+                src: expect_operand(operands[2])?,
+            },
+            "mov" => Instruction::Mov {
+                rd: expect_register(operands[0])?,
+                operand: expect_operand(operands[1])?,
+            },
+            "cmp" => Instruction::Cmp {
+                rs: expect_register(operands[0])?,
+                operand: expect_operand(operands[1])?,
+            },
+            mnemonic => {
+                if let Some(condition) = Condition::ALL
+                    .iter()
+                    .find(|condition| condition.get_mnemonic() == mnemonic)
+                {
+                    if !is_label(operands[0]) {
+                        return Err(ParseInstructionError(format!(
+                            "invalid label \"{}\"",
+                            operands[0]
+                        )));
+                    }
+                    Instruction::Branch {
+                        condition: *condition,
+                        label: operands[0].to_owned(),
+                    }
+                } else {
+                    let op = AluOp::ALL
+                        .iter()
+                        .find(|op| op.get_mnemonic() == mnemonic)
+                        .unwrap();
+                    Instruction::Alu {
+                        op: *op,
+                        rd: expect_register(operands[0])?,
+                        rs: expect_register(operands[1])?,
+                        operand: expect_operand(operands[2])?,
+                    }
+                }
+            }
+        };
+
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_wrap_around_the_address_space() {
+        let mut registers = [0; NUM_REGISTERS];
+        registers[1] = Data::MAX;
+        let indexed = Address {
+            offset: usize::MAX,
+            index: Some(1),
+        };
+        // 15 + 15, wrapped
+        assert_eq!(indexed.resolve(&registers, 16), 14);
+        assert_eq!(Address::from(21).resolve(&registers, 16), 5);
     }
 }
@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::events::{Event, EventHub};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Topology {
+    /// A single medium every node hears, one message at a time
+    Bus,
+    /// Every node linked to its two neighbors, messages take the shortest way
+    /// around
+    Ring,
+    /// Nodes on a grid linked to the ones next to them, routed X first then Y
+    Mesh,
+    /// A switch linking every pair of nodes in a single hop, messages only
+    /// conflict when they go to the same node
+    Crossbar,
+}
+
+impl Topology {
+    pub const ALL: [Topology; 4] = [
+        Topology::Bus,
+        Topology::Ring,
+        Topology::Mesh,
+        Topology::Crossbar,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            Topology::Bus => "bus",
+            Topology::Ring => "ring",
+            Topology::Mesh => "mesh",
+            Topology::Crossbar => "crossbar",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Topology> {
+        Topology::ALL
+            .into_iter()
+            .find(|topology| topology.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoherenceScheme {
+    /// Every transaction reaches every other cache
+    Snooping,
+    /// The home node keeps track of the caches that may hold every block and
+    /// only forwards transactions to them
+    Directory,
+}
+
+impl CoherenceScheme {
+    pub const ALL: [CoherenceScheme; 2] =
+        [CoherenceScheme::Snooping, CoherenceScheme::Directory];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            CoherenceScheme::Snooping => "snooping",
+            CoherenceScheme::Directory => "directory",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CoherenceScheme> {
+        CoherenceScheme::ALL
+            .into_iter()
+            .find(|scheme| scheme.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Nodes a link goes from and to
+pub type Link = (usize, usize);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterconnectStats {
+    pub messages: usize,
+    /// Links crossed by all of the messages
+    pub hops: usize,
+    /// Time messages spent waiting for a busy link
+    pub contention: Duration,
+}
+
+/// A message crossing the interconnect, with the time every hop starts and
+/// ends
+#[derive(Debug, Clone)]
+pub struct NetworkMessage {
+    pub from: usize,
+    pub to: usize,
+    pub label: &'static str,
+    pub hops: Vec<(Link, Instant, Instant)>,
+}
+
+impl NetworkMessage {
+    pub fn arrival(&self) -> Option<Instant> {
+        self.hops.last().map(|&(_, _, end)| end)
+    }
+}
+
+/// Grid width of a mesh, as square as it gets
+pub fn mesh_width(num_nodes: usize) -> usize {
+    let mut width = 1;
+    while width * width < num_nodes {
+        width += 1;
+    }
+    width
+}
+
+/// Links between the processors and the home node, which holds main memory
+/// and orders the transactions. Links are reserved ahead of time, a message
+/// waits for the ones before it to be done with a link.
+pub struct Interconnect {
+    topology: Topology,
+    num_nodes: usize,
+    hop_delay: Duration,
+    busy_until: BTreeMap<Link, Instant>,
+    stats: InterconnectStats,
+    gui_tx: Option<EventHub>,
+}
+
+impl Interconnect {
+    pub fn new(
+        topology: Topology,
+        num_processors: usize,
+        hop_delay: Duration,
+    ) -> Self {
+        Interconnect {
+            topology,
+            num_nodes: num_processors + 1,
+            hop_delay,
+            busy_until: BTreeMap::new(),
+            stats: InterconnectStats::default(),
+            gui_tx: None,
+        }
+    }
+
+    pub fn register_gui_listener(&mut self, gui_tx: EventHub) {
+        self.gui_tx = Some(gui_tx);
+    }
+
+    pub fn topology(&self) -> Topology { self.topology }
+
+    /// The node after the processors
+    pub fn home(&self) -> usize { self.num_nodes - 1 }
+
+    /// Nodes a message goes through, both ends included
+    pub fn route(&self, from: usize, to: usize) -> Vec<usize> {
+        match self.topology {
+            Topology::Bus | Topology::Crossbar => vec![from, to],
+            Topology::Ring => {
+                let n = self.num_nodes;
+                let forward = (to + n - from) % n;
+                let step = if forward <= n / 2 { 1 } else { n - 1 };
+                let mut route = vec![from];
+                while *route.last().unwrap() != to {
+                    route.push((route.last().unwrap() + step) % n);
+                }
+                route
+            }
+            Topology::Mesh => {
+                let width = mesh_width(self.num_nodes);
+                let (to_x, to_y) = (to % width, to / width);
+                let mut route = vec![from];
+                let (mut x, mut y) = (from % width, from / width);
+                while x != to_x {
+                    x = if x < to_x { x + 1 } else { x - 1 };
+                    route.push(y * width + x);
+                }
+                while y != to_y {
+                    y = if y < to_y { y + 1 } else { y - 1 };
+                    route.push(y * width + x);
+                }
+                route
+            }
+        }
+    }
+
+    // What messages on `link` compete for. The bus is a single medium and a
+    // crossbar only conflicts on the port of the receiver.
+    fn contended(&self, link: Link) -> Link {
+        match self.topology {
+            Topology::Bus => (self.home(), self.home()),
+            Topology::Crossbar => (link.1, link.1),
+            Topology::Ring | Topology::Mesh => link,
+        }
+    }
+
+    /// Sends a message that's ready at `departure`, returns when it arrives
+    pub fn send(
+        &mut self,
+        from: usize,
+        to: usize,
+        label: &'static str,
+        departure: Instant,
+    ) -> Instant {
+        if from == to {
+            return departure;
+        }
+
+        let mut time = departure;
+        let mut hops = Vec::new();
+        for nodes in self.route(from, to).windows(2) {
+            let link = (nodes[0], nodes[1]);
+            let contended = self.contended(link);
+            let start = self
+                .busy_until
+                .get(&contended)
+                .map_or(time, |&busy_until| busy_until.max(time));
+            let end = start + self.hop_delay;
+            self.busy_until.insert(contended, end);
+            self.stats.contention += start - time;
+            self.stats.hops += 1;
+            hops.push((link, start, end));
+            time = end;
+        }
+        self.stats.messages += 1;
+
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::NetworkMessage(NetworkMessage {
+                from,
+                to,
+                label,
+                hops,
+            }));
+        }
+        time
+    }
+
+    pub fn send_stats(&self) {
+        if let Some(ref sender) = self.gui_tx {
+            sender.send(Event::InterconnectStats(self.stats));
+        }
+    }
+}
@@ -1,12 +1,15 @@
-use std::{mem::size_of, slice::SliceIndex, sync::mpsc::Sender};
+use std::{mem::size_of, slice::SliceIndex};
 
-use crate::{app::Event, models::Data};
+use crate::{
+    events::{Event, EventHub},
+    models::Data,
+};
 
 #[allow(dead_code)]
 pub struct Memory {
     blocks: usize,
     storage: Vec<Data>,
-    gui_tx: Option<Sender<Event>>,
+    gui_tx: Option<EventHub>,
     offset_bits: usize,
 }
 
@@ -27,10 +30,12 @@ impl Memory {
         }
     }
 
-    pub fn register_gui_listener(&mut self, gui_tx: Sender<Event>) {
+    pub fn register_gui_listener(&mut self, gui_tx: EventHub) {
         self.gui_tx = Some(gui_tx);
     }
 
+    pub fn address_space(&self) -> usize { self.blocks << self.offset_bits }
+
     pub fn get_line(&self, address: usize) -> usize {
         address >> self.offset_bits
     }
@@ -52,12 +57,10 @@ impl Memory {
 
     pub fn store_line(&mut self, block_index: usize, data: Data) {
         if let Some(ref sender) = self.gui_tx {
-            sender
-                .send(Event::MemWrite {
-                    block_i: block_index,
-                    data,
-                })
-                .ok();
+            sender.send(Event::MemWrite {
+                block_i: block_index,
+                data,
+            });
         }
         self.storage[block_index] = data;
     }
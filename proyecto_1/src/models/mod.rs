@@ -1,23 +1,25 @@
+pub mod arbiter;
+pub mod assembler;
 pub mod bus;
 pub mod cache;
+pub mod consistency;
+pub mod directory;
+pub mod dram;
+pub mod energy;
+pub mod engine;
+pub mod error;
 pub mod instructions;
+pub mod interconnect;
 pub mod main_memory;
+pub mod numa;
+pub mod prefetcher;
 pub mod processor;
 pub mod system;
 
 pub type Data = u16;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum MemOp {
     Write,
     Read,
 }
-
-fn box_err<'a, E: std::error::Error + 'a>(
-    res: Result<(), E>,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    match res {
-        Ok(()) => Ok(()),
-        Err(err) => Err(Box::new(err)),
-    }
-}
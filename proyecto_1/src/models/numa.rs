@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+/// Main memory split in nodes, each one local to a group of consecutive
+/// processors. Blocks are spread over the nodes in chunks of `interleave`
+/// blocks.
+#[derive(Debug, Clone, Copy)]
+pub struct NumaConfig {
+    pub nodes: usize,
+    pub interleave: usize,
+    /// Extra time an access to the node of another group takes, both ways
+    pub remote_delay: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeStats {
+    /// Accesses from the processors local to the node
+    pub local: usize,
+    pub remote: usize,
+}
+
+impl NumaConfig {
+    pub fn node_of_block(&self, block: usize) -> usize {
+        (block / self.interleave) % self.nodes
+    }
+
+    /// Index of a block among the ones of its node
+    pub fn node_block(&self, block: usize) -> usize {
+        let stride = self.interleave * self.nodes;
+        block / stride * self.interleave + block % self.interleave
+    }
+
+    pub fn node_of_processor(
+        &self,
+        processor: usize,
+        num_processors: usize,
+    ) -> usize {
+        processor * self.nodes / num_processors
+    }
+
+    pub fn is_remote(
+        &self,
+        processor: usize,
+        num_processors: usize,
+        block: usize,
+    ) -> bool {
+        self.node_of_processor(processor, num_processors)
+            != self.node_of_block(block)
+    }
+
+    /// Blocks a node holds, in address order
+    pub fn blocks_of_node(&self, node: usize, blocks: usize) -> Vec<usize> {
+        (0..blocks)
+            .filter(|&block| self.node_of_block(block) == node)
+            .collect()
+    }
+
+    /// Processors local to a node
+    pub fn processors_of_node(
+        &self,
+        node: usize,
+        num_processors: usize,
+    ) -> Vec<usize> {
+        (0..num_processors)
+            .filter(|&i| self.node_of_processor(i, num_processors) == node)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numa(nodes: usize, interleave: usize) -> NumaConfig {
+        NumaConfig {
+            nodes,
+            interleave,
+            remote_delay: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn blocks_interleave_over_the_nodes() {
+        let config = numa(2, 2);
+        let nodes: Vec<usize> =
+            (0..8).map(|block| config.node_of_block(block)).collect();
+        assert_eq!(nodes, [0, 0, 1, 1, 0, 0, 1, 1]);
+        assert_eq!(config.blocks_of_node(1, 8), [2, 3, 6, 7]);
+        assert_eq!(config.processors_of_node(0, 4), [0, 1]);
+        assert!(!config.is_remote(1, 4, 4));
+        assert!(config.is_remote(1, 4, 6));
+
+        // Every node packs its blocks one after the other
+        let config = numa(3, 2);
+        for node in 0..3 {
+            let blocks: Vec<usize> = config
+                .blocks_of_node(node, 24)
+                .into_iter()
+                .map(|block| config.node_block(block))
+                .collect();
+            assert_eq!(blocks, (0..8).collect::<Vec<usize>>());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::VecDeque, mem::size_of};
+
+use crate::models::Data;
+
+/// Predicted blocks waiting to be fetched, the newest predictions are dropped
+/// when it's full
+pub const PREFETCH_QUEUE_SIZE: usize = 4;
+// Streams the stride detector keeps track of
+const STREAMS: usize = 4;
+// Blocks an access can be away from the last one of a stream and still belong
+// to it
+const STREAM_WINDOW: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrefetchPolicy {
+    Off,
+    /// The blocks after every accessed one
+    NextLine,
+    /// The blocks further along a stream once two accesses in a row moved the
+    /// same distance through it
+    Stride,
+}
+
+impl PrefetchPolicy {
+    pub const ALL: [PrefetchPolicy; 3] = [
+        PrefetchPolicy::Off,
+        PrefetchPolicy::NextLine,
+        PrefetchPolicy::Stride,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            PrefetchPolicy::Off => "off",
+            PrefetchPolicy::NextLine => "next-line",
+            PrefetchPolicy::Stride => "stride",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PrefetchPolicy> {
+        PrefetchPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrefetchStats {
+    /// Prefetches that went on the bus
+    pub issued: usize,
+    /// Prefetched lines a demand access used
+    pub useful: usize,
+    /// Demand accesses that came while the prefetch of their block was still
+    /// queued, they fetched it themselves
+    pub late: usize,
+    /// Prefetched lines evicted or invalidated before any use
+    pub useless: usize,
+}
+
+struct Stream {
+    last_block: usize,
+    stride: isize,
+}
+
+/// Predicts the blocks a CPU is going to access from the ones it accessed.
+/// There's no PC to tell the streams apart, an access belongs to the stream
+/// whose last block is closest to it.
+pub struct Prefetcher {
+    policy: PrefetchPolicy,
+    // Blocks predicted ahead of every access
+    degree: usize,
+    blocks: usize,
+    offset_bits: u32,
+    // Least recently used first
+    streams: VecDeque<Stream>,
+    queue: VecDeque<usize>,
+}
+
+impl Prefetcher {
+    pub fn new(
+        policy: PrefetchPolicy,
+        degree: usize,
+        address_space: usize,
+    ) -> Self {
+        let offset_bits = size_of::<Data>().trailing_zeros();
+        Prefetcher {
+            policy,
+            degree,
+            blocks: address_space >> offset_bits,
+            offset_bits,
+            streams: VecDeque::with_capacity(STREAMS),
+            queue: VecDeque::with_capacity(PREFETCH_QUEUE_SIZE),
+        }
+    }
+
+    /// Learns from a demand access and queues what it predicts. Returns
+    /// whether the block was still queued, its prefetch comes too late.
+    pub fn access(&mut self, address: usize) -> bool {
+        let block = address >> self.offset_bits;
+        let queued = self.queue.iter().position(|&queued| queued == block);
+        if let Some(position) = queued {
+            self.queue.remove(position);
+        }
+
+        let predicted = match self.policy {
+            PrefetchPolicy::Off => Vec::new(),
+            PrefetchPolicy::NextLine => {
+                (1..=self.degree).map(|i| (block + i) as isize).collect()
+            }
+            PrefetchPolicy::Stride => self.train_stride(block),
+        };
+        for block in predicted {
+            if !(0..self.blocks as isize).contains(&block) {
+                continue;
+            }
+            let block = block as usize;
+            if self.queue.len() < PREFETCH_QUEUE_SIZE
+                && !self.queue.contains(&block)
+            {
+                self.queue.push_back(block);
+            }
+        }
+        queued.is_some()
+    }
+
+    fn train_stride(&mut self, block: usize) -> Vec<isize> {
+        let closest = self
+            .streams
+            .iter()
+            .enumerate()
+            .map(|(i, stream)| (i, stream.last_block.abs_diff(block)))
+            .filter(|&(_, distance)| distance <= STREAM_WINDOW)
+            .min_by_key(|&(_, distance)| distance)
+            .map(|(i, _)| i);
+
+        let Some(mut stream) = closest.and_then(|i| self.streams.remove(i))
+        else {
+            if self.streams.len() == STREAMS {
+                self.streams.pop_front();
+            }
+            self.streams.push_back(Stream {
+                last_block: block,
+                stride: 0,
+            });
+            return Vec::new();
+        };
+
+        let stride = block as isize - stream.last_block as isize;
+        let confirmed = stride != 0 && stride == stream.stride;
+        if stride != 0 {
+            stream.stride = stride;
+            stream.last_block = block;
+        }
+        self.streams.push_back(stream);
+
+        match confirmed {
+            true => (1..=self.degree as isize)
+                .map(|i| block as isize + stride * i)
+                .collect(),
+            false => Vec::new(),
+        }
+    }
+
+    /// Address of the next block to prefetch
+    pub fn next_prefetch(&mut self) -> Option<usize> {
+        self.queue
+            .pop_front()
+            .map(|block| block << self.offset_bits)
+    }
+}
@@ -1,223 +1,1048 @@
 use std::{
-    error::Error,
     sync::{
-        mpsc::{sync_channel, Receiver, RecvError, Sender, SyncSender},
+        mpsc::{sync_channel, Receiver, RecvError, SyncSender},
         Arc, Mutex,
     },
-    thread,
+    thread::JoinHandle,
 };
 
 use crate::{
-    app::Event,
+    events::{Event, EventHub},
     models::{
-        box_err,
-        bus::{BusAction, BusSignal},
-        cache::{Cache, CacheLine, CacheState},
-        instructions::Instruction,
+        bus::{BusAction, BusMessage, BusPort, BusSignal},
+        cache::{Cache, CacheLine, CacheState, CoherenceProtocol, WritePolicy},
+        consistency::{ConsistencyModel, StoreBuffer},
+        error::{spawn_component, Component, ErrorContext, Shutdown, SimError},
+        instructions::{
+            Address, Flags, Instruction, Program, Register, NUM_REGISTERS,
+        },
+        prefetcher::Prefetcher,
+        system::SocProperties,
         Data, MemOp,
     },
+    random::UniformRng,
 };
 
+pub enum CpuCommand {
+    /// Executes the instruction right away, outside of the loaded program
+    Execute(Instruction),
+    LoadProgram(Program),
+    /// Executes the instruction at the program counter
+    Step,
+    /// Drains the store buffer and switches to the new model
+    SetConsistencyModel(ConsistencyModel),
+}
+
 pub struct Processor {
     controller_signal_input: SyncSender<BusSignal>,
-    cpu_instruction_input: SyncSender<Instruction>,
+    cpu_command_input: SyncSender<CpuCommand>,
+    cpu_grant_input: SyncSender<()>,
     cpu_data_input: SyncSender<(CacheState, Data)>,
+    // Of the CPU and the controller
+    threads: Vec<JoinHandle<()>>,
+}
+
+// Architectural state only the CPU thread touches
+struct CpuState {
+    registers: [Data; NUM_REGISTERS],
+    flags: Flags,
+    pc: usize,
+    program: Program,
+    // Addresses wrap around at the end of main memory
+    address_space: usize,
+    model: ConsistencyModel,
+    store_buffer: StoreBuffer,
+    // Decides when and, under PSO, in which order buffered writes drain
+    drain_rng: UniformRng,
+    prefetcher: Prefetcher,
+}
+
+impl CpuState {
+    fn new(
+        processor_i: usize,
+        address_space: usize,
+        props: &SocProperties,
+    ) -> Self {
+        CpuState {
+            registers: [0; NUM_REGISTERS],
+            flags: Flags::default(),
+            pc: 0,
+            program: Program::default(),
+            address_space,
+            model: props.consistency_model,
+            store_buffer: StoreBuffer::default(),
+            drain_rng: UniformRng::from_seed(
+                props.seed.wrapping_add(processor_i as u32),
+            ),
+            prefetcher: Prefetcher::new(
+                props.prefetch,
+                props.prefetch_degree,
+                address_space,
+            ),
+        }
+    }
+
+    fn resolve(&self, address: &Address) -> usize {
+        address.resolve(&self.registers, self.address_space)
+    }
+
+    fn write_register(
+        &mut self,
+        register: Register,
+        data: Data,
+        gui_tx: &EventHub,
+        processor_i: usize,
+    ) -> Result<(), SimError> {
+        self.registers[register] = data;
+        gui_tx.send(Event::RegisterWrite {
+            processor_i,
+            register,
+            data,
+        });
+        Ok(())
+    }
+
+    fn set_pc(
+        &mut self,
+        pc: usize,
+        gui_tx: &EventHub,
+        processor_i: usize,
+    ) -> Result<(), SimError> {
+        self.pc = pc;
+        gui_tx.send(Event::ProgramCounter { processor_i, pc });
+        Ok(())
+    }
+
+    fn send_store_buffer(
+        &self,
+        gui_tx: &EventHub,
+        processor_i: usize,
+    ) -> Result<(), SimError> {
+        gui_tx.send(Event::StoreBuffer {
+            processor_i,
+            entries: self.store_buffer.entries(),
+        });
+        Ok(())
+    }
+}
+
+// What a line in `state` does when it sees `action` on the bus: whether it
+// supplies its data and the state it goes to. Lines waiting for their data
+// never see a snoop, the bus holds back every transaction on their block
+// until theirs completes, so there's no transition for them.
+fn snoop_transition(
+    state: CacheState,
+    action: BusAction,
+    protocol: CoherenceProtocol,
+) -> Option<(bool, CacheState)> {
+    let action = match (action, protocol) {
+        // There's a single copy under VI, reads take it away
+        (BusAction::ReadMiss, CoherenceProtocol::VI) => {
+            BusAction::ReadExclusive
+        }
+        // The block is overwritten in memory, the copies only go away
+        (BusAction::WriteThrough(_), _) => BusAction::Invalidate,
+        _ => action,
+    };
+    let transition = match (action, state) {
+        // Only the writer's own controller sees its write backs
+        (BusAction::WriteMem, CacheState::MI_A | CacheState::OI_A) => {
+            (true, CacheState::Invalid)
+        }
+        (BusAction::WriteMem, _) => (false, CacheState::Invalid),
+        (
+            _,
+            CacheState::IS_D
+            | CacheState::IM_D
+            | CacheState::SM_D
+            | CacheState::OM_D,
+        ) => return None,
+        // No data yet, the own request will see the outcome of this one
+        (
+            _,
+            CacheState::Invalid
+            | CacheState::IS_AD
+            | CacheState::IM_AD
+            | CacheState::II_A,
+        ) => (false, state),
+
+        // Without an Owned state memory takes the data along with the reader,
+        // a write back waiting for the bus has nothing left to write
+        (BusAction::ReadMiss, CacheState::Exclusive | CacheState::Modified) => {
+            match protocol.has_owned() {
+                true => (true, CacheState::Owned),
+                false => (true, CacheState::Shared),
+            }
+        }
+        (BusAction::ReadMiss, CacheState::MI_A) => match protocol.has_owned() {
+            true => (true, CacheState::OI_A),
+            false => (true, CacheState::II_A),
+        },
+        (BusAction::ReadMiss, _) => (true, state),
+
+        // The writer overwrites the whole block after an invalidate, there's
+        // no need to supply it
+        (
+            BusAction::ReadExclusive | BusAction::Invalidate,
+            CacheState::SM_AD | CacheState::OM_AD,
+        ) => (action == BusAction::ReadExclusive, CacheState::IM_AD),
+        (
+            BusAction::ReadExclusive | BusAction::Invalidate,
+            CacheState::MI_A | CacheState::OI_A,
+        ) => (action == BusAction::ReadExclusive, CacheState::II_A),
+        (BusAction::ReadExclusive | BusAction::Invalidate, _) => {
+            (action == BusAction::ReadExclusive, CacheState::Invalid)
+        }
+        (BusAction::WriteThrough(_), _) => unreachable!(),
+    };
+    Some(transition)
+}
+
+/// Applies a snooped transaction to the line holding its block, wherever it
+/// is in the cache. Returns the data the line supplies, if any.
+pub fn snoop(
+    cache: &mut Cache,
+    signal: &BusSignal,
+    protocol: CoherenceProtocol,
+) -> Result<Option<Data>, SimError> {
+    let state = cache.get_state(signal.address);
+    let Some((supplies, next_state)) =
+        snoop_transition(state, signal.action, protocol)
+    else {
+        return Err(SimError::Protocol {
+            context: ErrorContext::default(),
+            state,
+        });
+    };
+    let data = cache
+        .lookup(signal.address)
+        .filter(|_| supplies)
+        .map(|line| line.data);
+    if data.is_some() {
+        cache.data_read();
+    }
+    cache.send_snoop_response(signal, state, data);
+
+    if next_state != state {
+        cache.change_state_address(signal.address, next_state);
+        // A reservation doesn't survive losing the copy, even if the line
+        // stays around waiting for the data
+        if matches!(next_state, CacheState::IM_AD | CacheState::II_A) {
+            cache.clear_reservation_for(signal.address);
+        }
+    }
+    Ok(data)
 }
 
 fn controller_handle_signal(
     signal: BusSignal,
+    protocol: CoherenceProtocol,
     cache_lock: &Arc<Mutex<Cache>>,
     bus_tx: &SyncSender<Option<Data>>,
-) -> Result<(), Box<dyn Error>> {
+    write_back_tx: &SyncSender<()>,
+) -> Result<(), SimError> {
+    // update the line's state before letting go of the lock, otherwise a
+    // local write could slip in between
     let mut cache = cache_lock.lock().unwrap();
-    match signal.action {
-        BusAction::Invalidate => {
-            cache.invalidate_address(signal.address);
+    let data = snoop(&mut cache, &signal, protocol)?;
+    drop(cache);
 
-            Ok(())
+    bus_tx.send(data)?;
+    // Only the writer's own controller sees its write backs, the line left
+    // the write-back buffer. A notice the CPU didn't pick up yet is as good
+    // as a new one.
+    if signal.action == BusAction::WriteMem {
+        write_back_tx.try_send(()).ok();
+    }
+    Ok(())
+}
+
+// Waits until the write-back buffer doesn't hold `address` anymore. A request
+// doesn't snoop its own cache, it would get memory's stale copy of a block
+// still in the buffer.
+fn cpu_wait_write_back(
+    address: usize,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+) -> Result<(), SimError> {
+    loop {
+        let cache = cache_lock.lock().unwrap();
+        if !cache.write_back_pending(address) {
+            return Ok(());
         }
-        BusAction::ReadMiss => {
-            match cache.get_address(signal.address) {
-                Some(cache_line) => {
-                    let data = cache_line.data;
-                    let state = cache_line.state;
-                    Mutex::unlock(cache);
-                    box_err(bus_tx.send(Some(data)))?;
-
-                    // update the line's state
-                    match state {
-                        CacheState::Invalid => unreachable!(),
-                        CacheState::Shared | CacheState::Owned => (),
-                        CacheState::Exclusive | CacheState::Modified => {
-                            let mut cache = cache_lock.lock().unwrap();
-                            cache.change_state_address(
-                                signal.address,
-                                CacheState::Owned,
-                            )
-                        }
-                    }
-                    Ok(())
-                }
-                None => {
-                    box_err(bus_tx.send(None))?;
-                    Ok(())
-                }
+        drop(cache);
+        bus.wait_write_back()?;
+    }
+}
+
+// Makes room for `address` and reserves its way in `state`. A dirty line in
+// the way moves to the write-back buffer and its write back is posted, the
+// miss goes ahead without waiting for it since the buffer answers for the
+// block meanwhile. Only a full buffer stalls the CPU. Clean lines are dropped
+// silently.
+fn cpu_allocate(
+    address: usize,
+    state: CacheState,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    processor_i: usize,
+) -> Result<(), SimError> {
+    cpu_wait_write_back(address, cache_lock, bus)?;
+    loop {
+        let mut cache = cache_lock.lock().unwrap();
+        let dirty = cache
+            .get_victim(address)
+            .filter(|(_, victim)| {
+                matches!(victim.state, CacheState::Modified | CacheState::Owned)
+            })
+            .map(|(victim_address, _)| victim_address);
+        match dirty {
+            Some(_) if cache.write_back_full() => {
+                drop(cache);
+                bus.wait_write_back()?;
+            }
+            Some(victim_address) => {
+                cache.push_write_back(victim_address);
+                cache.store_line(address, state, 0);
+                drop(cache);
+                return bus.post(BusSignal {
+                    origin: processor_i,
+                    address: victim_address,
+                    action: BusAction::WriteMem,
+                    prefetch: false,
+                });
+            }
+            None => {
+                cache.store_line(address, state, 0);
+                return Ok(());
             }
         }
-        BusAction::WriteMem(_) => Ok(()),
     }
 }
 
-fn maybe_write_back(
+// Puts the request on the bus, and once it's granted moves the line to the
+// state it waits for its data in
+fn cpu_request(
+    signal: BusSignal,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+) -> Result<(), SimError> {
+    bus.request(signal)?;
+
+    // No other transaction on the block goes ahead until this one completes,
+    // so nobody snoops the line between this point and its install
+    let mut cache = cache_lock.lock().unwrap();
+    let state = cache.get_state(signal.address);
+    cache.change_state_address(signal.address, state.ordered());
+    Ok(())
+}
+
+// Runs the transaction that gets a line not held in Modified or Exclusive
+// state up to receiving its data. Lines already present are upgraded and can
+// be snooped while the request waits for the bus, misses are allocated. The
+// caller installs the line and completes the transaction.
+fn cpu_request_modified(
     address: usize,
-    replaced_line: CacheLine,
-    bus_tx: &SyncSender<BusSignal>,
+    action: BusAction,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
     processor_i: usize,
-) -> Result<(), Box<dyn Error>> {
-    match replaced_line.state {
-        CacheState::Invalid | CacheState::Shared | CacheState::Exclusive => (),
-        // Write back
-        // No need to invalidate because for Modified everything else should be invalid and for Owned the others can keep their copies
-        CacheState::Modified | CacheState::Owned => {
-            box_err(bus_tx.send(BusSignal {
-                origin: processor_i,
+) -> Result<(BusSignal, Data), SimError> {
+    let mut cache = cache_lock.lock().unwrap();
+    match cache.get_address(address).map(|line| line.state) {
+        Some(state) => {
+            let upgrade_state = match state {
+                CacheState::Owned => CacheState::OM_AD,
+                _ => CacheState::SM_AD,
+            };
+            cache.change_state_address(address, upgrade_state);
+            drop(cache);
+        }
+        // report write miss
+        None => {
+            gui_tx.send(Event::Alert {
                 address,
-                action: BusAction::WriteMem(replaced_line.data),
-            }))?;
+                processor_i,
+                op: MemOp::Write,
+            });
+            drop(cache);
+            cpu_allocate(
+                address,
+                CacheState::IM_AD,
+                cache_lock,
+                bus,
+                processor_i,
+            )?;
+        }
+    }
+
+    let signal = BusSignal {
+        origin: processor_i,
+        address,
+        action,
+        prefetch: false,
+    };
+    cpu_request(signal, cache_lock, bus)?;
+    let (_, data) = bus.recv_data(signal)?;
+    // An Owned line nobody took away while it waited has the newest data.
+    // Nobody else supplies it then, and memory may be stale.
+    let cache = cache_lock.lock().unwrap();
+    let data = match cache.lookup(address) {
+        Some(line) if line.state == CacheState::OM_D => line.data,
+        _ => data,
+    };
+    drop(cache);
+    Ok((signal, data))
+}
+
+// Gets the line in Modified state and applies `op` to its data within a single
+// critical section, so no snoop can interleave between the read and the
+// write. `op` returns `None` to leave the data untouched. Returns the data the
+// line held before the update and whether it was updated.
+fn cpu_atomic_rmw(
+    address: usize,
+    op: impl FnOnce(&mut Cache, Data) -> Option<Data>,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<(Data, bool), SimError> {
+    let mut cache = cache_lock.lock().unwrap();
+    cache.swap_victim(address);
+    cache.demand_access(address);
+    // Already the only copy, no bus transaction needed
+    if let Some(&CacheLine {
+        state: CacheState::Modified | CacheState::Exclusive,
+        data,
+        ..
+    }) = cache.get_address(address)
+    {
+        let new_data = op(&mut cache, data);
+        if let Some(new_data) = new_data {
+            cache.store_line(address, CacheState::Modified, new_data);
         }
+        return Ok((data, new_data.is_some()));
     }
+    drop(cache);
+
+    let (signal, data) = cpu_request_modified(
+        address,
+        BusAction::ReadExclusive,
+        cache_lock,
+        bus,
+        gui_tx,
+        processor_i,
+    )?;
+
+    let mut cache = cache_lock.lock().unwrap();
+    // The line must be installed even if `op` declines to update it, the
+    // other copies are gone already
+    let new_data = op(&mut cache, data);
+    cache.store_line(address, CacheState::Modified, new_data.unwrap_or(data));
+    drop(cache);
+    bus.complete(signal)?;
+    Ok((data, new_data.is_some()))
+}
+
+// Returns the data at `address`, fetching it through the bus on a miss. When
+// `reserve` is set the address is also reserved for a later store-conditional,
+// in the same critical section as the read.
+fn cpu_read(
+    address: usize,
+    reserve: bool,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<Data, SimError> {
+    let mut cache = cache_lock.lock().unwrap();
+    cache.swap_victim(address);
+    cache.demand_access(address);
+    if let Some(&CacheLine { data, .. }) = cache.get_address(address) {
+        if reserve {
+            cache.set_reservation(Some(address));
+        }
+        return Ok(data);
+    }
+
+    gui_tx.send(Event::Alert {
+        address,
+        processor_i,
+        op: MemOp::Read,
+    });
+    drop(cache);
+    cpu_allocate(address, CacheState::IS_AD, cache_lock, bus, processor_i)?;
 
+    let signal = BusSignal {
+        origin: processor_i,
+        address,
+        action: BusAction::ReadMiss,
+        prefetch: false,
+    };
+    cpu_request(signal, cache_lock, bus)?;
+    let (state, data) = bus.recv_data(signal)?;
+
+    let mut cache = cache_lock.lock().unwrap();
+    cache.store_line(address, state, data);
+    if reserve {
+        cache.set_reservation(Some(address));
+    }
+    drop(cache);
+    bus.complete(signal)?;
+    Ok(data)
+}
+
+// Writes the block to memory and invalidates every other copy. A line the
+// cache holds is updated, a missing one is only brought in if `allocate`. The
+// line stays unreadable until the write is ordered, it's clean so memory
+// answers for it meanwhile.
+fn cpu_write_through(
+    address: usize,
+    data: Data,
+    allocate: bool,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<(), SimError> {
+    let mut cache = cache_lock.lock().unwrap();
+    let present = cache.get_address(address).is_some();
+    if present {
+        cache.change_state_address(address, CacheState::IS_AD);
+        drop(cache);
+    } else {
+        // report write miss
+        gui_tx.send(Event::Alert {
+            address,
+            processor_i,
+            op: MemOp::Write,
+        });
+        drop(cache);
+        if allocate {
+            cpu_allocate(
+                address,
+                CacheState::IS_AD,
+                cache_lock,
+                bus,
+                processor_i,
+            )?;
+        } else {
+            cpu_wait_write_back(address, cache_lock, bus)?;
+        }
+    }
+
+    let signal = BusSignal {
+        origin: processor_i,
+        address,
+        action: BusAction::WriteThrough(data),
+        prefetch: false,
+    };
+    cpu_request(signal, cache_lock, bus)?;
+    let (state, _) = bus.recv_data(signal)?;
+
+    if present || allocate {
+        let mut cache = cache_lock.lock().unwrap();
+        cache.store_line(address, state, data);
+        drop(cache);
+    }
+    bus.complete(signal)
+}
+
+// Brings in a block the prefetcher predicted, unless the cache holds it
+// already
+fn cpu_prefetch(
+    address: usize,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    processor_i: usize,
+) -> Result<(), SimError> {
+    let cache = cache_lock.lock().unwrap();
+    if cache.lookup(address).is_some() {
+        return Ok(());
+    }
+    drop(cache);
+    cpu_allocate(address, CacheState::IS_AD, cache_lock, bus, processor_i)?;
+
+    let signal = BusSignal {
+        origin: processor_i,
+        address,
+        action: BusAction::ReadMiss,
+        prefetch: true,
+    };
+    cpu_request(signal, cache_lock, bus)?;
+    let (state, data) = bus.recv_data(signal)?;
+
+    let mut cache = cache_lock.lock().unwrap();
+    cache.store_line(address, state, data);
+    cache.prefetch_issued(address);
+    drop(cache);
+    bus.complete(signal)
+}
+
+fn cpu_write(
+    address: usize,
+    data: Data,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<(), SimError> {
+    let mut cache = cache_lock.lock().unwrap();
+    cache.swap_victim(address);
+    cache.demand_access(address);
+    let write_through = cache.write_policy() == WritePolicy::WriteThrough;
+    let write_allocate = cache.write_allocate();
+    match cache.get_address(address).map(|line| line.state) {
+        // Already the only copy, no bus transaction needed. Dirty lines of a
+        // write-through cache come from atomics, they stay dirty.
+        Some(CacheState::Modified) => {
+            cache.store_line(address, CacheState::Modified, data);
+            return Ok(());
+        }
+        Some(CacheState::Exclusive) if !write_through => {
+            cache.store_line(address, CacheState::Modified, data);
+            return Ok(());
+        }
+        Some(CacheState::Shared | CacheState::Exclusive) if write_through => {
+            drop(cache);
+            return cpu_write_through(
+                address,
+                data,
+                write_allocate,
+                cache_lock,
+                bus,
+                gui_tx,
+                processor_i,
+            );
+        }
+        None if write_through || !write_allocate => {
+            drop(cache);
+            return cpu_write_through(
+                address,
+                data,
+                write_allocate,
+                cache_lock,
+                bus,
+                gui_tx,
+                processor_i,
+            );
+        }
+        _ => drop(cache),
+    }
+
+    // invalidate other caches before the write becomes visible, the block is
+    // a single word so there's no need to fetch it
+    let (signal, _) = cpu_request_modified(
+        address,
+        BusAction::Invalidate,
+        cache_lock,
+        bus,
+        gui_tx,
+        processor_i,
+    )?;
+
+    let mut cache = cache_lock.lock().unwrap();
+    cache.store_line(address, CacheState::Modified, data);
+    drop(cache);
+    bus.complete(signal)
+}
+
+// Performs the next buffered write, returns false if there was none
+fn cpu_drain_one(
+    cpu: &mut CpuState,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<bool, SimError> {
+    let Some((address, data)) =
+        cpu.store_buffer.pop(cpu.model, &mut cpu.drain_rng)
+    else {
+        return Ok(false);
+    };
+    cpu_write(address, data, cache_lock, bus, gui_tx, processor_i)?;
+    cpu.send_store_buffer(gui_tx, processor_i)?;
+    Ok(true)
+}
+
+fn cpu_drain_all(
+    cpu: &mut CpuState,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<(), SimError> {
+    while cpu_drain_one(cpu, cache_lock, bus, gui_tx, processor_i)? {}
     Ok(())
 }
 
 fn cpu_execute_instruction(
-    instruction: Instruction,
+    instruction: &Instruction,
+    cpu: &mut CpuState,
     cache_lock: &Arc<Mutex<Cache>>,
-    bus_tx: &SyncSender<BusSignal>,
-    data_rx: &Receiver<(CacheState, Data)>,
-    gui_tx: &Sender<Event>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
     processor_i: usize,
-) -> Result<(), Box<dyn Error>> {
-    let mut cache = cache_lock.lock().unwrap();
+) -> Result<(), SimError> {
+    if let Some(address) = instruction.address() {
+        if cpu.prefetcher.access(cpu.resolve(&address)) {
+            cache_lock.lock().unwrap().prefetch_late();
+        }
+    }
+
+    // Atomics and fences only go ahead once every earlier write is performed
+    if matches!(
+        instruction,
+        Instruction::Fence
+            | Instruction::TestAndSet { .. }
+            | Instruction::FetchAndAdd { .. }
+            | Instruction::CompareAndSwap { .. }
+            | Instruction::LoadLinked { .. }
+            | Instruction::StoreConditional { .. }
+    ) {
+        cpu_drain_all(cpu, cache_lock, bus, gui_tx, processor_i)?;
+    }
+
     match instruction {
-        Instruction::Calc => Ok(()),
-        Instruction::Read { address } => match cache.get_address(address) {
-            Some(_) => Ok(()),
-            None => {
-                gui_tx.send(Event::Alert {
+        Instruction::Calc | Instruction::Label(_) | Instruction::Fence => {
+            Ok(())
+        }
+        Instruction::Read { rd, address } => {
+            let address = cpu.resolve(address);
+            let data = match cpu.store_buffer.forward(address) {
+                Some(data) => data,
+                None => cpu_read(
                     address,
+                    false,
+                    cache_lock,
+                    bus,
+                    gui_tx,
                     processor_i,
-                    op: MemOp::Read,
-                })?;
-                Mutex::unlock(cache);
+                )?,
+            };
+            cpu.write_register(*rd, data, gui_tx, processor_i)
+        }
 
-                box_err(bus_tx.send(BusSignal {
-                    origin: processor_i,
+        Instruction::Write { address, src } => {
+            let address = cpu.resolve(address);
+            let data = src.value(&cpu.registers);
+            if !cpu.model.uses_store_buffer() {
+                return cpu_write(
                     address,
-                    action: BusAction::ReadMiss,
-                }))?;
-
-                match data_rx.recv() {
-                    Ok((state, data)) => {
-                        let mut cache = cache_lock.lock().unwrap();
-                        let replaced_line =
-                            cache.store_line(address, state, data);
-
-                        Mutex::unlock(cache);
-                        maybe_write_back(
-                            address,
-                            replaced_line,
-                            bus_tx,
-                            processor_i,
-                        )?;
-                        Ok(())
-                    }
-                    Err(err) => Err(Box::new(err)),
-                }
+                    data,
+                    cache_lock,
+                    bus,
+                    gui_tx,
+                    processor_i,
+                );
             }
-        },
-
-        Instruction::Write { address, data } => {
-            let replaced_line =
-                cache.store_line(address, CacheState::Modified, data);
 
-            // report write miss
-            if (replaced_line.tag != cache.get_tag(address))
-                || (replaced_line.state == CacheState::Invalid)
-            {
-                gui_tx.send(Event::Alert {
-                    address,
-                    processor_i,
-                    op: MemOp::Write,
-                })?;
+            if cpu.store_buffer.is_full() {
+                cpu_drain_one(cpu, cache_lock, bus, gui_tx, processor_i)?;
             }
+            cpu.store_buffer.push(address, data);
+            cpu.send_store_buffer(gui_tx, processor_i)
+        }
 
-            // invalidate other caches
-            if replaced_line.state != CacheState::Modified {
-                Mutex::unlock(cache);
-                box_err(bus_tx.send(BusSignal {
-                    origin: processor_i,
+        Instruction::TestAndSet { rd, address } => {
+            let (old, _) = cpu_atomic_rmw(
+                cpu.resolve(address),
+                |_, _| Some(1),
+                cache_lock,
+                bus,
+                gui_tx,
+                processor_i,
+            )?;
+            cpu.write_register(*rd, old, gui_tx, processor_i)
+        }
+
+        Instruction::FetchAndAdd { rd, address, value } => {
+            let value = value.value(&cpu.registers);
+            let (old, _) = cpu_atomic_rmw(
+                cpu.resolve(address),
+                |_, data| Some(data.wrapping_add(value)),
+                cache_lock,
+                bus,
+                gui_tx,
+                processor_i,
+            )?;
+            cpu.write_register(*rd, old, gui_tx, processor_i)
+        }
+
+        Instruction::CompareAndSwap {
+            rd,
+            address,
+            expected,
+            new,
+        } => {
+            let expected = expected.value(&cpu.registers);
+            let new = new.value(&cpu.registers);
+            let (old, _) = cpu_atomic_rmw(
+                cpu.resolve(address),
+                |_, data| Some(if data == expected { new } else { data }),
+                cache_lock,
+                bus,
+                gui_tx,
+                processor_i,
+            )?;
+            cpu.write_register(*rd, old, gui_tx, processor_i)
+        }
+
+        Instruction::LoadLinked { rd, address } => {
+            let data = cpu_read(
+                cpu.resolve(address),
+                true,
+                cache_lock,
+                bus,
+                gui_tx,
+                processor_i,
+            )?;
+            cpu.write_register(*rd, data, gui_tx, processor_i)
+        }
+
+        Instruction::StoreConditional { rd, address, src } => {
+            let address = cpu.resolve(address);
+            let data = src.value(&cpu.registers);
+
+            // Fail right away if the reservation is already gone, without
+            // bothering the bus
+            let mut cache = cache_lock.lock().unwrap();
+            let success = if cache.has_reservation(address) {
+                drop(cache);
+                let (_, success) = cpu_atomic_rmw(
                     address,
-                    action: BusAction::Invalidate,
-                }))?
+                    |cache, _| {
+                        let reserved = cache.has_reservation(address);
+                        cache.set_reservation(None);
+                        reserved.then_some(data)
+                    },
+                    cache_lock,
+                    bus,
+                    gui_tx,
+                    processor_i,
+                )?;
+                success
             } else {
-                Mutex::unlock(cache);
+                cache.set_reservation(None);
+                false
+            };
+            gui_tx.send(Event::StoreConditional {
+                processor_i,
+                success,
+            });
+            cpu.write_register(*rd, success.into(), gui_tx, processor_i)
+        }
+
+        Instruction::Alu {
+            op,
+            rd,
+            rs,
+            operand,
+        } => {
+            let data =
+                op.apply(cpu.registers[*rs], operand.value(&cpu.registers));
+            cpu.write_register(*rd, data, gui_tx, processor_i)
+        }
+
+        Instruction::Mov { rd, operand } => {
+            let data = operand.value(&cpu.registers);
+            cpu.write_register(*rd, data, gui_tx, processor_i)
+        }
+
+        Instruction::Cmp { rs, operand } => {
+            let a = cpu.registers[*rs];
+            let b = operand.value(&cpu.registers);
+            cpu.flags = Flags {
+                zero: a == b,
+                less: a < b,
+            };
+            gui_tx.send(Event::Flags {
+                processor_i,
+                flags: cpu.flags,
+            });
+            Ok(())
+        }
+
+        Instruction::Branch { condition, label } => {
+            // Labels outside of the loaded program can't be jumped to
+            match cpu.program.label_target(label) {
+                Some(target) if condition.holds(cpu.flags) => {
+                    cpu.set_pc(target, gui_tx, processor_i)
+                }
+                _ => Ok(()),
             }
+        }
+    }
+}
 
-            maybe_write_back(address, replaced_line, bus_tx, processor_i)
+fn cpu_handle_command(
+    command: CpuCommand,
+    cpu: &mut CpuState,
+    cache_lock: &Arc<Mutex<Cache>>,
+    bus: &BusPort,
+    gui_tx: &EventHub,
+    processor_i: usize,
+) -> Result<(), SimError> {
+    let instruction = match command {
+        CpuCommand::Execute(instruction) => Some(instruction),
+        CpuCommand::LoadProgram(program) => {
+            cpu.program = program;
+            return cpu.set_pc(0, gui_tx, processor_i);
+        }
+        // Past the end of the program the CPU idles, its store buffer keeps
+        // draining though
+        CpuCommand::Step => match cpu.program.get(cpu.pc) {
+            Some(instruction) => {
+                let instruction = instruction.clone();
+                gui_tx.send(Event::Fetch {
+                    processor_i,
+                    instruction: instruction.clone(),
+                });
+                cpu.set_pc(cpu.pc + 1, gui_tx, processor_i)?;
+                Some(instruction)
+            }
+            None => None,
+        },
+        CpuCommand::SetConsistencyModel(model) => {
+            cpu_drain_all(cpu, cache_lock, bus, gui_tx, processor_i)?;
+            cpu.model = model;
+            return Ok(());
         }
+    };
+
+    let halted = instruction.is_none();
+    if let Some(instruction) = instruction {
+        // Before the instruction overwrites the registers the address uses
+        let address =
+            instruction.address().map(|address| cpu.resolve(&address));
+        cpu_execute_instruction(
+            &instruction,
+            cpu,
+            cache_lock,
+            bus,
+            gui_tx,
+            processor_i,
+        )
+        .map_err(|err| match address {
+            Some(address) => err.at(address),
+            None => err,
+        })?;
+        gui_tx.send(Event::InstructionRetired {
+            processor_i,
+            instruction,
+        });
+    }
+
+    // Buffered writes drain at their own pace, half of the time one of them
+    // gets performed after a command
+    if !cpu.store_buffer.is_empty() && cpu.drain_rng.gen_range(0..2) == 0 {
+        cpu_drain_one(cpu, cache_lock, bus, gui_tx, processor_i)?;
+    }
+    // And so do prefetches, one after every command
+    if let Some(address) = cpu.prefetcher.next_prefetch() {
+        cpu_prefetch(address, cache_lock, bus, processor_i)?;
     }
+
+    if halted && cpu.store_buffer.is_empty() {
+        gui_tx.send(Event::Halted { processor_i });
+    }
+    Ok(())
 }
 
 impl Processor {
+    #[allow(clippy::too_many_arguments)]
     pub fn init(
         processor_i: usize,
-        bus_signal_sender: SyncSender<BusSignal>,
+        bus_signal_sender: SyncSender<BusMessage>,
         bus_data_sender: SyncSender<Option<Data>>,
         cache: Cache,
-        gui_sender: Sender<Event>,
+        address_space: usize,
+        props: &SocProperties,
+        gui_sender: EventHub,
+        shutdown: &Shutdown,
     ) -> Processor {
-        let (cpu_instruction_tx, cpu_instruction_rx) = sync_channel(1);
+        let props = *props;
+        let (cpu_command_tx, cpu_command_rx) = sync_channel(1);
+        let (cpu_grant_tx, cpu_grant_rx) = sync_channel(0);
         let (cpu_data_tx, cpu_data_rx) = sync_channel(0);
         let (controller_tx, controller_rx) = sync_channel(0);
+        let (write_back_tx, write_back_rx) = sync_channel(1);
+        let bus = BusPort::new(
+            bus_signal_sender,
+            cpu_grant_rx,
+            cpu_data_rx,
+            write_back_rx,
+            shutdown.clone(),
+        );
 
         let local_cache = Arc::new(Mutex::new(cache));
 
+        let mut threads = Vec::with_capacity(2);
         {
-            let cache_lock = local_cache.clone();
-            thread::spawn(move || {
-                Self::cpu_thread(
-                    processor_i,
-                    cache_lock,
-                    cpu_instruction_rx,
-                    cpu_data_rx,
-                    bus_signal_sender,
-                    gui_sender,
-                )
-            });
+            let shutdown_poll = shutdown.clone();
+            threads.push(spawn_component(
+                Component::Cpu(processor_i),
+                shutdown.clone(),
+                gui_sender.clone(),
+                (
+                    local_cache.clone(),
+                    cpu_command_rx,
+                    bus,
+                    CpuState::new(processor_i, address_space, &props),
+                    gui_sender.clone(),
+                ),
+                move |(cache_lock, command_rx, bus, cpu, gui_sender)| {
+                    Self::cpu_thread(
+                        processor_i,
+                        cache_lock,
+                        command_rx,
+                        bus,
+                        cpu,
+                        gui_sender,
+                        &shutdown_poll,
+                    )
+                },
+            ));
         }
 
         {
-            let cache_lock = local_cache;
-            thread::spawn(move || {
-                Self::controller_thread(
-                    processor_i,
-                    cache_lock,
-                    controller_rx,
-                    bus_data_sender,
-                )
-            });
+            let shutdown_poll = shutdown.clone();
+            threads.push(spawn_component(
+                Component::Controller(processor_i),
+                shutdown.clone(),
+                gui_sender,
+                (local_cache, controller_rx, bus_data_sender, write_back_tx),
+                move |(cache_lock, controller_rx, bus_tx, write_back_tx)| {
+                    Self::controller_thread(
+                        props.protocol,
+                        cache_lock,
+                        controller_rx,
+                        bus_tx,
+                        write_back_tx,
+                        &shutdown_poll,
+                    )
+                },
+            ));
         }
 
         Processor {
+            cpu_grant_input: cpu_grant_tx,
             cpu_data_input: cpu_data_tx,
-            cpu_instruction_input: cpu_instruction_tx,
+            cpu_command_input: cpu_command_tx,
             controller_signal_input: controller_tx,
+            threads,
         }
     }
 
-    pub fn cpu_instruction_input(&self) -> SyncSender<Instruction> {
-        self.cpu_instruction_input.clone()
+    pub fn into_threads(self) -> Vec<JoinHandle<()>> { self.threads }
+
+    pub fn cpu_command_input(&self) -> SyncSender<CpuCommand> {
+        self.cpu_command_input.clone()
+    }
+
+    pub fn cpu_grant_input(&self) -> SyncSender<()> {
+        self.cpu_grant_input.clone()
     }
 
     pub fn cpu_data_input(&self) -> SyncSender<(CacheState, Data)> {
@@ -228,58 +1053,104 @@ impl Processor {
         self.controller_signal_input.clone()
     }
 
+    // Runs until the commands stop coming
     fn cpu_thread(
         processor_i: usize,
-        cache_lock: Arc<Mutex<Cache>>,
-        instruction_rx: Receiver<Instruction>,
-        data_rx: Receiver<(CacheState, Data)>,
-        bus_tx: SyncSender<BusSignal>,
-        gui_sender: Sender<Event>,
-    ) {
+        cache_lock: &Arc<Mutex<Cache>>,
+        command_rx: &Receiver<CpuCommand>,
+        bus: &BusPort,
+        cpu: &mut CpuState,
+        gui_sender: &EventHub,
+        shutdown: &Shutdown,
+    ) -> Result<(), SimError> {
         loop {
-            match instruction_rx.recv() {
-                Ok(instruction) => {
-                    if cpu_execute_instruction(
-                        instruction,
-                        &cache_lock,
-                        &bus_tx,
-                        &data_rx,
-                        &gui_sender,
-                        processor_i,
-                    )
-                    .is_err()
-                    {
-                        println!("Processor {processor_i} dying.");
-                        break;
-                    }
-                }
-                Err(RecvError) => {
-                    println!("Processor {processor_i} dying.");
-                    break;
-                }
+            match shutdown.recv(command_rx) {
+                Ok(command) => cpu_handle_command(
+                    command,
+                    cpu,
+                    cache_lock,
+                    bus,
+                    gui_sender,
+                    processor_i,
+                )?,
+                Err(RecvError) => return Ok(()),
             }
         }
     }
 
+    // Runs until the bus is gone
     fn controller_thread(
-        processor_i: usize,
-        cache_lock: Arc<Mutex<Cache>>,
-        controller_rx: Receiver<BusSignal>,
-        bus_tx: SyncSender<Option<Data>>,
-    ) {
+        protocol: CoherenceProtocol,
+        cache_lock: &Arc<Mutex<Cache>>,
+        controller_rx: &Receiver<BusSignal>,
+        bus_tx: &SyncSender<Option<Data>>,
+        write_back_tx: &SyncSender<()>,
+        shutdown: &Shutdown,
+    ) -> Result<(), SimError> {
         loop {
-            match controller_rx.recv() {
-                Ok(signal) => {
-                    if controller_handle_signal(signal, &cache_lock, &bus_tx)
-                        .is_err()
-                    {
-                        println!("Controller {processor_i} dying.");
-                        break;
-                    };
+            match shutdown.recv(controller_rx) {
+                Ok(signal) => controller_handle_signal(
+                    signal,
+                    protocol,
+                    cache_lock,
+                    bus_tx,
+                    write_back_tx,
+                )
+                .map_err(|err| err.during(signal))?,
+                Err(RecvError) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNOOPED: [BusAction; 4] = [
+        BusAction::ReadMiss,
+        BusAction::ReadExclusive,
+        BusAction::Invalidate,
+        BusAction::WriteThrough(0),
+    ];
+
+    #[test]
+    fn snoops_on_pending_requests() {
+        for protocol in CoherenceProtocol::ALL {
+            for action in SNOOPED {
+                // Misses have nothing to give, their own request sees the
+                // outcome of this one once it gets the bus
+                for state in [CacheState::IS_AD, CacheState::IM_AD] {
+                    assert_eq!(
+                        snoop_transition(state, action, protocol),
+                        Some((false, state))
+                    );
                 }
-                Err(RecvError) => {
-                    println!("Controller {processor_i} dying.");
-                    break;
+                let transition =
+                    snoop_transition(CacheState::SM_AD, action, protocol);
+                let expected = match (action, protocol) {
+                    // The upgrade becomes a write miss, VI reads take the copy
+                    // away too
+                    (BusAction::ReadMiss, CoherenceProtocol::VI)
+                    | (BusAction::ReadExclusive, _) => {
+                        (true, CacheState::IM_AD)
+                    }
+                    // The Shared copy is still readable
+                    (BusAction::ReadMiss, _) => (true, CacheState::SM_AD),
+                    // The writer overwrites the whole block
+                    _ => (false, CacheState::IM_AD),
+                };
+                assert_eq!(transition, Some(expected), "{action:?}");
+
+                // The bus holds back every transaction on a block waiting
+                // for its data
+                for state in [
+                    CacheState::IS_D,
+                    CacheState::IM_D,
+                    CacheState::SM_D,
+                    CacheState::OM_D,
+                ] {
+                    assert_eq!(snoop_transition(state, action, protocol), None);
                 }
             }
         }
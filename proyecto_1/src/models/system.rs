@@ -1,114 +1,791 @@
 use std::{
-    error::Error,
-    sync::mpsc::{sync_channel, RecvError, Sender, SyncSender},
-    thread,
-    time::Duration,
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        mpsc::{
+            sync_channel, Receiver, RecvError, RecvTimeoutError, SyncSender,
+        },
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    app::Event,
+    events::{Event, EventHub},
     models::{
-        box_err,
-        bus::{Bus, BusAction, BusSignal},
-        cache::{Cache, CacheState},
-        instructions::Instruction,
+        arbiter::{Arbiter, ArbitrationPolicy},
+        bus::{
+            Bus, BusAction, BusMessage, BusSignal, DataBus, DataPhase,
+            TrafficStats,
+        },
+        cache::{
+            Cache, CacheState, CoherenceProtocol, ReplacementPolicy,
+            WritePolicy,
+        },
+        consistency::ConsistencyModel,
+        directory::Directory,
+        dram::{DramTiming, MemoryController, MemoryRequest, RowPolicy},
+        energy::{EnergyModel, DEFAULT_ENERGY},
+        error::{spawn_component, Component, Shutdown, SimError},
+        interconnect::{CoherenceScheme, Interconnect, Topology},
         main_memory::Memory,
-        processor::Processor,
+        numa::NumaConfig,
+        prefetcher::PrefetchPolicy,
+        processor::{CpuCommand, Processor},
+        Data,
     },
 };
 
+#[derive(Clone, Copy)]
 pub struct SocProperties {
     pub num_processors: usize,
     pub cache_associativity: usize,
     pub cache_sets: usize,
+    /// Entries of the victim cache of every processor, none if 0
+    pub victim_cache: usize,
+    /// Entries of the write-back buffer of every processor, at least one
+    pub write_back_buffer: usize,
+    pub write_policy: WritePolicy,
+    pub write_allocate: bool,
+    pub replacement: ReplacementPolicy,
+    pub protocol: CoherenceProtocol,
+    pub prefetch: PrefetchPolicy,
+    /// Blocks predicted ahead of every access
+    pub prefetch_degree: usize,
     pub main_memory_blocks: usize,
+    /// Initial model, the GUI can switch it at runtime
+    pub consistency_model: ConsistencyModel,
+    /// Time a message takes to cross the bus, or a single link of a network
+    pub bus_delay: Duration,
+    /// Timing of the DRAM of every memory node
+    pub dram: DramTiming,
+    pub numa: NumaConfig,
+    pub arbitration: ArbitrationPolicy,
+    pub topology: Topology,
+    pub coherence: CoherenceScheme,
+    pub energy: EnergyModel,
+    /// Seeds the random decisions the processors make, like when their store
+    /// buffers drain
+    pub seed: u32,
 }
 
-pub fn init_system(
+/// The system the GUI starts with: four processors with two-way caches of two
+/// sets, MOESI over a bus and eight blocks of memory. Its delays are long
+/// enough to watch every message go by.
+pub const DEFAULT_PROPS: SocProperties = SocProperties {
+    num_processors: 4,
+    cache_associativity: 2,
+    cache_sets: 2,
+    victim_cache: 0,
+    write_back_buffer: 2,
+    write_policy: WritePolicy::WriteBack,
+    write_allocate: true,
+    replacement: ReplacementPolicy::StatePriority,
+    energy: DEFAULT_ENERGY,
+    protocol: CoherenceProtocol::MOESI,
+    prefetch: PrefetchPolicy::Off,
+    prefetch_degree: 1,
+    main_memory_blocks: 8,
+    consistency_model: ConsistencyModel::Sequential,
+    bus_delay: Duration::from_millis(400),
+    dram: DramTiming {
+        banks: 2,
+        row_blocks: 2,
+        row_policy: RowPolicy::Open,
+        t_rcd: 3,
+        t_cas: 3,
+        t_rp: 3,
+        cycle: Duration::from_millis(100),
+    },
+    numa: NumaConfig {
+        nodes: 1,
+        interleave: 1,
+        remote_delay: Duration::from_millis(400),
+    },
+    arbitration: ArbitrationPolicy::FirstComeFirstServed,
+    topology: Topology::Bus,
+    coherence: CoherenceScheme::Snooping,
+    seed: 0,
+};
+
+impl SocProperties {
+    /// The same system with no delays, for when nobody watches the messages
+    /// go by
+    pub fn headless(self) -> Self {
+        SocProperties {
+            bus_delay: Duration::ZERO,
+            dram: DramTiming {
+                cycle: Duration::ZERO,
+                ..self.dram
+            },
+            numa: NumaConfig {
+                remote_delay: Duration::ZERO,
+                ..self.numa
+            },
+            ..self
+        }
+    }
+
+    /// Lines of all of the caches, victim caches included
+    pub fn cache_lines(&self) -> usize {
+        self.num_processors
+            * (self.cache_associativity * self.cache_sets + self.victim_cache)
+    }
+
+    /// Whether a system can be built with these properties, the components
+    /// divide by or index with most of them
+    pub fn validate(&self) -> Result<(), InvalidProps> {
+        let error = |message: String| Err(InvalidProps(message));
+        if self.num_processors == 0 {
+            return error("there must be at least one processor".into());
+        }
+        if self.cache_associativity == 0 {
+            return error("the associativity must be at least 1".into());
+        }
+        if !self.cache_sets.is_power_of_two() {
+            return error(format!(
+                "{} sets isn't a power of two",
+                self.cache_sets
+            ));
+        }
+        // A CPU evicting a dirty line waits for an entry to free up
+        if self.write_back_buffer == 0 {
+            return error(
+                "the write-back buffer must have at least an entry".into(),
+            );
+        }
+        if self.main_memory_blocks == 0 {
+            return error("memory must have at least a block".into());
+        }
+        if self.dram.banks == 0 {
+            return error("there must be at least one bank".into());
+        }
+        if self.dram.row_blocks == 0 {
+            return error("a DRAM row must hold at least a block".into());
+        }
+        if self.numa.nodes == 0 {
+            return error("there must be at least one memory node".into());
+        }
+        if self.numa.interleave == 0 {
+            return error("the interleave must be at least a block".into());
+        }
+        if self.numa.nodes > self.num_processors {
+            return error(format!(
+                "there are more memory nodes than the {} processors",
+                self.num_processors
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Why a system can't be built with some `SocProperties`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidProps(pub String);
+
+impl fmt::Display for InvalidProps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidProps {}
+
+/// A running system, the threads of its components and the command inputs of
+/// its CPUs. Dropping it stops every thread right away.
+pub struct System {
     props: SocProperties,
-    gui_sender: Sender<Event>,
-) -> Vec<SyncSender<Instruction>> {
-    let (bus_signal_tx, bus_signal_rx) = sync_channel(0);
-    let (bus_data_tx, bus_data_rx) = sync_channel(0);
-
-    let mut processors = Vec::with_capacity(props.num_processors);
-    let mut bus = Bus::new(bus_signal_rx, bus_data_rx);
-    let mut main_memory = Memory::new(props.main_memory_blocks);
-    main_memory.register_gui_listener(gui_sender.clone());
-
-    for i in 0..props.num_processors {
-        let mut cache =
-            Cache::new_cold(i, props.cache_associativity, props.cache_sets);
-        cache.register_gui_listener(gui_sender.clone());
-        let processor = Processor::init(
-            i,
-            bus_signal_tx.clone(),
-            bus_data_tx.clone(),
-            cache,
-            gui_sender.clone(),
-        );
-        bus.register_processor(&processor);
-        processors.push(processor);
+    initial_memory: Vec<(usize, Data)>,
+    events: EventHub,
+    cpus: Vec<SyncSender<CpuCommand>>,
+    shutdown: Shutdown,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl System {
+    /// Starts every component. `initial_memory` holds (address, data) pairs
+    /// to store before starting, and again on every reset. Everything the
+    /// system does is sent as an event to the listeners of `events`, a
+    /// `Sender<Event>` is a listener of every event. Fails if `props` isn't
+    /// valid.
+    pub fn new(
+        props: SocProperties,
+        initial_memory: &[(usize, Data)],
+        events: impl Into<EventHub>,
+    ) -> Result<Self, InvalidProps> {
+        props.validate()?;
+        let mut system = System {
+            props,
+            initial_memory: initial_memory.to_vec(),
+            events: events.into(),
+            cpus: Vec::new(),
+            shutdown: Shutdown::default(),
+            threads: Vec::new(),
+        };
+        system.start();
+        Ok(system)
     }
 
-    thread::spawn(move || system_control_thread(bus, main_memory));
+    pub fn props(&self) -> &SocProperties { &self.props }
+
+    /// Where more listeners subscribe, they stay subscribed across resets
+    pub fn events(&self) -> &EventHub { &self.events }
+
+    /// Command inputs of the CPUs, none once the system is shut down
+    pub fn cpus(&self) -> &[SyncSender<CpuCommand>] { &self.cpus }
+
+    /// Gives a command to a CPU, it fails if the CPU stopped or doesn't
+    /// exist
+    pub fn send(
+        &self,
+        cpu_i: usize,
+        command: CpuCommand,
+    ) -> Result<(), SimError> {
+        match self.cpus.get(cpu_i) {
+            Some(cpu) => Ok(cpu.send(command)?),
+            None => Err(SimError::Disconnected(Default::default())),
+        }
+    }
 
-    processors
-        .iter()
-        .map(|p| p.cpu_instruction_input())
-        .collect()
+    /// Lets the CPUs finish their commands and the posted write backs drain,
+    /// then waits for every thread. Their last events are sent by then.
+    pub fn shutdown(&mut self) {
+        self.cpus.clear();
+        self.join();
+    }
+
+    /// Starts over with cold caches and the initial memory. Events the old
+    /// system sent before stopping may still be waiting in the channel.
+    pub fn reset(&mut self) {
+        self.abort();
+        self.start();
+    }
+
+    /// Starts over with another configuration, like `reset`. The system is
+    /// left as it was if `props` isn't valid.
+    pub fn rebuild(
+        &mut self,
+        props: SocProperties,
+    ) -> Result<(), InvalidProps> {
+        props.validate()?;
+        self.abort();
+        self.props = props;
+        self.start();
+        Ok(())
+    }
+
+    // Stops every thread without waiting for what they were doing
+    fn abort(&mut self) {
+        self.cpus.clear();
+        self.shutdown.raise();
+        self.join();
+    }
+
+    fn join(&mut self) {
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+    }
+
+    fn start(&mut self) {
+        let shutdown = Shutdown::default();
+        self.shutdown = shutdown.clone();
+        let props = self.props;
+        let gui_sender = &self.events;
+        let mut threads = Vec::new();
+        // Every processor has at most one request waiting for the arbiter, plus
+        // the completion of its previous transaction and its posted write backs
+        let (bus_signal_tx, bus_signal_rx) =
+            sync_channel((2 + props.write_back_buffer) * props.num_processors);
+        let (bus_data_tx, bus_data_rx) = sync_channel(0);
+        // And at most one transaction in its data phase or in memory
+        let (data_phase_tx, data_phase_rx) = sync_channel(props.num_processors);
+        let (memory_txs, memory_rxs): (Vec<_>, Vec<_>) = (0..props.numa.nodes)
+            .map(|_| sync_channel(props.num_processors))
+            .unzip();
+
+        let mut processors = Vec::with_capacity(props.num_processors);
+        let mut bus = Bus::new(
+            bus_signal_rx,
+            bus_data_rx,
+            data_phase_tx.clone(),
+            memory_txs,
+            props.numa,
+            shutdown.clone(),
+        );
+        let mut data_bus = DataBus::new(data_phase_rx, shutdown.clone());
+        bus.register_gui_listener(gui_sender.clone());
+        let arbiter =
+            Arbiter::new(props.arbitration, props.num_processors, props.seed);
+        let mut main_memory = Memory::new(props.main_memory_blocks);
+        let address_space = main_memory.address_space();
+        main_memory.register_gui_listener(gui_sender.clone());
+        for &(address, data) in &self.initial_memory {
+            main_memory.store_address(address % address_space, data);
+        }
+
+        for i in 0..props.num_processors {
+            let mut cache = Cache::new_cold(
+                i,
+                props.cache_associativity,
+                props.cache_sets,
+                props.victim_cache,
+                props.write_back_buffer,
+                props.write_policy,
+                props.write_allocate,
+            );
+            cache.set_replacement_policy(props.replacement);
+            cache.register_gui_listener(gui_sender.clone());
+            let processor = Processor::init(
+                i,
+                bus_signal_tx.clone(),
+                bus_data_tx.clone(),
+                cache,
+                address_space,
+                &props,
+                gui_sender.clone(),
+                &shutdown,
+            );
+            bus.register_processor(&processor);
+            data_bus.register_processor(&processor);
+            processors.push(processor);
+        }
+
+        let mut interconnect = Interconnect::new(
+            props.topology,
+            props.num_processors,
+            props.bus_delay,
+        );
+        interconnect.register_gui_listener(gui_sender.clone());
+        let interconnect = Arc::new(Mutex::new(interconnect));
+        let home = Home {
+            interconnect: interconnect.clone(),
+            directory: Directory::new(props.main_memory_blocks),
+            traffic: TrafficStats::default(),
+        };
+
+        for (node, memory_rx) in memory_rxs.into_iter().enumerate() {
+            let node = MemoryNode {
+                node,
+                controller: MemoryController::new(props.dram),
+                remote_delay: props.numa.remote_delay,
+            };
+            let shutdown_poll = shutdown.clone();
+            threads.push(spawn_component(
+                Component::MemoryController(node.node),
+                shutdown.clone(),
+                gui_sender.clone(),
+                (
+                    node,
+                    memory_rx,
+                    interconnect.clone(),
+                    data_phase_tx.clone(),
+                    gui_sender.clone(),
+                ),
+                move |(
+                    node,
+                    memory_rx,
+                    interconnect,
+                    data_phase_tx,
+                    gui_sender,
+                )| {
+                    memory_controller_thread(
+                        node,
+                        memory_rx,
+                        interconnect,
+                        data_phase_tx,
+                        gui_sender,
+                        &shutdown_poll,
+                    )
+                },
+            ));
+        }
+        threads.push(spawn_component(
+            Component::Bus,
+            shutdown.clone(),
+            gui_sender.clone(),
+            (bus, arbiter, main_memory, home),
+            move |(bus, arbiter, main_memory, home)| {
+                system_control_thread(bus, arbiter, main_memory, home, &props)
+            },
+        ));
+        threads.push(spawn_component(
+            Component::DataBus,
+            shutdown,
+            gui_sender.clone(),
+            data_bus,
+            data_bus_thread,
+        ));
+
+        self.cpus = processors.iter().map(|p| p.cpu_command_input()).collect();
+        threads
+            .extend(processors.into_iter().flat_map(Processor::into_threads));
+        self.threads = threads;
+    }
 }
 
-// This is called after the signal has already been propagated
+impl Drop for System {
+    fn drop(&mut self) { self.abort() }
+}
+
+// Address phase of a transaction, called once it got the bus. The other
+// caches are snooped right away, the data reaches the requester later on the
+// data bus. The time both take comes from the messages they need on the
+// interconnect.
 fn handle_signal(
     signal: BusSignal,
     bus: &Bus,
     main_memory: &mut Memory,
-) -> Result<(), Box<dyn Error>> {
-    // Simulated bus delay
-    thread::sleep(Duration::from_millis(400));
-    match signal.action {
+    home: &mut Home,
+    props: &SocProperties,
+) -> Result<(), SimError> {
+    let block = main_memory.get_line(signal.address);
+    let targets = match props.coherence {
+        CoherenceScheme::Snooping => (0..props.num_processors)
+            .filter(|&i| i != signal.origin)
+            .collect(),
+        CoherenceScheme::Directory => home.directory.targets(block, &signal),
+    };
+    home.directory.update(block, &signal);
+
+    // The request reaches the home node, which forwards it to the caches
+    // that have to see it and waits for their answers. Every cache hears a
+    // message on a bus at once, and they answer on shared lines.
+    let mut net = home.interconnect.lock().unwrap();
+    let node = net.home();
+    let at_home = net.send(signal.origin, node, "request", Instant::now());
+    let snooped = match (net.topology(), props.coherence) {
+        (Topology::Bus, CoherenceScheme::Snooping) => at_home,
+        _ => targets
+            .iter()
+            .map(|&target| {
+                let delivered = net.send(node, target, "snoop", at_home);
+                net.send(target, node, "answer", delivered)
+            })
+            .max()
+            .unwrap_or(at_home),
+    };
+    net.send_stats();
+    drop(net);
+    bus.wait_until(snooped)?;
+
+    // Data from another cache is forwarded by the home node right away, data
+    // from memory waits for the DRAM
+    let respond = |label, state, data| {
+        reply(signal, label, state, data, bus, &home.interconnect)
+    };
+    let traffic = &mut home.traffic;
+    if signal.action != BusAction::WriteMem {
+        traffic.snoops += targets.len();
+    }
+    let result = match signal.action {
+        BusAction::ReadMiss => {
+            traffic.read_misses += 1;
+            traffic.data_transfers += 1;
+            bus.request_cache_data(signal, &targets)?;
+            match bus.check_cache_data(targets.len())? {
+                Some(data) => {
+                    // Without an Owned state the block can't stay dirty in
+                    // the cache that supplied it
+                    if !props.protocol.has_owned() {
+                        main_memory.store_address(signal.address, data);
+                        traffic.memory_writes += 1;
+                    }
+                    respond("data", props.protocol.read_state(true), data)
+                }
+                None => {
+                    traffic.memory_reads += 1;
+                    bus.request_memory(
+                        signal,
+                        block,
+                        props.protocol.read_state(false),
+                        main_memory.get_address(signal.address),
+                    )
+                }
+            }
+        }
+        BusAction::ReadExclusive => {
+            traffic.read_exclusives += 1;
+            traffic.data_transfers += 1;
+            bus.request_cache_data(signal, &targets)?;
+            match bus.check_cache_data(targets.len())? {
+                Some(data) => respond("data", CacheState::Modified, data),
+                None => {
+                    traffic.memory_reads += 1;
+                    bus.request_memory(
+                        signal,
+                        block,
+                        CacheState::Modified,
+                        main_memory.get_address(signal.address),
+                    )
+                }
+            }
+        }
+        // The writer overwrites the whole block after an invalidate, so there
+        // is no data to wait for, only the acknowledgement that the other
+        // copies are gone
         BusAction::Invalidate => {
-            box_err(bus.propagate_signal(signal))?;
+            traffic.invalidates += 1;
+            bus.request_cache_data(signal, &targets)?;
+            let data = bus.check_cache_data(targets.len())?.unwrap_or_default();
+            respond("ack", CacheState::Modified, data)
         }
+        // Nothing to acknowledge, the line left the write-back buffer as soon
+        // as its data was taken
+        BusAction::WriteMem => match bus.request_write_back_data(signal)? {
+            Some(data) => {
+                traffic.write_backs += 1;
+                traffic.data_transfers += 1;
+                traffic.memory_writes += 1;
+                main_memory.store_address(signal.address, data);
+                bus.send_write_back(signal, data);
+                bus.request_memory(signal, block, CacheState::Invalid, data)
+            }
+            None => Ok(()),
+        },
+        // Acknowledged once memory took it too, the writer decides whether
+        // it keeps a copy
+        BusAction::WriteThrough(data) => {
+            traffic.write_throughs += 1;
+            traffic.data_transfers += 1;
+            traffic.memory_writes += 1;
+            bus.request_cache_data(signal, &targets)?;
+            bus.check_cache_data(targets.len())?;
+            main_memory.store_address(signal.address, data);
+            bus.request_memory(signal, block, CacheState::Shared, data)
+        }
+    };
+    bus.send_traffic(home.traffic);
+    result
+}
 
-        BusAction::ReadMiss => {
-            box_err(bus.request_cache_data(signal))?;
-            return box_err(match bus.check_cache_data()? {
-                Some(data) => bus.send_data_to_cpu(
-                    signal.origin,
-                    CacheState::Shared,
-                    data,
-                ),
-                None => bus.send_data_to_cpu(
-                    signal.origin,
-                    CacheState::Exclusive,
-                    main_memory.get_address(signal.address),
+// Sends the data or acknowledgement of a transaction from the home node to
+// the requester
+fn reply(
+    signal: BusSignal,
+    label: &'static str,
+    state: CacheState,
+    data: Data,
+    bus: &Bus,
+    interconnect: &Mutex<Interconnect>,
+) -> Result<(), SimError> {
+    let mut net = interconnect.lock().unwrap();
+    let node = net.home();
+    let arrival = net.send(node, signal.origin, label, Instant::now());
+    net.send_stats();
+    drop(net);
+
+    bus.schedule_data(
+        signal.origin,
+        state,
+        data,
+        arrival.saturating_duration_since(Instant::now()),
+    )?;
+    Ok(())
+}
+
+// What the node ordering the transactions keeps besides memory. The memory
+// controller sends its data through the interconnect too.
+struct Home {
+    interconnect: Arc<Mutex<Interconnect>>,
+    // Kept under snooping too, it's only consulted under the directory
+    // scheme
+    directory: Directory,
+    traffic: TrafficStats,
+}
+
+// Pending-transaction table, keyed by block. A block stays in it from its
+// address phase until the requester installed the data, requests for it wait
+// in the arbiter meanwhile.
+type InFlight = BTreeMap<usize, BusSignal>;
+
+fn handle_message(
+    message: BusMessage,
+    arbiter: &mut Arbiter,
+    in_flight: &mut InFlight,
+    bus: &Bus,
+    main_memory: &Memory,
+) {
+    match message {
+        BusMessage::Request(signal) => arbiter.push(signal),
+        BusMessage::Done(signal) => {
+            in_flight.remove(&main_memory.get_line(signal.address));
+            bus.send_in_flight(in_flight.values().copied().collect());
+            bus.send_transaction_end(signal);
+        }
+    }
+}
+
+// Runs until every CPU is gone
+fn system_control_thread(
+    bus: &Bus,
+    arbiter: &mut Arbiter,
+    main_memory: &mut Memory,
+    home: &mut Home,
+    props: &SocProperties,
+) -> Result<(), SimError> {
+    let mut in_flight = InFlight::new();
+    loop {
+        // Wait until there's a request for a block that isn't busy
+        while !arbiter.can_grant(|signal| {
+            !in_flight.contains_key(&main_memory.get_line(signal.address))
+        }) {
+            match bus.recv_message() {
+                Ok(message) => handle_message(
+                    message,
+                    arbiter,
+                    &mut in_flight,
+                    bus,
+                    main_memory,
                 ),
-            });
+                Err(RecvError) => return Ok(()),
+            }
         }
-        BusAction::WriteMem(data) => {
-            println!("BUS: Write back to main memory {0}", signal.address);
-            main_memory.store_address(signal.address, data)
+        // Everything that arrived during the last address phase competes too
+        while let Ok(message) = bus.try_recv_message() {
+            handle_message(message, arbiter, &mut in_flight, bus, main_memory);
         }
-    }
 
-    Ok(())
+        let signal = arbiter
+            .grant_next(|signal| {
+                !in_flight.contains_key(&main_memory.get_line(signal.address))
+            })
+            .unwrap();
+        in_flight.insert(main_memory.get_line(signal.address), signal);
+        bus.send_in_flight(in_flight.values().copied().collect());
+        bus.grant(signal, arbiter)
+            .map_err(|err| SimError::from(err).during(signal))?;
+        handle_signal(signal, bus, main_memory, home, props)
+            .map_err(|err| err.during(signal))?;
+        // Nobody completes a posted write back, memory has the block already
+        if signal.action == BusAction::WriteMem {
+            handle_message(
+                BusMessage::Done(signal),
+                arbiter,
+                &mut in_flight,
+                bus,
+                main_memory,
+            );
+        }
+    }
 }
 
-fn system_control_thread(bus: Bus, mut main_memory: Memory) {
+// Delivers every data phase once its latency is over, independently of the
+// address phases going on meanwhile
+fn data_bus_thread(data_bus: &mut DataBus) -> Result<(), SimError> {
+    let mut scheduled: Vec<DataPhase> = Vec::new();
     loop {
-        match bus.recv_signal() {
-            Ok(signal) => {
-                if handle_signal(signal, &bus, &mut main_memory).is_err() {
-                    println!("Bus dying.");
-                    break;
-                };
+        let next = scheduled
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, phase)| phase.ready)
+            .map(|(i, phase)| (i, phase.ready));
+        match data_bus.recv_data_phase_until(next.map(|(_, ready)| ready)) {
+            Ok(phase) => scheduled.push(phase),
+            Err(RecvTimeoutError::Timeout) => {
+                let (i, _) = next.unwrap();
+                data_bus.send_data_to_cpu(scheduled.remove(i))?;
             }
-            Err(RecvError) => {
-                println!("Bus dying.");
-                break;
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+// A NUMA memory node, with its own DRAM
+struct MemoryNode {
+    node: usize,
+    controller: MemoryController,
+    remote_delay: Duration,
+}
+
+// Starts the block accesses as their banks free up, and hands every one over
+// to the data bus as soon as it's known when the DRAM will be done with it.
+// Remote requests pay the trip between nodes on top of that.
+fn memory_controller_thread(
+    node: &mut MemoryNode,
+    requests: &Receiver<MemoryRequest>,
+    interconnect: &Mutex<Interconnect>,
+    data_phase_tx: &SyncSender<DataPhase>,
+    gui_tx: &EventHub,
+    shutdown: &Shutdown,
+) -> Result<(), SimError> {
+    let controller = &mut node.controller;
+    loop {
+        while let Some((request, done)) = controller.issue(Instant::now()) {
+            if request.posted {
+                continue;
             }
+            let label = if request.write { "ack" } else { "data" };
+            let departure =
+                if request.remote { done + node.remote_delay } else { done };
+            let mut net = interconnect.lock().unwrap();
+            let home = net.home();
+            let arrival = net.send(home, request.origin, label, departure);
+            net.send_stats();
+            drop(net);
+
+            let phase = DataPhase {
+                origin: request.origin,
+                state: request.state,
+                data: request.data,
+                ready: arrival,
+            };
+            data_phase_tx.send(phase)?;
+        }
+        gui_tx.send(Event::MemoryStats {
+            node: node.node,
+            banks: controller.stats().to_vec(),
+            queued: controller.queued(),
+            accesses: controller.accesses(),
+        });
+
+        match shutdown.recv_until(requests, controller.next_bank_free()) {
+            Ok(request) => controller.push(request),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::models::instructions::{Address, Instruction};
+
+    #[test]
+    fn invalid_systems_are_not_built() {
+        let props = SocProperties {
+            cache_sets: 3,
+            ..DEFAULT_PROPS.headless()
+        };
+        let (events_tx, _events_rx) = channel();
+        let err = System::new(props, &[], events_tx.clone()).err().unwrap();
+        assert_eq!(err.to_string(), "3 sets isn't a power of two");
+        let props = SocProperties {
+            write_back_buffer: 0,
+            ..DEFAULT_PROPS.headless()
+        };
+        assert!(System::new(props, &[], events_tx.clone()).is_err());
+
+        let mut system =
+            System::new(DEFAULT_PROPS.headless(), &[], events_tx).unwrap();
+        let nodes = SocProperties {
+            numa: NumaConfig {
+                nodes: 0,
+                ..DEFAULT_PROPS.numa
+            },
+            ..DEFAULT_PROPS.headless()
+        };
+        assert!(system.rebuild(nodes).is_err());
+        // Left running as it was
+        assert_eq!(system.cpus().len(), DEFAULT_PROPS.num_processors);
+        let read = Instruction::Read {
+            rd: 1,
+            address: Address::from(0),
+        };
+        system.send(0, CpuCommand::Execute(read)).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+pub struct ExampleProgram {
+    pub name: &'static str,
+    /// One program per CPU, CPUs past the end of the list get an empty one
+    pub sources: &'static [&'static str],
+}
+
+// Every CPU adds 1 to the counter at 0b0010 three times, protected by a
+// test-and-set spin lock at 0b0000
+const TAS_COUNTER: &str = "
+    mov r3, 3
+loop:
+acquire:
+    tas r1, 0b0000
+    cmp r1, 0
+    bne acquire
+    read r2, 0b0010
+    add r2, r2, 1
+    write 0b0010, r2
+    write 0b0000, 0
+    sub r3, r3, 1
+    cmp r3, 0
+    bne loop
+";
+
+// Same counter without a lock, retrying until the store-conditional succeeds
+const LL_SC_COUNTER: &str = "
+    mov r3, 3
+loop:
+    ll r2, 0b0010
+    add r2, r2, 1
+    sc r1, 0b0010, r2
+    cmp r1, 0
+    beq loop
+    sub r3, r3, 1
+    cmp r3, 0
+    bne loop
+";
+
+// Single producer, single consumer ring buffer of 4 slots starting at 0b1000.
+// The head index lives at 0b0100 and the tail index at 0b0110, both count up
+// forever and get wrapped when indexing.
+const PRODUCER: &str = "
+    mov r1, 0
+    mov r4, 0
+produce:
+    add r1, r1, 1
+wait_space:
+    read r5, 0b0100
+    sub r6, r4, r5
+    cmp r6, 4
+    bge wait_space
+    and r7, r4, 3
+    shl r7, r7, 1
+    write 0b1000(r7), r1
+    add r4, r4, 1
+    write 0b0110, r4
+    cmp r1, 8
+    blt produce
+";
+
+// Leaves the sum of the consumed items in r2
+const CONSUMER: &str = "
+    mov r2, 0
+    mov r4, 0
+consume:
+wait_item:
+    read r5, 0b0110
+    cmp r5, r4
+    beq wait_item
+    and r7, r4, 3
+    shl r7, r7, 1
+    read r1, 0b1000(r7)
+    add r2, r2, r1
+    add r4, r4, 1
+    write 0b0100, r4
+    cmp r4, 8
+    blt consume
+";
+
+// Litmus tests, x lives at 0b0000 and y at 0b0010. The outcome is read from
+// r1 and r2 once every CPU halted.
+
+// Store buffering: r1 = r2 = 0 is forbidden under SC but allowed under TSO and
+// PSO, each CPU's write can still be sitting in its store buffer
+const SB_0: &str = "
+    write 0b0000, 1
+    read r1, 0b0010
+";
+
+const SB_1: &str = "
+    write 0b0010, 1
+    read r1, 0b0000
+";
+
+// Store buffering with fences, r1 = r2 = 0 is forbidden under every model
+const SB_FENCE_0: &str = "
+    write 0b0000, 1
+    fence
+    read r1, 0b0010
+";
+
+const SB_FENCE_1: &str = "
+    write 0b0010, 1
+    fence
+    read r1, 0b0000
+";
+
+// Message passing: the reader seeing the flag (r1 = 1) but not the data
+// (r2 = 0) is forbidden under SC and TSO, PSO lets the flag's write drain
+// before the data's. The reader waits a bit to give the writes time to drain.
+const MP_WRITER: &str = "
+    write 0b0000, 1
+    write 0b0010, 1
+";
+
+const MP_READER: &str = "
+    calc
+    calc
+    read r1, 0b0010
+    read r2, 0b0000
+";
+
+// Independent reads of independent writes: the readers disagreeing on the
+// order of the writes (CPU3 r1 = 1, r2 = 0 and CPU4 r1 = 1, r2 = 0) is
+// forbidden under every model, writes reach every cache at once through the
+// bus
+const IRIW_WRITE_X: &str = "
+    write 0b0000, 1
+";
+
+const IRIW_WRITE_Y: &str = "
+    write 0b0010, 1
+";
+
+const IRIW_READ_XY: &str = "
+    read r1, 0b0000
+    read r2, 0b0010
+";
+
+const IRIW_READ_YX: &str = "
+    read r1, 0b0010
+    read r2, 0b0000
+";
+
+pub const EXAMPLE_PROGRAMS: [ExampleProgram; 7] = [
+    ExampleProgram {
+        name: "Shared counter (test-and-set lock)",
+        sources: &[TAS_COUNTER, TAS_COUNTER, TAS_COUNTER, TAS_COUNTER],
+    },
+    ExampleProgram {
+        name: "Shared counter (LL/SC)",
+        sources: &[LL_SC_COUNTER, LL_SC_COUNTER, LL_SC_COUNTER, LL_SC_COUNTER],
+    },
+    ExampleProgram {
+        name: "Producer/consumer queue",
+        sources: &[PRODUCER, CONSUMER],
+    },
+    ExampleProgram {
+        name: "Litmus: store buffering (SB)",
+        sources: &[SB_0, SB_1],
+    },
+    ExampleProgram {
+        name: "Litmus: store buffering with fences",
+        sources: &[SB_FENCE_0, SB_FENCE_1],
+    },
+    ExampleProgram {
+        name: "Litmus: message passing (MP)",
+        sources: &[MP_WRITER, MP_READER],
+    },
+    ExampleProgram {
+        name: "Litmus: independent reads of independent writes (IRIW)",
+        sources: &[IRIW_WRITE_X, IRIW_WRITE_Y, IRIW_READ_XY, IRIW_READ_YX],
+    },
+];
+
+impl ExampleProgram {
+    pub fn source(&self, processor_i: usize) -> &'static str {
+        self.sources
+            .get(processor_i)
+            .map_or("", |source| source.trim_start_matches('\n'))
+    }
+}
@@ -0,0 +1,180 @@
+use std::mem::size_of;
+
+use crate::{
+    models::{
+        instructions::{Address, Instruction, Operand},
+        Data,
+    },
+    random::UniformRng,
+};
+
+// Blocks the producer cycles through
+const PRODUCER_BUFFER: usize = 2;
+// Blocks every CPU keeps reading in the read-mostly scenario
+const READ_MOSTLY_BLOCKS: usize = 4;
+// Steps between two updates of the read-mostly data
+const READ_MOSTLY_PERIOD: usize = 16;
+// Blocks every CPU reads and writes in the wide sharing scenario
+const WIDELY_SHARED_BLOCKS: u32 = 2;
+
+/// Classic ways in which CPUs share data, each one a stream of instructions
+/// per CPU
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SharingPattern {
+    Migratory,
+    ProducerConsumer,
+    ReadMostly,
+    WideSharing,
+    FalseSharing,
+}
+
+impl SharingPattern {
+    pub const ALL: [SharingPattern; 5] = [
+        SharingPattern::Migratory,
+        SharingPattern::ProducerConsumer,
+        SharingPattern::ReadMostly,
+        SharingPattern::WideSharing,
+        SharingPattern::FalseSharing,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            SharingPattern::Migratory => "migratory",
+            SharingPattern::ProducerConsumer => "producer-consumer",
+            SharingPattern::ReadMostly => "read-mostly",
+            SharingPattern::WideSharing => "wide-sharing",
+            SharingPattern::FalseSharing => "false-sharing",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SharingPattern> {
+        SharingPattern::ALL
+            .into_iter()
+            .find(|pattern| pattern.get_name().eq_ignore_ascii_case(name))
+    }
+
+    /// What the scenario does and what it shows about MOESI
+    pub fn description(&self) -> &str {
+        match self {
+            SharingPattern::Migratory => {
+                "One CPU at a time reads the block at 0 and then writes it, \
+                 like data behind a lock. Every turn is a read miss another \
+                 cache supplies followed by an upgrade. MOESI keeps the dirty \
+                 block out of memory while it moves around, MESI and MSI \
+                 write it back on every read."
+            }
+            SharingPattern::ProducerConsumer => {
+                "CPU1 writes a buffer of two blocks that the other CPUs read \
+                 right after. Under MOESI the producer keeps each block in \
+                 Owned and supplies every reader, MESI and MSI write it to \
+                 memory on the first read. Under VI every reader takes the \
+                 only copy away from the others."
+            }
+            SharingPattern::ReadMostly => {
+                "Every CPU keeps reading four blocks that CPU1 updates once \
+                 in a while. The copies stay Shared everywhere and every \
+                 update invalidates all of them. VI can only keep one copy, \
+                 so nearly every read misses."
+            }
+            SharingPattern::WideSharing => {
+                "Every CPU reads and writes the same two blocks at random. \
+                 Nearly every access misses or invalidates the other copies. \
+                 Owned saves the memory write MESI pays whenever a dirty \
+                 block is read, write-through sends every write to memory."
+            }
+            SharingPattern::FalseSharing => {
+                "Every CPU only reads and writes its own word, the words are \
+                 next to each other. Blocks hold a single word here, so every \
+                 word has a block of its own and there's no coherence \
+                 traffic past the first misses. It's the baseline for when \
+                 blocks hold several words and the CPUs would fight over \
+                 them."
+            }
+        }
+    }
+
+    /// Instruction CPU `cpu_i` runs at its `step`-th turn. The CPUs take their
+    /// turns together.
+    pub fn instruction(
+        &self,
+        cpu_i: usize,
+        step: usize,
+        num_processors: usize,
+        rng: &mut UniformRng,
+    ) -> Instruction {
+        let data = Operand::Immediate(step as Data);
+        match self {
+            SharingPattern::Migratory => {
+                if (step / 2) % num_processors != cpu_i {
+                    return Instruction::Calc;
+                }
+                match step % 2 {
+                    0 => Instruction::Read {
+                        rd: 1,
+                        address: word(0),
+                    },
+                    _ => Instruction::Write {
+                        address: word(0),
+                        src: data,
+                    },
+                }
+            }
+            // The consumers read the block written the step before
+            SharingPattern::ProducerConsumer => match cpu_i {
+                0 => Instruction::Write {
+                    address: word(step % PRODUCER_BUFFER),
+                    src: data,
+                },
+                _ => Instruction::Read {
+                    rd: 1,
+                    address: word(
+                        (step + PRODUCER_BUFFER - 1) % PRODUCER_BUFFER,
+                    ),
+                },
+            },
+            // The readers get their copies before the first update
+            SharingPattern::ReadMostly => {
+                if cpu_i == 0
+                    && step % READ_MOSTLY_PERIOD == READ_MOSTLY_PERIOD - 1
+                {
+                    Instruction::Write {
+                        address: word(
+                            step / READ_MOSTLY_PERIOD % READ_MOSTLY_BLOCKS,
+                        ),
+                        src: data,
+                    }
+                } else {
+                    Instruction::Read {
+                        rd: 1,
+                        address: word((step + cpu_i) % READ_MOSTLY_BLOCKS),
+                    }
+                }
+            }
+            SharingPattern::WideSharing => {
+                let block = rng.gen_range(0..WIDELY_SHARED_BLOCKS) as usize;
+                match rng.gen_range(0..2) {
+                    0 => Instruction::Read {
+                        rd: 1,
+                        address: word(block),
+                    },
+                    _ => Instruction::Write {
+                        address: word(block),
+                        src: data,
+                    },
+                }
+            }
+            SharingPattern::FalseSharing => match step % 2 {
+                0 => Instruction::Write {
+                    address: word(cpu_i),
+                    src: data,
+                },
+                _ => Instruction::Read {
+                    rd: 1,
+                    address: word(cpu_i),
+                },
+            },
+        }
+    }
+}
+
+fn word(i: usize) -> Address { (i * size_of::<Data>()).into() }
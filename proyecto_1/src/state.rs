@@ -0,0 +1,237 @@
+use std::{collections::BTreeMap, mem::size_of, sync::mpsc::Receiver};
+
+use crate::{
+    events::Event,
+    models::{
+        bus::TrafficStats,
+        cache::{CacheActivity, CacheLine, CacheState},
+        error::SimError,
+        instructions::{Flags, NUM_REGISTERS},
+        prefetcher::PrefetchStats,
+        system::SocProperties,
+        Data, MemOp,
+    },
+};
+
+/// Statistics of a run, of the whole system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// Memory instructions the CPUs executed
+    pub accesses: usize,
+    pub read_misses: usize,
+    pub write_misses: usize,
+    pub traffic: TrafficStats,
+    /// Of all of the CPUs
+    pub prefetch: PrefetchStats,
+    /// Of all of the memory nodes
+    pub local_accesses: usize,
+    pub remote_accesses: usize,
+    pub row_hits: usize,
+    pub messages: usize,
+    pub hops: usize,
+    /// Of all of the caches
+    pub activity: CacheActivity,
+    /// Instructions of the CPU that executed the most
+    pub cycles: usize,
+}
+
+impl Stats {
+    pub fn miss_rate(&self) -> f32 {
+        match self.accesses {
+            0 => 0.0,
+            accesses => {
+                (self.read_misses + self.write_misses) as f32 / accesses as f32
+            }
+        }
+    }
+}
+
+/// What the events of a system told so far: the contents of its caches,
+/// buffers, memory and registers, which CPUs halted and its statistics. It's
+/// rebuilt from the events alone, so it's only as recent as the last event
+/// applied.
+pub struct SystemState {
+    /// Lines of every cache, the ways of a set are next to each other
+    pub caches: Vec<Vec<CacheLine>>,
+    /// Tags hold the block number
+    pub victim_caches: Vec<Vec<CacheLine>>,
+    /// Tags hold the block number
+    pub write_back_buffers: Vec<Vec<CacheLine>>,
+    pub memory: Vec<Data>,
+    pub registers: Vec<[Data; NUM_REGISTERS]>,
+    pub flags: Vec<Flags>,
+    pub pcs: Vec<usize>,
+    pub halted: Vec<bool>,
+    /// Instructions every CPU retired
+    pub retired: Vec<usize>,
+    /// Why the system stopped, if a component failed
+    pub error: Option<SimError>,
+    stats: Stats,
+    // Statistics the events carry as running totals of every component
+    prefetch: BTreeMap<usize, PrefetchStats>,
+    activity: BTreeMap<usize, CacheActivity>,
+    // Local and remote accesses, and row hits, of every node
+    nodes: BTreeMap<usize, (usize, usize, usize)>,
+    associativity: usize,
+    index_bits: u32,
+    offset_bits: u32,
+}
+
+impl SystemState {
+    /// State of a system that just started, with cold caches and zeroed
+    /// memory
+    pub fn new(props: &SocProperties) -> Self {
+        SystemState {
+            caches: vec![
+                vec![
+                    CacheLine::new_cold();
+                    props.cache_associativity * props.cache_sets
+                ];
+                props.num_processors
+            ],
+            victim_caches: vec![Vec::new(); props.num_processors],
+            write_back_buffers: vec![Vec::new(); props.num_processors],
+            memory: vec![0; props.main_memory_blocks],
+            registers: vec![[0; NUM_REGISTERS]; props.num_processors],
+            flags: vec![Flags::default(); props.num_processors],
+            pcs: vec![0; props.num_processors],
+            halted: vec![false; props.num_processors],
+            retired: vec![0; props.num_processors],
+            error: None,
+            stats: Stats::default(),
+            prefetch: BTreeMap::new(),
+            activity: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            associativity: props.cache_associativity,
+            index_bits: props.cache_sets.trailing_zeros(),
+            offset_bits: size_of::<Data>().trailing_zeros(),
+        }
+    }
+
+    pub fn apply(&mut self, event: Event) {
+        match event {
+            Event::CacheWrite {
+                cache_i,
+                block_i,
+                line,
+            } => self.caches[cache_i][block_i] = line,
+            Event::VictimCache { cache_i, lines } => {
+                self.victim_caches[cache_i] = lines
+            }
+            Event::WriteBackBuffer { cache_i, lines } => {
+                self.write_back_buffers[cache_i] = lines
+            }
+            Event::MemWrite { block_i, data } => self.memory[block_i] = data,
+            Event::RegisterWrite {
+                processor_i,
+                register,
+                data,
+            } => self.registers[processor_i][register] = data,
+            Event::Flags { processor_i, flags } => {
+                self.flags[processor_i] = flags
+            }
+            Event::ProgramCounter { processor_i, pc } => {
+                self.pcs[processor_i] = pc
+            }
+            Event::Halted { processor_i } => self.halted[processor_i] = true,
+            Event::InstructionRetired {
+                processor_i,
+                instruction,
+            } => {
+                self.retired[processor_i] += 1;
+                if instruction.address().is_some() {
+                    self.stats.accesses += 1;
+                }
+            }
+            Event::Alert { op, .. } => match op {
+                MemOp::Read => self.stats.read_misses += 1,
+                MemOp::Write => self.stats.write_misses += 1,
+            },
+            Event::BusTraffic(traffic) => self.stats.traffic = traffic,
+            Event::PrefetchStats { processor_i, stats } => {
+                self.prefetch.insert(processor_i, stats);
+            }
+            Event::CacheActivity { cache_i, activity } => {
+                self.activity.insert(cache_i, activity);
+            }
+            Event::MemoryStats {
+                node,
+                banks,
+                accesses,
+                ..
+            } => {
+                let row_hits = banks.iter().map(|bank| bank.row_hits).sum();
+                self.nodes
+                    .insert(node, (accesses.local, accesses.remote, row_hits));
+            }
+            Event::InterconnectStats(stats) => {
+                self.stats.messages = stats.messages;
+                self.stats.hops = stats.hops;
+            }
+            Event::Error(error) => self.error = Some(error),
+            _ => (),
+        }
+    }
+
+    /// Applies the events that already came in, without waiting for more
+    pub fn pump(&mut self, events_rx: &Receiver<Event>) {
+        while let Ok(event) = events_rx.try_recv() {
+            self.apply(event);
+        }
+    }
+
+    pub fn all_halted(&self) -> bool {
+        self.halted.iter().all(|&halted| halted)
+    }
+
+    /// Value the system would read at `address`. Any valid copy is up to
+    /// date once every processor halted, and so is a write back still owning
+    /// its block. Addresses wrap around the memory like the initial memory's
+    /// do.
+    pub fn memory_value(&self, address: usize) -> Data {
+        let address_space = self.memory.len() << self.offset_bits;
+        let block = (address % address_space) >> self.offset_bits;
+        let cached = self.caches.iter().flat_map(|cache| {
+            cache.iter().enumerate().filter_map(|(block_i, line)| {
+                let index = block_i / self.associativity;
+                let line_block = (line.tag << self.index_bits) | index;
+                (line.state.is_valid() && line_block == block)
+                    .then_some(line.data)
+            })
+        });
+        let victims = self.victim_caches.iter().flatten().filter_map(|line| {
+            (line.state.is_valid() && line.tag == block).then_some(line.data)
+        });
+        let write_backs =
+            self.write_back_buffers.iter().flatten().filter_map(|line| {
+                let owner =
+                    matches!(line.state, CacheState::MI_A | CacheState::OI_A);
+                (owner && line.tag == block).then_some(line.data)
+            });
+        cached
+            .chain(victims)
+            .chain(write_backs)
+            .last()
+            .unwrap_or(self.memory[block])
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+        stats.cycles = self.retired.iter().copied().max().unwrap_or(0);
+        for prefetch in self.prefetch.values() {
+            stats.prefetch.issued += prefetch.issued;
+            stats.prefetch.useful += prefetch.useful;
+            stats.prefetch.late += prefetch.late;
+            stats.prefetch.useless += prefetch.useless;
+        }
+        for activity in self.activity.values() {
+            stats.activity.add(activity);
+        }
+        for &(local, remote, row_hits) in self.nodes.values() {
+            stats.local_accesses += local;
+            stats.remote_accesses += remote;
+            stats.row_hits += row_hits;
+        }
+        stats
+    }
+}
@@ -0,0 +1,492 @@
+//! Design-space sweeps run the same workload or trace on many configurations
+//! of the system, without the GUI, and tabulate the statistics of every run.
+//! The configurations run in parallel, each one on a system of its own.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+    },
+    thread,
+};
+
+use crate::{
+    models::{
+        engine::Engine,
+        error::SimError,
+        instructions::{Instruction, Program},
+        processor::CpuCommand,
+        system::{InvalidProps, SocProperties, System},
+    },
+    state::{Stats, SystemState},
+    trace::Trace,
+    workload::{Workload, WorkloadConfig},
+};
+
+/// Which implementation of the system runs the configurations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Simulator {
+    /// The one the GUI uses, a thread per CPU and controller and the bus
+    /// ones, with no delays
+    Threaded,
+    /// Single-threaded and atomic, see `Engine`
+    Fast,
+}
+
+impl Simulator {
+    pub const ALL: [Simulator; 2] = [Simulator::Threaded, Simulator::Fast];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            Simulator::Threaded => "threaded",
+            Simulator::Fast => "fast",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Simulator> {
+        Simulator::ALL
+            .into_iter()
+            .find(|simulator| simulator.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// What the CPUs execute
+pub enum SweepInput {
+    /// Instructions of the configuration's workload, per CPU
+    Workload(usize),
+    Trace(Trace),
+}
+
+/// Why a configuration couldn't be run
+#[derive(Debug, Clone)]
+pub enum SweepError {
+    Props(InvalidProps),
+    Sim(SimError),
+}
+
+impl fmt::Display for SweepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SweepError::Props(err) => write!(f, "{err}"),
+            SweepError::Sim(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for SweepError {}
+
+impl From<InvalidProps> for SweepError {
+    fn from(err: InvalidProps) -> Self { SweepError::Props(err) }
+}
+
+impl From<SimError> for SweepError {
+    fn from(err: SimError) -> Self { SweepError::Sim(err) }
+}
+
+/// A point of the design space, `values` holds the value of every swept
+/// option in the order they were given
+#[derive(Clone)]
+pub struct SweepConfig {
+    pub values: Vec<String>,
+    pub props: SocProperties,
+    pub workload: WorkloadConfig,
+}
+
+impl SweepConfig {
+    /// Whether the configuration can run `input`
+    pub fn validate(&self, input: &SweepInput) -> Result<(), InvalidProps> {
+        self.props.validate()?;
+        match input {
+            SweepInput::Workload(_) => self.workload.validate(&self.props),
+            SweepInput::Trace(trace)
+                if trace.processors() > self.props.num_processors =>
+            {
+                Err(InvalidProps(format!(
+                    "the trace needs {} processors",
+                    trace.processors()
+                )))
+            }
+            SweepInput::Trace(_) => Ok(()),
+        }
+    }
+}
+
+// Instructions every CPU executes, in the order the CPUs take their turns in
+// the automatic mode
+fn programs(config: &SweepConfig, input: &SweepInput) -> Vec<Vec<Instruction>> {
+    let props = config.props;
+    let mut programs = vec![Vec::new(); props.num_processors];
+    match input {
+        SweepInput::Workload(instructions) => {
+            let mut workload = Workload::new(
+                config.workload,
+                props.num_processors,
+                props.main_memory_blocks,
+                props.seed,
+            );
+            for _ in 0..*instructions {
+                for (cpu_i, program) in programs.iter_mut().enumerate() {
+                    program.push(workload.next_instruction(cpu_i));
+                }
+            }
+        }
+        SweepInput::Trace(trace) => {
+            for (cpu_i, instruction) in &trace.entries {
+                programs[*cpu_i].push(instruction.clone());
+            }
+        }
+    }
+    programs
+}
+
+// Every CPU runs its instructions as a program. The CPUs are stepped in turns
+// like in the automatic mode, and the run ends once all of them halted with
+// their writes performed. A trace only keeps the order of every CPU's own
+// instructions.
+fn run_threaded(
+    config: &SweepConfig,
+    input: &SweepInput,
+) -> Result<Stats, SweepError> {
+    let (events_tx, events_rx) = channel();
+    let mut system = System::new(config.props, &[], events_tx)?;
+    let cpus = system.cpus();
+
+    let mut state = SystemState::new(&config.props);
+    for (cpu, program) in cpus.iter().zip(programs(config, input)) {
+        // Workloads and traces don't have labels
+        let program = Program::new(program).unwrap();
+        cpu.send(CpuCommand::LoadProgram(program)).ok();
+    }
+
+    loop {
+        state.pump(&events_rx);
+        // The CPUs are gone, the rest of the system is shutting down
+        if let Some(error) = state.error.take() {
+            return Err(error.into());
+        }
+        let running: Vec<usize> =
+            (0..cpus.len()).filter(|&i| !state.halted[i]).collect();
+        if running.is_empty() {
+            break;
+        }
+        for cpu_i in running {
+            cpus[cpu_i].send(CpuCommand::Step).ok();
+        }
+    }
+
+    // The posted write backs and the last statistics come in while the
+    // system winds down
+    system.shutdown();
+    state.pump(&events_rx);
+    match state.error.take() {
+        Some(error) => Err(error.into()),
+        None => Ok(state.stats()),
+    }
+}
+
+// The engine has no timing, so there are no DRAM rows or interconnect
+// messages to count, they're left at 0. The workload is generated as it runs, it doesn't have
+// to fit in memory.
+fn run_fast(
+    config: &SweepConfig,
+    input: &SweepInput,
+) -> Result<Stats, SweepError> {
+    let props = config.props;
+    let mut engine = Engine::new(props)?;
+    match input {
+        SweepInput::Workload(instructions) => {
+            let mut workload = Workload::new(
+                config.workload,
+                props.num_processors,
+                props.main_memory_blocks,
+                props.seed,
+            );
+            for _ in 0..*instructions {
+                for cpu_i in 0..props.num_processors {
+                    engine.execute(cpu_i, &workload.next_instruction(cpu_i));
+                }
+            }
+        }
+        SweepInput::Trace(trace) => {
+            for (cpu_i, instruction) in &trace.entries {
+                engine.execute(*cpu_i, instruction);
+            }
+        }
+    }
+
+    let stats = engine.stats();
+    Ok(Stats {
+        accesses: stats.accesses,
+        read_misses: stats.read_misses,
+        write_misses: stats.write_misses,
+        traffic: stats.traffic,
+        prefetch: stats.prefetch,
+        local_accesses: stats.local_accesses,
+        remote_accesses: stats.remote_accesses,
+        activity: stats.activity,
+        cycles: stats.cycles,
+        ..Default::default()
+    })
+}
+
+/// Runs every configuration on up to `jobs` threads, the results come in the
+/// order of `configs`. A configuration that couldn't run doesn't stop the
+/// rest. `progress` gets the index and the result of every configuration as
+/// it finishes, in the order they finish.
+pub fn run(
+    configs: &[SweepConfig],
+    input: &SweepInput,
+    simulator: Simulator,
+    jobs: usize,
+    mut progress: impl FnMut(usize, &Result<Stats, SweepError>),
+) -> Vec<Result<Stats, SweepError>> {
+    let next = AtomicUsize::new(0);
+    let (results_tx, results_rx) = channel();
+    let mut results = Vec::with_capacity(configs.len());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(configs.len()) {
+            let results_tx = results_tx.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(config) = configs.get(i) else {
+                    break;
+                };
+                let stats = config.validate(input).map_err(SweepError::from);
+                let stats = stats.and_then(|()| match simulator {
+                    Simulator::Threaded => run_threaded(config, input),
+                    Simulator::Fast => run_fast(config, input),
+                });
+                results_tx.send((i, stats)).ok();
+            });
+        }
+        drop(results_tx);
+        for (i, stats) in results_rx {
+            progress(i, &stats);
+            results.push((i, stats));
+        }
+    });
+
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, stats)| stats).collect()
+}
+
+/// The results of a sweep as CSV, a column per swept option followed by the
+/// statistics. The cells of what `simulator` doesn't measure are left empty.
+pub struct SweepResults<'a> {
+    pub simulator: Simulator,
+    pub options: &'a [String],
+    pub configs: &'a [SweepConfig],
+    pub stats: &'a [Stats],
+}
+
+const STATS_HEADER: &str = "accesses,read_misses,write_misses,miss_rate,\
+                            bus_read_misses,bus_read_exclusives,\
+                            bus_invalidates,bus_write_backs,\
+                            bus_write_throughs,data_transfers,memory_reads,\
+                            memory_writes,prefetches_issued,\
+                            prefetches_useful,prefetches_late,\
+                            prefetches_useless,local_accesses,\
+                            remote_accesses,row_hits,messages,hops,snoops,\
+                            energy_caches_pj,energy_bus_pj,\
+                            energy_memory_pj,energy_leakage_pj,\
+                            energy_total_pj";
+
+impl fmt::Display for SweepResults<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for option in self.options {
+            write!(f, "{option},")?;
+        }
+        writeln!(f, "{STATS_HEADER}")?;
+        // The fast engine has no DRAM rows or interconnect, a 0 would read as
+        // a measurement
+        let timed = |count: usize| match self.simulator {
+            Simulator::Threaded => count.to_string(),
+            Simulator::Fast => String::new(),
+        };
+
+        for (config, stats) in self.configs.iter().zip(self.stats) {
+            for value in &config.values {
+                write!(f, "{value},")?;
+            }
+            let traffic = &stats.traffic;
+            let prefetch = &stats.prefetch;
+            let energy = config.props.energy.report(
+                &stats.activity,
+                traffic,
+                config.props.cache_lines(),
+                stats.cycles,
+            );
+            writeln!(
+                f,
+                "{},{},{},{:.4},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},\
+                 {},{},{},{:.1},{:.1},{:.1},{:.1},{:.1}",
+                stats.accesses,
+                stats.read_misses,
+                stats.write_misses,
+                stats.miss_rate(),
+                traffic.read_misses,
+                traffic.read_exclusives,
+                traffic.invalidates,
+                traffic.write_backs,
+                traffic.write_throughs,
+                traffic.data_transfers,
+                traffic.memory_reads,
+                traffic.memory_writes,
+                prefetch.issued,
+                prefetch.useful,
+                prefetch.late,
+                prefetch.useless,
+                stats.local_accesses,
+                stats.remote_accesses,
+                timed(stats.row_hits),
+                timed(stats.messages),
+                timed(stats.hops),
+                traffic.snoops,
+                energy.caches,
+                energy.bus,
+                energy.memory,
+                energy.leakage,
+                energy.total(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+    use crate::{
+        models::{
+            cache::CoherenceProtocol,
+            consistency::ConsistencyModel,
+            instructions::{Address, Operand},
+            system::DEFAULT_PROPS,
+            Data,
+        },
+        workload::DEFAULT_WORKLOAD,
+    };
+
+    #[test]
+    fn failed_configurations_dont_stop_the_rest() {
+        let config = |private_blocks: usize| SweepConfig {
+            values: vec![private_blocks.to_string()],
+            props: DEFAULT_PROPS.headless(),
+            workload: WorkloadConfig {
+                private_blocks,
+                ..DEFAULT_WORKLOAD
+            },
+        };
+        // Every block private leaves none shared
+        let blocks = DEFAULT_PROPS.main_memory_blocks;
+        let configs = [config(0), config(blocks), config(1)];
+        let mut finished = Vec::new();
+        let results = run(
+            &configs,
+            &SweepInput::Workload(10),
+            Simulator::Fast,
+            1,
+            |i, _| finished.push(i),
+        );
+        finished.sort();
+        assert_eq!(finished, [0, 1, 2]);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(SweepError::Props(_))));
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn fast_engine_rejects_relaxed_models() {
+        let config = |consistency_model: ConsistencyModel| SweepConfig {
+            values: vec![consistency_model.get_name().to_string()],
+            props: SocProperties {
+                consistency_model,
+                ..DEFAULT_PROPS.headless()
+            },
+            workload: DEFAULT_WORKLOAD,
+        };
+        let configs = ConsistencyModel::ALL.map(config);
+        let results = run(
+            &configs,
+            &SweepInput::Workload(10),
+            Simulator::Fast,
+            1,
+            |_, _| {},
+        );
+        for (config, result) in configs.iter().zip(results) {
+            match config.props.consistency_model {
+                ConsistencyModel::Sequential => assert!(result.is_ok()),
+                _ => assert!(matches!(result, Err(SweepError::Props(_)))),
+            }
+        }
+    }
+
+    // The threaded system only keeps the order of every CPU's own
+    // instructions, so the CPUs work on blocks of their own. The blocks of a
+    // CPU map to the same set and evict each other.
+    #[test]
+    fn both_simulators_agree_under_sc() {
+        let blocks = 16;
+        let processors = DEFAULT_PROPS.num_processors;
+        let mut trace = Trace::default();
+        for round in 0..3 {
+            for cpu_i in 0..processors {
+                for block in (cpu_i..blocks).step_by(processors) {
+                    let address = Address::from(block * size_of::<Data>());
+                    let instruction = match (round + block / processors) % 2 {
+                        0 => Instruction::Read { rd: 1, address },
+                        _ => Instruction::Write {
+                            address,
+                            src: Operand::Immediate(round as Data),
+                        },
+                    };
+                    trace.entries.push((cpu_i, instruction));
+                }
+            }
+        }
+        let configs = CoherenceProtocol::ALL.map(|protocol| SweepConfig {
+            values: vec![protocol.get_name().to_string()],
+            props: SocProperties {
+                protocol,
+                main_memory_blocks: blocks,
+                ..DEFAULT_PROPS.headless()
+            },
+            workload: DEFAULT_WORKLOAD,
+        });
+        let input = SweepInput::Trace(trace);
+        let counts = |simulator| {
+            run(&configs, &input, simulator, configs.len(), |_, _| {})
+                .into_iter()
+                .map(|stats| {
+                    let stats = stats.unwrap();
+                    let traffic = stats.traffic;
+                    [
+                        stats.accesses,
+                        stats.read_misses,
+                        stats.write_misses,
+                        traffic.read_misses,
+                        traffic.read_exclusives,
+                        traffic.invalidates,
+                        traffic.write_backs,
+                        traffic.data_transfers,
+                        traffic.memory_reads,
+                        traffic.memory_writes,
+                        traffic.snoops,
+                    ]
+                })
+                .collect::<Vec<_>>()
+        };
+        let fast = counts(Simulator::Fast);
+        for (config, (fast, threaded)) in configs
+            .iter()
+            .zip(fast.iter().zip(counts(Simulator::Threaded)))
+        {
+            assert_eq!(*fast, threaded, "{}", config.values[0]);
+        }
+        assert!(fast.iter().all(|counts| counts[6] > 0));
+    }
+}
@@ -0,0 +1,174 @@
+//! Traces list the instructions the CPUs execute in the order they happen, one
+//! per line with the CPU that executes it first:
+//!
+//! ```text
+//! ; CPU1 writes a block and CPU2 reads it
+//! 0 write 0x10, 1
+//! 1 read r1, 0x10
+//! ```
+//!
+//! Instructions are in the assembler's syntax. There's no program to jump
+//! around in, so labels and branches aren't allowed.
+
+use std::{fmt, str::FromStr};
+
+use crate::models::instructions::Instruction;
+
+// Everything after this character in a line is ignored
+const COMMENT_CHAR: char = ';';
+
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    /// CPU and instruction, in order
+    pub entries: Vec<(usize, Instruction)>,
+}
+
+impl Trace {
+    /// CPUs the trace needs
+    pub fn processors(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|&(cpu_i, _)| cpu_i + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseTraceError(pub String);
+
+impl fmt::Display for ParseTraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseTraceError {}
+
+impl FromStr for Trace {
+    type Err = ParseTraceError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut trace = Trace::default();
+        for (line_i, line) in text.lines().enumerate() {
+            let error = |message: String| {
+                ParseTraceError(format!("line {}: {message}", line_i + 1))
+            };
+            let line = match line.split_once(COMMENT_CHAR) {
+                Some((code, _comment)) => code,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (cpu, instruction) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| error(format!("missing instruction: {line}")))?;
+            let cpu_i = cpu
+                .parse()
+                .map_err(|_| error(format!("invalid cpu \"{cpu}\"")))?;
+            let instruction: Instruction = instruction
+                .trim()
+                .parse()
+                .map_err(|err| error(format!("{err}")))?;
+            if matches!(
+                instruction,
+                Instruction::Label(_) | Instruction::Branch { .. }
+            ) {
+                return Err(error(
+                    "labels and branches can't be traced".into(),
+                ));
+            }
+            trace.entries.push((cpu_i, instruction));
+        }
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::instructions::{Address, Operand};
+
+    fn parse_error(text: &str) -> String {
+        text.parse::<Trace>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_trace() {
+        let text = "\
+; CPU1 writes a block and CPU2 reads it
+
+0 write 0x10, 1
+    2   read r1, 4(r2) ; indexed
+1 FAA r3, 0b10, r1
+";
+        let trace: Trace = text.parse().unwrap();
+        assert_eq!(
+            trace.entries,
+            [
+                (
+                    0,
+                    Instruction::Write {
+                        address: Address::from(0x10),
+                        src: Operand::Immediate(1),
+                    }
+                ),
+                (
+                    2,
+                    Instruction::Read {
+                        rd: 1,
+                        address: Address {
+                            offset: 4,
+                            index: Some(2),
+                        },
+                    }
+                ),
+                (
+                    1,
+                    Instruction::FetchAndAdd {
+                        rd: 3,
+                        address: Address::from(2),
+                        value: Operand::Register(1),
+                    }
+                ),
+            ]
+        );
+        assert_eq!(trace.processors(), 3);
+    }
+
+    #[test]
+    fn empty_traces_need_no_processors() {
+        let trace: Trace = "; nothing to do\n\n".parse().unwrap();
+        assert!(trace.entries.is_empty());
+        assert_eq!(trace.processors(), 0);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(
+            parse_error("0 calc\ncalc"),
+            "line 2: missing instruction: calc"
+        );
+        assert_eq!(parse_error("x calc"), "line 1: invalid cpu \"x\"");
+        assert_eq!(parse_error("-1 calc"), "line 1: invalid cpu \"-1\"");
+        assert_eq!(
+            parse_error("\n0 jmp 4"),
+            "line 2: unknown instruction \"jmp\""
+        );
+        assert!(parse_error("0 read r9, 0")
+            .starts_with("line 1: expected a register between r0 and r7"));
+    }
+
+    #[test]
+    fn rejects_labels_and_branches() {
+        for line in ["0 loop:", "1 bne loop"] {
+            assert_eq!(
+                parse_error(line),
+                "line 1: labels and branches can't be traced"
+            );
+        }
+    }
+}
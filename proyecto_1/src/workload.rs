@@ -0,0 +1,377 @@
+use std::{collections::VecDeque, mem::size_of};
+
+use crate::{
+    models::{
+        instructions::{Instruction, Operand, Register, NUM_REGISTERS},
+        system::{InvalidProps, SocProperties},
+        Data,
+    },
+    random::UniformRng,
+    scenarios::SharingPattern,
+};
+
+// Last addresses every CPU remembers for temporal locality
+const HISTORY: usize = 4;
+
+/// Instructions a workload can generate, the rest only make sense inside of a
+/// program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InstructionKind {
+    Calc,
+    Read,
+    Write,
+    TestAndSet,
+    FetchAndAdd,
+    CompareAndSwap,
+    LoadLinked,
+    StoreConditional,
+    Fence,
+}
+
+impl InstructionKind {
+    pub const ALL: [InstructionKind; 9] = [
+        InstructionKind::Calc,
+        InstructionKind::Read,
+        InstructionKind::Write,
+        InstructionKind::TestAndSet,
+        InstructionKind::FetchAndAdd,
+        InstructionKind::CompareAndSwap,
+        InstructionKind::LoadLinked,
+        InstructionKind::StoreConditional,
+        InstructionKind::Fence,
+    ];
+
+    /// Same as the assembler's mnemonics
+    pub fn get_name(&self) -> &str {
+        match self {
+            InstructionKind::Calc => "calc",
+            InstructionKind::Read => "read",
+            InstructionKind::Write => "write",
+            InstructionKind::TestAndSet => "tas",
+            InstructionKind::FetchAndAdd => "faa",
+            InstructionKind::CompareAndSwap => "cas",
+            InstructionKind::LoadLinked => "ll",
+            InstructionKind::StoreConditional => "sc",
+            InstructionKind::Fence => "fence",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<InstructionKind> {
+        InstructionKind::ALL
+            .into_iter()
+            .find(|kind| kind.get_name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// How the blocks of a region are picked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressDistribution {
+    Uniform,
+    /// Number of successes out of one trial per block but the first, centered
+    /// around a block and spreading out from it
+    Binomial,
+    /// The first blocks are the most popular ones, with a long tail
+    Zipf,
+    /// A few hot blocks get most of the accesses, the cold rest shares the
+    /// others evenly
+    HotCold,
+}
+
+impl AddressDistribution {
+    pub const ALL: [AddressDistribution; 4] = [
+        AddressDistribution::Uniform,
+        AddressDistribution::Binomial,
+        AddressDistribution::Zipf,
+        AddressDistribution::HotCold,
+    ];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            AddressDistribution::Uniform => "uniform",
+            AddressDistribution::Binomial => "binomial",
+            AddressDistribution::Zipf => "zipf",
+            AddressDistribution::HotCold => "hot-cold",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AddressDistribution> {
+        AddressDistribution::ALL.into_iter().find(|distribution| {
+            distribution.get_name().eq_ignore_ascii_case(name)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WorkloadConfig {
+    /// Named scenario that replaces the random instructions below
+    pub scenario: Option<SharingPattern>,
+    /// Relative weight of every kind of instruction, indexed by its
+    /// discriminant
+    pub mix: [u32; InstructionKind::ALL.len()],
+    pub distribution: AddressDistribution,
+    /// Chance of success of every binomial trial, in percent
+    pub binomial_percent: u32,
+    /// Block k of a region is picked with a weight of 1 / (k + 1)^s
+    pub zipf_exponent: f32,
+    /// Share of the blocks of a region that are hot, in percent
+    pub hot_blocks_percent: u32,
+    /// Share of the accesses of a region that go to its hot blocks, in
+    /// percent
+    pub hot_accesses_percent: u32,
+    /// Blocks at the end of memory only one CPU uses, per CPU. The blocks
+    /// before them are shared by all of them.
+    pub private_blocks: usize,
+    /// Accesses to the shared region when there are private ones, in percent
+    pub shared_percent: u32,
+    /// Accesses that repeat one of the last addresses the CPU accessed, in
+    /// percent
+    pub locality_percent: u32,
+}
+
+/// Every instruction and every block equally likely, all of memory shared
+pub const DEFAULT_WORKLOAD: WorkloadConfig = WorkloadConfig {
+    scenario: None,
+    mix: [1; InstructionKind::ALL.len()],
+    distribution: AddressDistribution::Uniform,
+    binomial_percent: 50,
+    zipf_exponent: 1.0,
+    hot_blocks_percent: 20,
+    hot_accesses_percent: 80,
+    private_blocks: 0,
+    shared_percent: 50,
+    locality_percent: 0,
+};
+
+impl WorkloadConfig {
+    /// Whether the workload fits in a system with `props`, at least a block
+    /// has to stay shared
+    pub fn validate(&self, props: &SocProperties) -> Result<(), InvalidProps> {
+        if self.private_blocks * props.num_processors
+            >= props.main_memory_blocks
+        {
+            return Err(InvalidProps(format!(
+                "{} private blocks per processor leave no shared ones",
+                self.private_blocks
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Generates the instructions the automatic mode sends to every CPU
+pub struct Workload {
+    config: WorkloadConfig,
+    rng: UniformRng,
+    num_processors: usize,
+    blocks: usize,
+    offset_bits: u32,
+    // Newest last
+    history: Vec<VecDeque<usize>>,
+    // Instructions every CPU got so far, scenarios are laid out by step
+    steps: Vec<usize>,
+}
+
+impl Workload {
+    pub fn new(
+        config: WorkloadConfig,
+        num_processors: usize,
+        blocks: usize,
+        seed: u32,
+    ) -> Self {
+        Workload {
+            config,
+            rng: UniformRng::from_seed(seed),
+            num_processors,
+            blocks,
+            offset_bits: size_of::<Data>().trailing_zeros(),
+            history: vec![VecDeque::with_capacity(HISTORY); num_processors],
+            steps: vec![0; num_processors],
+        }
+    }
+
+    pub fn config_mut(&mut self) -> &mut WorkloadConfig { &mut self.config }
+
+    /// Most private blocks every CPU can have, at least a block stays shared
+    pub fn max_private_blocks(&self) -> usize {
+        (self.blocks - 1) / self.num_processors
+    }
+
+    pub fn next_instruction(&mut self, cpu_i: usize) -> Instruction {
+        let step = self.steps[cpu_i];
+        self.steps[cpu_i] += 1;
+        if let Some(pattern) = self.config.scenario {
+            return pattern.instruction(
+                cpu_i,
+                step,
+                self.num_processors,
+                &mut self.rng,
+            );
+        }
+
+        match self.gen_kind() {
+            InstructionKind::Calc => Instruction::Calc,
+            InstructionKind::Read => Instruction::Read {
+                rd: self.gen_register(),
+                address: self.gen_address(cpu_i).into(),
+            },
+            InstructionKind::Write => Instruction::Write {
+                address: self.gen_address(cpu_i).into(),
+                src: self.gen_operand(),
+            },
+            InstructionKind::TestAndSet => Instruction::TestAndSet {
+                rd: self.gen_register(),
+                address: self.gen_address(cpu_i).into(),
+            },
+            InstructionKind::FetchAndAdd => Instruction::FetchAndAdd {
+                rd: self.gen_register(),
+                address: self.gen_address(cpu_i).into(),
+                value: self.gen_operand(),
+            },
+            InstructionKind::CompareAndSwap => Instruction::CompareAndSwap {
+                rd: self.gen_register(),
+                address: self.gen_address(cpu_i).into(),
+                expected: self.gen_operand(),
+                new: self.gen_operand(),
+            },
+            InstructionKind::LoadLinked => Instruction::LoadLinked {
+                rd: self.gen_register(),
+                address: self.gen_address(cpu_i).into(),
+            },
+            InstructionKind::StoreConditional => {
+                Instruction::StoreConditional {
+                    rd: self.gen_register(),
+                    address: self.gen_address(cpu_i).into(),
+                    src: self.gen_operand(),
+                }
+            }
+            InstructionKind::Fence => Instruction::Fence,
+        }
+    }
+
+    fn chance(&mut self, percent: u32) -> bool {
+        self.rng.gen_range(0..100) < percent
+    }
+
+    // Uniform in [0, 1)
+    fn gen_unit(&mut self) -> f32 {
+        self.rng.gen() as f32 / (u32::MAX as f32 + 1.0)
+    }
+
+    // Only calcs if every weight is 0
+    fn gen_kind(&mut self) -> InstructionKind {
+        let total: u64 =
+            self.config.mix.iter().map(|&weight| weight as u64).sum();
+        if total == 0 {
+            return InstructionKind::Calc;
+        }
+        // Past what the generator can pick from, a pick is scaled up to the
+        // total instead
+        let mut pick = match u32::try_from(total) {
+            Ok(total) => self.rng.gen_range(0..total) as u64,
+            Err(_) => ((self.rng.gen() as u128 * total as u128) >> 32) as u64,
+        };
+        for (kind, weight) in
+            InstructionKind::ALL.into_iter().zip(self.config.mix)
+        {
+            if pick < weight as u64 {
+                return kind;
+            }
+            pick -= weight as u64;
+        }
+        unreachable!()
+    }
+
+    fn gen_register(&mut self) -> Register {
+        self.rng.gen_range(0..NUM_REGISTERS as u32) as Register
+    }
+
+    fn gen_operand(&mut self) -> Operand {
+        Operand::Immediate(self.rng.gen() as u16)
+    }
+
+    fn gen_address(&mut self, cpu_i: usize) -> usize {
+        let remembered = self.history[cpu_i].len() as u32;
+        let locality = self.config.locality_percent;
+        let address = if remembered > 0 && self.chance(locality) {
+            let i = self.rng.gen_range(0..remembered) as usize;
+            self.history[cpu_i][i]
+        } else {
+            self.gen_block(cpu_i) << self.offset_bits
+        };
+
+        let history = &mut self.history[cpu_i];
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(address);
+        address
+    }
+
+    // Picks the region first, then a block within it
+    fn gen_block(&mut self, cpu_i: usize) -> usize {
+        let private = self.config.private_blocks;
+        let shared = self.blocks - private * self.num_processors;
+        if private > 0 && !self.chance(self.config.shared_percent) {
+            shared + cpu_i * private + self.gen_index(private)
+        } else {
+            self.gen_index(shared)
+        }
+    }
+
+    // Index into a region of `len` blocks
+    fn gen_index(&mut self, len: usize) -> usize {
+        match self.config.distribution {
+            AddressDistribution::Uniform => {
+                self.rng.gen_range(0..len as u32) as usize
+            }
+            AddressDistribution::Binomial => {
+                let percent = self.config.binomial_percent;
+                (1..len).filter(|_| self.chance(percent)).count()
+            }
+            AddressDistribution::Zipf => {
+                let exponent = self.config.zipf_exponent;
+                let weights: Vec<f32> = (1..=len)
+                    .map(|rank| 1.0 / (rank as f32).powf(exponent))
+                    .collect();
+                let mut pick = self.gen_unit() * weights.iter().sum::<f32>();
+                for (i, weight) in weights.iter().enumerate() {
+                    if pick < *weight {
+                        return i;
+                    }
+                    pick -= weight;
+                }
+                // Rounding errors
+                len - 1
+            }
+            AddressDistribution::HotCold => {
+                let hot = (len * self.config.hot_blocks_percent as usize / 100)
+                    .clamp(1, len);
+                if hot == len || self.chance(self.config.hot_accesses_percent) {
+                    self.rng.gen_range(0..hot as u32) as usize
+                } else {
+                    hot + self.rng.gen_range(0..(len - hot) as u32) as usize
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixes_can_weigh_up_to_the_largest_weight() {
+        let config = WorkloadConfig {
+            mix: [u32::MAX; InstructionKind::ALL.len()],
+            ..DEFAULT_WORKLOAD
+        };
+        let mut workload = Workload::new(config, 2, 16, 0);
+        let kinds: Vec<InstructionKind> =
+            (0..200).map(|_| workload.gen_kind()).collect();
+        // Every kind weighs the same, they all show up
+        for kind in InstructionKind::ALL {
+            assert!(kinds.contains(&kind), "no {}", kind.get_name());
+        }
+    }
+}
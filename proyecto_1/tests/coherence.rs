@@ -0,0 +1,78 @@
+use std::{sync::mpsc::Receiver, time::Duration};
+
+use proyecto_1::{
+    events::{Event, EventHub, EventKind},
+    models::{
+        instructions::{Address, Instruction, Operand},
+        processor::CpuCommand,
+        system::{System, DEFAULT_PROPS},
+    },
+    state::SystemState,
+};
+
+// Runs the instruction on a CPU and waits until it retires, so instructions
+// on different CPUs happen in the order they're given
+fn execute(
+    system: &System,
+    retired: &Receiver<Event>,
+    cpu_i: usize,
+    instruction: Instruction,
+) {
+    system
+        .send(cpu_i, CpuCommand::Execute(instruction))
+        .unwrap();
+    loop {
+        match retired.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Event::InstructionRetired { processor_i, .. }
+                if processor_i == cpu_i =>
+            {
+                return
+            }
+            _ => (),
+        }
+    }
+}
+
+fn read(rd: usize, address: usize) -> Instruction {
+    Instruction::Read {
+        rd,
+        address: Address::from(address),
+    }
+}
+
+// The Owned line is the only up to date copy once the sharer drops its own,
+// an atomic upgrading it must not take memory's stale data
+#[test]
+fn atomic_on_owned_line_without_sharers() {
+    let props = DEFAULT_PROPS.headless();
+    let events = EventHub::new();
+    let retired = events.subscribe_to(&[EventKind::InstructionRetired]);
+    let all = events.subscribe();
+    let mut system = System::new(props, &[], events).unwrap();
+
+    let write = Instruction::Write {
+        address: Address::from(0),
+        src: Operand::Immediate(5),
+    };
+    execute(&system, &retired, 0, write);
+    // CPU2 shares the block, leaving CPU1 in Owned, and then reads two other
+    // blocks of the same set so its copy is evicted
+    execute(&system, &retired, 1, read(1, 0));
+    execute(&system, &retired, 1, read(1, 4));
+    execute(&system, &retired, 1, read(1, 8));
+    let add = Instruction::FetchAndAdd {
+        rd: 1,
+        address: Address::from(0),
+        value: Operand::Immediate(1),
+    };
+    execute(&system, &retired, 0, add);
+    execute(&system, &retired, 2, read(2, 0));
+    system.shutdown();
+
+    let mut state = SystemState::new(&props);
+    state.pump(&all);
+    assert!(state.error.is_none());
+    assert_eq!(state.registers[0][1], 5);
+    assert_eq!(state.registers[2][2], 6);
+    assert_eq!(state.memory_value(0), 6);
+}
@@ -0,0 +1,107 @@
+use std::{
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::Duration,
+};
+
+use proyecto_1::{
+    events::Event,
+    models::{
+        cache::CoherenceProtocol,
+        instructions::{Address, Instruction, Operand},
+        processor::CpuCommand,
+        system::{SocProperties, System, DEFAULT_PROPS},
+    },
+    state::SystemState,
+};
+
+const SYSTEMS: usize = 50;
+
+// Threads of this process, there's no portable way to count them
+#[cfg(target_os = "linux")]
+fn threads() -> usize { std::fs::read_dir("/proc/self/task").unwrap().count() }
+
+fn read(rd: usize, address: usize) -> Instruction {
+    Instruction::Read {
+        rd,
+        address: Address::from(address),
+    }
+}
+
+// Systems are built, run, reset, rebuilt and either shut down or dropped in
+// the middle of their work. Every thread they started must be gone after.
+#[test]
+fn systems_stop_every_thread() {
+    #[cfg(target_os = "linux")]
+    let baseline = threads();
+    let (events_tx, events_rx) = channel();
+
+    for system_i in 0..SYSTEMS {
+        let protocol =
+            CoherenceProtocol::ALL[system_i % CoherenceProtocol::ALL.len()];
+        let props = SocProperties {
+            protocol,
+            ..DEFAULT_PROPS.headless()
+        };
+        let mut system =
+            System::new(props, &[(0, 7)], events_tx.clone()).unwrap();
+        let write = Instruction::Write {
+            address: Address::from(2),
+            src: Operand::Immediate(1),
+        };
+        system.send(0, CpuCommand::Execute(write)).unwrap();
+        system.send(1, CpuCommand::Execute(read(1, 2))).unwrap();
+
+        match system_i % 3 {
+            0 => system.reset(),
+            1 => {
+                system
+                    .rebuild(SocProperties {
+                        num_processors: 2,
+                        ..props
+                    })
+                    .unwrap();
+                assert_eq!(system.cpus().len(), 2);
+            }
+            _ => (),
+        }
+
+        if system_i % 2 == 0 {
+            // Dropped with commands still in flight
+            for cpu_i in 0..system.cpus().len() {
+                system.send(cpu_i, CpuCommand::Execute(read(2, 0))).unwrap();
+            }
+            drop(system);
+            continue;
+        }
+
+        // What the systems before this one sent, aborted ones may have sent
+        // errors as they stopped
+        while events_rx.try_recv().is_ok() {}
+        // The initial memory is back after a reset or a rebuild
+        let mut state = SystemState::new(system.props());
+        system.send(0, CpuCommand::Execute(read(2, 0))).unwrap();
+        system.shutdown();
+        assert!(system.cpus().is_empty());
+        assert!(system.send(0, CpuCommand::Step).is_err());
+        while let Ok(event) = events_rx.try_recv() {
+            if let Event::Error(err) = &event {
+                panic!("system {system_i} failed: {err}");
+            }
+            state.apply(event);
+        }
+        assert_eq!(state.registers[0][2], 7);
+    }
+
+    // Every component holds a listener, the channel only disconnects once
+    // all of them stopped
+    drop(events_tx);
+    loop {
+        match events_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(_) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => panic!("a thread is still up"),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    assert_eq!(threads(), baseline);
+}